@@ -0,0 +1,807 @@
+/**
+ * Proxy configuration: TOML file, then command-line flags (or environment variables) on top.
+ * Everything has a default, so the binary may run without a file.
+ */
+use serde::Deserialize;
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::janus::{self, JanusHealthCheckConfig, EventOverflowPolicy};
+use crate::janus::plugin::{JanusPluginProvider, JanusPluginFactory, VideoRoomPluginFactory, FileVideoRoomState};
+use crate::janus::provider::{self, ProxyStateProvider, JanusBackendProvider, IdGenerator};
+use crate::janus::provider::{MemoryStateProvider, MemoryBackendProvider, RedisStateProvider, RedisBackendProvider};
+use crate::janus::tls::TlsAcceptor;
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "janus-proxy", about = "Janus WebRTC Server API proxy")]
+pub struct Cli {
+    /** TOML configuration file */
+    #[structopt(short, long, env = "JANUS_PROXY_CONFIG", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+
+    /** Validate configuration and exit */
+    #[structopt(long)]
+    pub check_config: bool,
+
+    /** Websocket listener address, overrides `listen.websocket` */
+    #[structopt(long, env = "JANUS_PROXY_LISTEN")]
+    pub listen: Option<String>,
+
+    /** HTTP/REST listener address, overrides `listen.http` */
+    #[structopt(long, env = "JANUS_PROXY_HTTP_LISTEN")]
+    pub http_listen: Option<String>,
+
+    /** Admin API listener address, overrides `listen.admin` */
+    #[structopt(long, env = "JANUS_PROXY_ADMIN_LISTEN")]
+    pub admin_listen: Option<String>,
+
+    /** Prometheus metrics listener address, overrides `listen.metrics` */
+    #[structopt(long, env = "JANUS_PROXY_METRICS_LISTEN")]
+    pub metrics_listen: Option<String>,
+
+    /** janus-gateway url, may repeat (or comma separated), replaces configured backends */
+    #[structopt(long = "backend", env = "JANUS_PROXY_BACKENDS", use_delimiter = true)]
+    pub backends: Vec<String>,
+
+    /** Shared secret clients must provide, overrides `auth.api_secret` */
+    #[structopt(long, env = "JANUS_PROXY_API_SECRET", hide_env_values = true)]
+    pub api_secret: Option<String>,
+
+    /** Admin API secret, overrides `auth.admin_secret` */
+    #[structopt(long, env = "JANUS_PROXY_ADMIN_SECRET", hide_env_values = true)]
+    pub admin_secret: Option<String>,
+
+    /** PEM certificate chain for websocket listener, overrides `tls.cert` */
+    #[structopt(long, env = "JANUS_PROXY_TLS_CERT", parse(from_os_str))]
+    pub tls_cert: Option<PathBuf>,
+
+    /** PEM private key for websocket listener, overrides `tls.key` */
+    #[structopt(long, env = "JANUS_PROXY_TLS_KEY", parse(from_os_str))]
+    pub tls_key: Option<PathBuf>,
+
+    /** Seconds, overrides `session.timeout` */
+    #[structopt(long, env = "JANUS_PROXY_SESSION_TIMEOUT")]
+    pub session_timeout: Option<u64>,
+
+    /** Log filter, e.g. "debug" or "info,janus_proxy=trace", overrides `log.level` */
+    #[structopt(long, env = "JANUS_PROXY_LOG_LEVEL")]
+    pub log_level: Option<String>,
+
+    /** "text" or "json", overrides `log.format` */
+    #[structopt(long, env = "JANUS_PROXY_LOG_FORMAT")]
+    pub log_format: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen: ListenConfig,
+    pub tls: Option<TlsConfig>,
+    pub backends: Vec<BackendConfig>,
+    pub state: StateConfig,
+    /** Plugins served, by package name */
+    pub plugins: Vec<String>,
+    pub videoroom: VideoRoomConfig,
+    pub session: SessionConfig,
+    pub events: EventsConfig,
+    pub auth: AuthConfig,
+    pub shutdown: ShutdownConfig,
+    pub log: LogConfig,
+    pub health_check: HealthCheckConfig,
+    pub balancer: BalancerConfig,
+    pub backend_pool: BackendPoolConfig,
+    pub ids: IdsConfig
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ListenConfig {
+    pub websocket: String,
+    /** Disabled if absent */
+    pub http: Option<String>,
+    /** Disabled if absent. Not protected unless `auth.admin_secret` is set, better listen locally */
+    pub admin: Option<String>,
+    /** Prometheus `GET /metrics`, disabled if absent */
+    pub metrics: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackendConfig {
+    /** Janus API websocket url */
+    pub url: String,
+    /** Relative share of new sessions */
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+    /** janus-gateway `api_secret` */
+    pub secret: Option<String>,
+    /** Janus Admin API websocket url */
+    pub admin_url: Option<String>,
+    /** janus-gateway `admin_secret` */
+    pub admin_secret: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateConfig {
+    /** "memory" for a single instance, "redis" to share sessions, tokens and backends between instances */
+    pub provider: String,
+    /** Redis url, e.g. "redis://127.0.0.1:6379/0", required by "redis" provider */
+    pub url: Option<String>,
+    /** Namespace of redis keys, instances sharing it work together */
+    pub prefix: String,
+    /** Seconds session and handle ids stay reserved after their instance is gone without shutdown */
+    pub lease: u64,
+    /** Name of this instance among those sharing `prefix`, random if absent */
+    pub instance: Option<String>,
+    /** Seconds between checks for expired leases, whose janus-gateway sessions are then destroyed. 0 to disable */
+    pub sweep_interval: u64
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VideoRoomConfig {
+    /**
+     * JSON file keeping rooms created with `permanent`, they are lost on restart if absent.
+     * Not with "redis" state: rooms, and backends they're pinned to, are kept by each instance.
+     */
+    pub rooms_file: Option<PathBuf>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionConfig {
+    /** Seconds without any request before a session is destroyed, 0 to disable */
+    pub timeout: u64,
+    /** Seconds a session waits for `claim` after its transport is gone */
+    pub reclaim_timeout: u64,
+    /** Recreate janus-gateway session and handle when its connection is lost, otherwise handles are detached */
+    pub reconnect: bool
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventsConfig {
+    /** Backend events waiting for a client (per handle), and events kept for a detached session */
+    pub queue_size: usize,
+    /** When a client doesn't keep up: "drop_oldest", "disconnect" or "backpressure" */
+    pub overflow: String
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub api_secret: Option<String>,
+    pub token_auth: bool,
+    pub admin_secret: Option<String>
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShutdownConfig {
+    /** Seconds to wait for sessions to end on SIGTERM/SIGINT */
+    pub deadline: u64,
+    /** Refuse `create` while draining */
+    pub refuse_sessions: bool
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BalancerConfig {
+    /** "round_robin", "weighted_random", "least_sessions" or "power_of_two" */
+    pub strategy: String
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdsConfig {
+    /** Session, handle, room and backend transaction ids: "random", "monotonic" or "seeded" */
+    pub strategy: String,
+    /** Sequence of "seeded" ids */
+    pub seed: u64
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BackendPoolConfig {
+    /** Backend sessions sharing a websocket connection to janus-gateway before another one is opened */
+    pub sessions_per_connection: usize
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    /** Probe backends, otherwise every configured backend is always considered up */
+    pub enabled: bool,
+    /** Seconds between probes */
+    pub interval: u64,
+    /** Seconds before a probe is considered failed */
+    pub timeout: u64,
+    /** Consecutive successes to mark a backend up */
+    pub rise: u32,
+    /** Consecutive failures to mark a backend down */
+    pub fall: u32,
+    /** Create and destroy a session on every probe, on top of `info` */
+    pub probe_session: bool
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /** `tracing` filter directives, same syntax as `RUST_LOG` */
+    pub level: String,
+    /** "text" for humans, "json" for log collectors, one object per line */
+    pub format: String
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            listen: ListenConfig::default(),
+            tls: None,
+            backends: vec![BackendConfig {
+                url: String::from("ws://localhost:8188"),
+                weight: default_weight(),
+                secret: None,
+                admin_url: None,
+                admin_secret: None
+            }],
+            state: StateConfig::default(),
+            plugins: JanusPluginProvider::default().names(),
+            videoroom: VideoRoomConfig::default(),
+            session: SessionConfig::default(),
+            events: EventsConfig::default(),
+            auth: AuthConfig::default(),
+            shutdown: ShutdownConfig::default(),
+            log: LogConfig::default(),
+            health_check: HealthCheckConfig::default(),
+            balancer: BalancerConfig::default(),
+            backend_pool: BackendPoolConfig::default(),
+            ids: IdsConfig::default()
+        }
+    }
+}
+
+impl Default for ListenConfig {
+    fn default() -> ListenConfig {
+        ListenConfig {
+            websocket: String::from("0.0.0.0:3000"),
+            http: None,
+            admin: None,
+            metrics: None
+        }
+    }
+}
+
+impl Default for StateConfig {
+    fn default() -> StateConfig {
+        StateConfig {
+            provider: String::from("memory"),
+            url: None,
+            prefix: String::from("janus-proxy"),
+            lease: 60,
+            instance: None,
+            sweep_interval: 30
+        }
+    }
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            timeout: 60,
+            reclaim_timeout: 0,
+            reconnect: true
+        }
+    }
+}
+
+impl Default for EventsConfig {
+    fn default() -> EventsConfig {
+        EventsConfig {
+            queue_size: 64,
+            overflow: String::from("drop_oldest")
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> ShutdownConfig {
+        ShutdownConfig {
+            deadline: 30,
+            refuse_sessions: true
+        }
+    }
+}
+
+impl Default for BalancerConfig {
+    fn default() -> BalancerConfig {
+        BalancerConfig {
+            strategy: String::from("round_robin")
+        }
+    }
+}
+
+impl Default for BackendPoolConfig {
+    fn default() -> BackendPoolConfig {
+        BackendPoolConfig {
+            sessions_per_connection: 100
+        }
+    }
+}
+
+impl Default for IdsConfig {
+    fn default() -> IdsConfig {
+        IdsConfig {
+            strategy: String::from("random"),
+            seed: 0
+        }
+    }
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> HealthCheckConfig {
+        HealthCheckConfig {
+            enabled: true,
+            interval: 5,
+            timeout: 3,
+            rise: 2,
+            fall: 2,
+            probe_session: false
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            level: String::from("info"),
+            format: String::from("text")
+        }
+    }
+}
+
+fn check_addr(errors: &mut Vec<String>, key: &str, addr: &str) {
+    if addr.parse::<SocketAddr>().is_err() {
+        errors.push(format!("{}: invalid socket address \"{}\"", key, addr));
+    }
+}
+
+fn check_ws_url(errors: &mut Vec<String>, key: &str, url: &str) {
+    if !(url.starts_with("ws://") || url.starts_with("wss://")) || url.parse::<http::Uri>().is_err() {
+        errors.push(format!("{}: invalid websocket url \"{}\"", key, url));
+    }
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Could not read \"{}\": {}", path.display(), e))
+        };
+        toml::from_str(&text).map_err(|e| format!("Invalid config \"{}\": {}", path.display(), e))
+    }
+
+    /** Configuration file if given, defaults otherwise, then flags override */
+    pub fn load(cli: &Cli) -> Result<Config, String> {
+        let mut config = match &cli.config {
+            Some(path) => Self::from_file(path)?,
+            None => Config::default()
+        };
+        config.apply(cli);
+        Ok(config)
+    }
+
+    fn apply(&mut self, cli: &Cli) {
+        if let Some(x) = &cli.listen {
+            self.listen.websocket = x.clone();
+        }
+        if let Some(x) = &cli.http_listen {
+            self.listen.http = Some(x.clone());
+        }
+        if let Some(x) = &cli.admin_listen {
+            self.listen.admin = Some(x.clone());
+        }
+        if let Some(x) = &cli.metrics_listen {
+            self.listen.metrics = Some(x.clone());
+        }
+        if !cli.backends.is_empty() {
+            self.backends = cli.backends.iter()
+                .map(|url| BackendConfig {
+                    url: url.clone(),
+                    weight: default_weight(),
+                    secret: None,
+                    admin_url: None,
+                    admin_secret: None
+                })
+                .collect();
+        }
+        if cli.api_secret.is_some() {
+            self.auth.api_secret = cli.api_secret.clone();
+        }
+        if cli.admin_secret.is_some() {
+            self.auth.admin_secret = cli.admin_secret.clone();
+        }
+        match (&cli.tls_cert, &cli.tls_key) {
+            (Some(cert), Some(key)) => self.tls = Some(TlsConfig { cert: cert.clone(), key: key.clone() }),
+            (Some(cert), None) => self.tls = Some(TlsConfig { cert: cert.clone(), key: self.tls.as_ref().map(|x| x.key.clone()).unwrap_or_default() }),
+            (None, Some(key)) => self.tls = Some(TlsConfig { cert: self.tls.as_ref().map(|x| x.cert.clone()).unwrap_or_default(), key: key.clone() }),
+            (None, None) => {}
+        }
+        if let Some(x) = cli.session_timeout {
+            self.session.timeout = x;
+        }
+        if let Some(x) = &cli.log_level {
+            self.log.level = x.clone();
+        }
+        if let Some(x) = &cli.log_format {
+            self.log.format = x.clone();
+        }
+    }
+
+    /** Report every problem at once, so that nothing fails half way through startup */
+    pub fn validate(&self) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+
+        check_addr(&mut errors, "listen.websocket", &self.listen.websocket);
+        let mut addrs = vec![&self.listen.websocket];
+        if let Some(x) = &self.listen.http {
+            check_addr(&mut errors, "listen.http", x);
+            addrs.push(x);
+        }
+        if let Some(x) = &self.listen.admin {
+            check_addr(&mut errors, "listen.admin", x);
+            addrs.push(x);
+        }
+        if let Some(x) = &self.listen.metrics {
+            check_addr(&mut errors, "listen.metrics", x);
+            addrs.push(x);
+        }
+        if addrs.iter().collect::<HashSet<_>>().len() != addrs.len() {
+            errors.push("listen: listeners must use different addresses".to_string());
+        }
+
+        if let Some(tls) = &self.tls {
+            if let Err(e) = TlsAcceptor::new(&tls.cert, &tls.key) {
+                errors.push(format!("tls: {}", e));
+            }
+        }
+
+        if self.backends.is_empty() {
+            errors.push("backends: at least one janus-gateway instance is required".to_string());
+        }
+        let mut urls = HashSet::new();
+        for (i, backend) in self.backends.iter().enumerate() {
+            check_ws_url(&mut errors, &format!("backends[{}].url", i), &backend.url);
+            if let Some(x) = &backend.admin_url {
+                check_ws_url(&mut errors, &format!("backends[{}].admin_url", i), x);
+            }
+            if backend.weight == 0 {
+                errors.push(format!("backends[{}].weight: must be at least 1", i));
+            }
+            if !urls.insert(&backend.url) {
+                errors.push(format!("backends[{}].url: duplicated \"{}\"", i, backend.url));
+            }
+        }
+
+        if provider::strategy(&self.balancer.strategy).is_none() {
+            errors.push(format!("balancer.strategy: unknown strategy \"{}\"", self.balancer.strategy));
+        }
+        match &self.ids.strategy[..] {
+            "random" => {},
+            // Every instance would go through the same sequence
+            _ if self.state.provider == "redis" => errors.push("ids.strategy: only \"random\" ids can be shared between instances".to_string()),
+            x if provider::id_generator(x, 0).is_none() => errors.push(format!("ids.strategy: unknown strategy \"{}\"", x)),
+            _ => {}
+        }
+        if self.events.queue_size == 0 {
+            errors.push("events.queue_size: must be at least 1".to_string());
+        }
+        match janus::overflow_policy(&self.events.overflow) {
+            None => errors.push(format!("events.overflow: unknown policy \"{}\"", self.events.overflow)),
+            // A stalled reader would hold up responses to every session on the connection, until they time out
+            Some(EventOverflowPolicy::Backpressure) if self.backend_pool.sessions_per_connection > 1 => errors.push(
+                "events.overflow: \"backpressure\" requires backend_pool.sessions_per_connection = 1".to_string()
+            ),
+            _ => {}
+        }
+        if self.backend_pool.sessions_per_connection == 0 {
+            errors.push("backend_pool.sessions_per_connection: must be at least 1".to_string());
+        }
+
+        let health_check = &self.health_check;
+        if health_check.interval == 0 || health_check.timeout == 0 {
+            errors.push("health_check: interval and timeout must be at least 1 second".to_string());
+        }
+        if health_check.rise == 0 || health_check.fall == 0 {
+            errors.push("health_check: rise and fall must be at least 1".to_string());
+        }
+
+        match (&self.state.provider[..], &self.state.url) {
+            ("memory", _) => {},
+            ("redis", None) => errors.push("state.url: required by \"redis\" provider".to_string()),
+            ("redis", Some(url)) => if let Err(e) = RedisStateProvider::new(url) {
+                errors.push(format!("state.url: {}", e));
+            },
+            (x, _) => errors.push(format!("state.provider: unknown provider \"{}\"", x))
+        }
+        if self.state.lease == 0 {
+            errors.push("state.lease: must be at least 1 second".to_string());
+        }
+        if let Some(x) = &self.state.instance {
+            if x.is_empty() || x.contains(':') {
+                errors.push(format!("state.instance: invalid name \"{}\", must be non-empty without ':'", x));
+            }
+        }
+
+        if self.plugins.is_empty() {
+            errors.push("plugins: at least one plugin is required".to_string());
+        }
+        for name in self.plugins.iter() {
+            if JanusPluginProvider::builtin(name).is_none() {
+                errors.push(format!("plugins: unknown plugin \"{}\"", name));
+            }
+        }
+        if let Some(path) = &self.videoroom.rooms_file {
+            // Every instance would host the same rooms, each pinning them to a backend of its own
+            if self.state.provider == "redis" {
+                errors.push("videoroom.rooms_file: rooms aren't shared between instances, can't be used with \"redis\" state".to_string());
+            }
+            // Only parse it: the plugin isn't set up before `plugin_provider`
+            else if let Err(e) = FileVideoRoomState::open(path) {
+                errors.push(format!("videoroom.rooms_file: {}", e));
+            }
+        }
+
+        if let Err(e) = EnvFilter::try_new(&self.log.level) {
+            errors.push(format!("log.level: invalid filter \"{}\": {}", self.log.level, e));
+        }
+        if self.log.format != "text" && self.log.format != "json" {
+            errors.push(format!("log.format: unknown format \"{}\", expect \"text\" or \"json\"", self.log.format));
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /** Install the global `tracing` subscriber, configuration must be validated */
+    pub fn init_logging(&self) {
+        let builder = tracing_subscriber::fmt()
+            .with_env_filter(EnvFilter::new(&self.log.level));
+        if self.log.format == "json" {
+            builder.json().with_current_span(true).with_span_list(true).init();
+        } else {
+            builder.init();
+        }
+    }
+
+    /** A new generator of the configured strategy, each user gets its own sequence. Configuration must be validated */
+    pub fn id_generator(&self) -> Box<dyn IdGenerator> {
+        provider::id_generator(&self.ids.strategy, self.ids.seed).unwrap()
+    }
+
+    /** Session, handle and token storage, configuration must be validated */
+    pub fn state_provider(&self) -> Box<dyn ProxyStateProvider> {
+        match &self.state.url {
+            Some(url) if self.state.provider == "redis" => {
+                let mut provider = RedisStateProvider::new(url).unwrap()
+                    .with_id_generator(self.id_generator())
+                    .with_prefix(self.state.prefix.clone())
+                    .with_lease(Duration::from_secs(self.state.lease));
+                if let Some(x) = &self.state.instance {
+                    provider = provider.with_instance(x.clone());
+                }
+                Box::new(provider)
+            },
+            _ => Box::new(MemoryStateProvider::new().with_id_generator(self.id_generator()))
+        }
+    }
+
+    /** Backends with their weights and selection strategy, configuration must be validated */
+    pub fn backend_provider(&self) -> Box<dyn JanusBackendProvider> {
+        let strategy = provider::strategy(&self.balancer.strategy).unwrap();
+        match &self.state.url {
+            Some(url) if self.state.provider == "redis" => {
+                let provider = RedisBackendProvider::new(url).unwrap()
+                    .with_prefix(self.state.prefix.clone())
+                    .with_strategy(strategy);
+                for x in self.backends.iter() {
+                    provider.set_weight(x.url.clone(), x.weight);
+                }
+                Box::new(provider)
+            },
+            _ => {
+                let provider = MemoryBackendProvider::new().with_strategy(strategy);
+                for x in self.backends.iter() {
+                    provider.set_weight(x.url.clone(), x.weight);
+                }
+                Box::new(provider)
+            }
+        }
+    }
+
+    /** Policy for clients which don't keep up with events, configuration must be validated */
+    pub fn event_overflow(&self) -> EventOverflowPolicy {
+        janus::overflow_policy(&self.events.overflow).unwrap()
+    }
+
+    /** Backend health checking, None if disabled */
+    pub fn health_check(&self) -> Option<JanusHealthCheckConfig> {
+        if !self.health_check.enabled {
+            return None
+        }
+        Some(JanusHealthCheckConfig {
+            backends: self.backends.iter().map(|x| x.url.clone()).collect(),
+            interval: Duration::from_secs(self.health_check.interval),
+            timeout: Duration::from_secs(self.health_check.timeout),
+            rise: self.health_check.rise,
+            fall: self.health_check.fall,
+            probe_session: self.health_check.probe_session
+        })
+    }
+
+    /** Enabled plugins, configuration must be validated */
+    pub fn plugin_provider(&self) -> JanusPluginProvider {
+        self.plugins.iter().fold(JanusPluginProvider::empty(), |provider, name| {
+            let factory: Box<dyn JanusPluginFactory> = match &name[..] {
+                "janus.plugin.videoroom" => Box::new(
+                    VideoRoomPluginFactory::open(self.videoroom.rooms_file.as_deref(), self.id_generator()).unwrap()
+                ),
+                _ => JanusPluginProvider::builtin(name).unwrap()
+            };
+            provider.add(name.clone(), factory)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lazy_static::lazy_static;
+    use std::sync::{Mutex, MutexGuard};
+
+    lazy_static! {
+        /** `Cli` reads environment variables (not a map we could pass), held while any test parses or sets them */
+        static ref ENVIRONMENT: Mutex<()> = Mutex::new(());
+    }
+
+    fn environment() -> MutexGuard<'static, ()> {
+        // Not poisoned for good by a failed test
+        ENVIRONMENT.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn parse(text: &str) -> Config {
+        toml::from_str(text).unwrap()
+    }
+
+    /** Parse `args`, the environment must be locked */
+    fn parse_cli(args: &[&str]) -> Cli {
+        Cli::from_iter_safe(std::iter::once("janus-proxy").chain(args.iter().cloned())).unwrap()
+    }
+
+    fn cli(args: &[&str]) -> Cli {
+        let _environment = environment();
+        parse_cli(args)
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+        // Sections may be omitted
+        assert_eq!(parse("").validate(), Ok(()));
+        assert_eq!(parse("[session]\ntimeout = 0").validate(), Ok(()));
+        assert_eq!(parse("[events]\noverflow = \"backpressure\"\n[backend_pool]\nsessions_per_connection = 1").validate(), Ok(()));
+    }
+
+    #[test]
+    fn each_error_is_reported() {
+        let cases = [
+            ("[listen]\nwebsocket = \"nope\"", "listen.websocket: invalid socket address \"nope\""),
+            ("[listen]\nhttp = \"localhost\"", "listen.http: invalid socket address \"localhost\""),
+            ("[listen]\nadmin = \"1.2.3.4\"", "listen.admin: invalid socket address \"1.2.3.4\""),
+            ("[listen]\nmetrics = \":9090\"", "listen.metrics: invalid socket address \":9090\""),
+            ("[listen]\nhttp = \"0.0.0.0:3000\"", "listen: listeners must use different addresses"),
+            ("[tls]\ncert = \"/nonexistent/cert.pem\"\nkey = \"/nonexistent/key.pem\"",
+                "tls: Could not open \"/nonexistent/cert.pem\": No such file or directory (os error 2)"),
+            ("backends = []", "backends: at least one janus-gateway instance is required"),
+            ("[[backends]]\nurl = \"http://localhost:8188\"", "backends[0].url: invalid websocket url \"http://localhost:8188\""),
+            ("[[backends]]\nurl = \"ws://a\"\nadmin_url = \"a:7188\"", "backends[0].admin_url: invalid websocket url \"a:7188\""),
+            ("[[backends]]\nurl = \"ws://a\"\nweight = 0", "backends[0].weight: must be at least 1"),
+            ("[[backends]]\nurl = \"ws://a\"\n[[backends]]\nurl = \"ws://a\"", "backends[1].url: duplicated \"ws://a\""),
+            ("[balancer]\nstrategy = \"fastest\"", "balancer.strategy: unknown strategy \"fastest\""),
+            ("[ids]\nstrategy = \"uuid\"", "ids.strategy: unknown strategy \"uuid\""),
+            ("[ids]\nstrategy = \"monotonic\"\n[state]\nprovider = \"redis\"\nurl = \"redis://127.0.0.1/\"",
+                "ids.strategy: only \"random\" ids can be shared between instances"),
+            ("[events]\nqueue_size = 0", "events.queue_size: must be at least 1"),
+            ("[events]\noverflow = \"explode\"", "events.overflow: unknown policy \"explode\""),
+            ("[events]\noverflow = \"backpressure\"", "events.overflow: \"backpressure\" requires backend_pool.sessions_per_connection = 1"),
+            ("[backend_pool]\nsessions_per_connection = 0", "backend_pool.sessions_per_connection: must be at least 1"),
+            ("[health_check]\ntimeout = 0", "health_check: interval and timeout must be at least 1 second"),
+            ("[health_check]\nfall = 0", "health_check: rise and fall must be at least 1"),
+            ("[state]\nprovider = \"redis\"", "state.url: required by \"redis\" provider"),
+            ("[state]\nprovider = \"redis\"\nurl = \"http://127.0.0.1/\"", "state.url: Invalid redis url \"http://127.0.0.1/\""),
+            ("[state]\nprovider = \"redis\"\nurl = \"redis://127.0.0.1/\"\n[videoroom]\nrooms_file = \"/nonexistent/rooms.json\"", "videoroom.rooms_file: rooms aren't shared between instances"),
+            ("[state]\nprovider = \"etcd\"", "state.provider: unknown provider \"etcd\""),
+            ("[state]\nlease = 0", "state.lease: must be at least 1 second"),
+            ("[state]\ninstance = \"a:b\"", "state.instance: invalid name \"a:b\", must be non-empty without ':'"),
+            ("plugins = []", "plugins: at least one plugin is required"),
+            ("plugins = [\"janus.plugin.videoroom\", \"janus.plugin.echotest\"]", "plugins: unknown plugin \"janus.plugin.echotest\""),
+            ("[log]\nformat = \"xml\"", "log.format: unknown format \"xml\", expect \"text\" or \"json\"")
+        ];
+        for (text, error) in cases.iter() {
+            let errors = parse(text).validate().unwrap_err();
+            assert_eq!(errors.len(), 1, "{}: {:?}", text, errors);
+            assert!(errors[0].starts_with(error), "{}: {:?}", text, errors);
+        }
+
+        // Everything at once
+        let errors = parse("backends = []\nplugins = []\n[events]\nqueue_size = 0").validate().unwrap_err();
+        assert_eq!(errors.len(), 3);
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let path = std::env::temp_dir().join(format!("janus-proxy-config-{}.toml", std::process::id()));
+        std::fs::write(&path, "[listen]\nwebsocket = \"127.0.0.1:3000\"\n[session]\ntimeout = 10\n[[backends]]\nurl = \"ws://a\"").unwrap();
+        let path = path.to_str().unwrap();
+
+        let config = Config::load(&cli(&["--config", path])).unwrap();
+        assert_eq!((&config.listen.websocket[..], config.session.timeout), ("127.0.0.1:3000", 10));
+
+        let config = Config::load(&cli(&[
+            "--config", path,
+            "--listen", "127.0.0.1:4000",
+            "--http-listen", "127.0.0.1:4001",
+            "--session-timeout", "20",
+            "--backend", "ws://b,ws://c",
+            "--backend", "ws://d",
+            "--api-secret", "secret",
+            "--log-format", "json"
+        ])).unwrap();
+        assert_eq!(config.listen.websocket, "127.0.0.1:4000");
+        assert_eq!(config.listen.http.as_deref(), Some("127.0.0.1:4001"));
+        assert_eq!(config.session.timeout, 20);
+        assert_eq!(config.backends.iter().map(|x| &x.url[..]).collect::<Vec<_>>(), vec!["ws://b", "ws://c", "ws://d"]);
+        assert_eq!(config.auth.api_secret.as_deref(), Some("secret"));
+        assert_eq!(config.log.format, "json");
+        assert_eq!(config.validate(), Ok(()));
+
+        // Either half of the TLS pair may be given, the other one comes from the file
+        let config = Config::load(&cli(&["--tls-cert", "/tmp/cert.pem"])).unwrap();
+        let tls = config.tls.unwrap();
+        assert_eq!((tls.cert, tls.key), (PathBuf::from("/tmp/cert.pem"), PathBuf::new()));
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let (with_environment, with_flag) = {
+            let _environment = environment();
+            std::env::set_var("JANUS_PROXY_ADMIN_LISTEN", "127.0.0.1:7188");
+            let parsed = (parse_cli(&[]), parse_cli(&["--admin-listen", "127.0.0.1:7189"]));
+            std::env::remove_var("JANUS_PROXY_ADMIN_LISTEN");
+            parsed
+        };
+        let config = Config::load(&with_environment).unwrap();
+        assert_eq!(config.listen.admin.as_deref(), Some("127.0.0.1:7188"));
+        // Flags win over environment
+        let config = Config::load(&with_flag).unwrap();
+        assert_eq!(config.listen.admin.as_deref(), Some("127.0.0.1:7189"));
+    }
+}
@@ -0,0 +1,504 @@
+use futures::{StreamExt, SinkExt};
+use futures::future::join_all;
+use tokio::net::TcpListener;
+use tokio_tungstenite::tungstenite::Message;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{info, warn, error, info_span, Instrument};
+use super::{JanusProxy, ProxyState};
+use super::helper;
+use super::metrics::{self, GaugeGuard};
+use super::core::json::{self, *};
+use super::core::apierror::*;
+use super::core::request::*;
+use super::core::response::JanusResponse;
+use super::gateway::JanusGateway;
+use super::connection::{accept_ws, JANUS_ADMIN_PROTOCOL};
+
+/** Admin API endpoint of a janus-gateway instance */
+#[derive(Clone)]
+pub struct JanusAdminBackend {
+    pub url: String,
+    pub secret: Option<String>
+}
+
+/** Admin API settings, mirror janus-gateway `admin_secret` */
+#[derive(Default)]
+pub struct JanusAdminConfig {
+    pub secret: Option<String>,
+    /** Admin endpoints, keyed by janus-gateway url (as given to backend provider) */
+    pub backends: HashMap<String, JanusAdminBackend>
+}
+
+/** Core settings, applied to every janus-gateway instance */
+static BROADCAST_REQUESTS: [&str; 11] = [
+    "get_status",
+    "set_session_timeout",
+    "set_log_level",
+    "set_log_timestamps",
+    "set_log_colors",
+    "set_locking_debug",
+    "set_refcount_debug",
+    "set_libnice_debug",
+    "set_min_nack_queue",
+    "set_no_media_timer",
+    "set_slowlink_threshold"
+];
+
+/** Handle-level requests, forwarded to the janus-gateway instance owning the handle */
+static HANDLE_REQUESTS: [&str; 6] = [
+    "handle_info",
+    "start_pcap",
+    "stop_pcap",
+    "start_text2pcap",
+    "stop_text2pcap",
+    "hangup_webrtc"
+];
+
+pub(crate) async fn serve(janus: Arc<JanusProxy>, mut listener: TcpListener) {
+    // Admin API stays available while draining, for monitoring
+    loop {
+        let (stream, addr) = tokio::select! {
+            x = listener.accept() => match x {
+                Ok(x) => x,
+                Err(_) => break
+            },
+            _ = janus.wait_for(ProxyState::Stopped) => break
+        };
+
+        let janus = Arc::clone(&janus);
+        tokio::spawn(async move {
+            let mut ws = match accept_ws(stream, JANUS_ADMIN_PROTOCOL).await {
+                Ok(x) => x,
+                Err(e) => return warn!(error = %e, "Websocket handshake failed")
+            };
+
+            info!("New admin connection");
+            let _connected = GaugeGuard::new(metrics::CONNECTIONS.with_label_values(&["admin"]));
+            while let Some(item) = ws.next().await {
+                let message = match item {
+                    Ok(Message::Text(data)) => {
+                        let response = match json::parse(&data) {
+                            Ok(request) => janus.handle_admin_request(request).await,
+                            Err(e) => JanusResponse::bad_request(e)
+                        };
+                        response.into()
+                    },
+                    Ok(Message::Close(_)) => break,
+                    Ok(x) => x,
+                    Err(e) => {
+                        error!(error = %e, "Internal error");
+                        break
+                    }
+                };
+
+                if ws.send(message).await.is_err() {
+                    break
+                }
+            }
+        }.instrument(info_span!("admin_connection", peer = %addr)));
+    }
+}
+
+impl JanusProxy {
+    /** Ported from janus-gateway `janus_process_incoming_admin_request` */
+    async fn handle_admin_request(&self, request: IncomingRequestParameters) -> JanusResponse {
+        let IncomingRequestParameters {
+            transaction,
+            janus: message_text,
+            session_id,
+            handle_id,
+            mut rest,
+            ..
+        } = request;
+
+        let response_transaction = transaction.clone();
+        let response_error = |e: JanusError| {
+            JanusResponse::new("error", session_id, response_transaction).with_err(e)
+        };
+
+        let response = async {
+            if session_id == 0 && handle_id == 0 {
+                match &message_text[..] {
+                    "ping" => return Ok(JanusResponse::new("pong", 0, transaction)),
+                    "info" => return Ok(JanusResponse::new("server_info", 0, transaction).with_fields(self.server_info().await)),
+                    _ => {}
+                }
+            }
+
+            self.verify_admin_secret(&mut rest)?;
+
+            if session_id == 0 && handle_id == 0 {
+                return match &message_text[..] {
+                    "accept_new_sessions" => {
+                        let params: AnsParameters = json::from_object(rest)?;
+                        self.accept_new_sessions(params.accept);
+                        Ok(JanusResponse::new("success", 0, transaction).with_field("accept", json!(params.accept)))
+                    },
+                    "list_sessions" => {
+                        let sessions = self.sessions.read().await.keys().cloned().collect::<Vec<u64>>();
+                        Ok(JanusResponse::new("success", 0, transaction).with_field("sessions", json!(sessions)))
+                    },
+                    // Sessions of every proxy instance sharing state, not only this one
+                    "list_instance_sessions" => {
+                        let mut instances = self.state.list_sessions().await;
+                        for sessions in instances.values_mut() {
+                            sessions.sort();
+                        }
+                        Ok(JanusResponse::new("success", 0, transaction).with_field("instances", json!(instances)))
+                    },
+                    "add_token" => {
+                        self.verify_token_auth()?;
+                        let params: AddTokenParameters = json::from_object(rest)?;
+
+                        // No plugins specified, or none: give access to all plugins, like janus-gateway.
+                        // Providers only allow the plugins listed, so these are stored explicitly.
+                        let plugins = match params.plugins {
+                            Some(x) if !x.is_empty() => {
+                                if !x.iter().all(|name| self.plugins.has(name)) {
+                                    return Err(JanusError::new(JANUS_ERROR_INVALID_ELEMENT_TYPE, "Invalid element type (some of the provided plugins are invalid)".to_string()))
+                                }
+                                x
+                            },
+                            _ => self.plugins.names()
+                        };
+
+                        self.state.add_token(&params.token, plugins.clone()).await;
+                        Ok(JanusResponse::new("success", 0, transaction).with_data(json!({ "plugins": plugins })))
+                    },
+                    "remove_token" => {
+                        self.verify_token_auth()?;
+                        let params: TokenParameters = json::from_object(rest)?;
+                        if !self.state.remove_token(&params.token).await {
+                            return Err(JanusError::new(JANUS_ERROR_TOKEN_NOT_FOUND, format!("Token {} not found", params.token)))
+                        }
+                        Ok(JanusResponse::new("success", 0, transaction))
+                    },
+                    "list_tokens" => {
+                        self.verify_token_auth()?;
+                        let mut tokens = self.state.list_tokens().await;
+                        tokens.sort();
+                        let tokens = tokens.into_iter()
+                            .map(|(token, mut plugins)| {
+                                plugins.sort();
+                                json!({ "token": token, "allowed_plugins": plugins })
+                            })
+                            .collect::<Vec<JSON_ANY>>();
+                        Ok(JanusResponse::new("success", 0, transaction).with_data(json!({ "tokens": tokens })))
+                    },
+                    x if BROADCAST_REQUESTS.contains(&x) => {
+                        Self::validate_admin_settings(x, &rest)?;
+                        let mut response = self.admin_broadcast(x, rest).await?;
+                        response.transaction = transaction;
+                        Ok(response)
+                    },
+                    x => Err(
+                        JanusError::new(JANUS_ERROR_INVALID_REQUEST_PATH, format!("Unhandled request '{}' at this path", x))
+                    )
+                }
+            }
+
+            let session = match self.sessions.read().await.get(&session_id) {
+                Some(x) => Arc::clone(x),
+                None => return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("No such session {}", session_id)))
+            };
+
+            /* Session-level request */
+            if handle_id == 0 {
+                return match &message_text[..] {
+                    "list_handles" => {
+                        let handles = session.handles.read().await.keys().cloned().collect::<Vec<u64>>();
+                        Ok(JanusResponse::new("success", session_id, transaction).with_field("handles", json!(handles)))
+                    },
+                    x => Err(
+                        JanusError::new(JANUS_ERROR_INVALID_REQUEST_PATH, format!("Unhandled request '{}' at this path", x))
+                    )
+                }
+            }
+
+            /* Handle-level request */
+            let handle = match session.handles.read().await.get(&handle_id) {
+                Some(x) => Arc::clone(x),
+                None => return Err(
+                    JanusError::new(JANUS_ERROR_HANDLE_NOT_FOUND, format!("No such handle {} in session {}", handle_id, session_id))
+                )
+            };
+
+            match &message_text[..] {
+                "detach_handle" => {
+                    self.state.remove_handle(&handle_id).await;
+                    if session.handles.write().await.remove(&handle_id).is_some() {
+                        metrics::HANDLES.dec();
+                    }
+                    handle.destroy_gateway().await;
+                    Ok(JanusResponse::new("success", session_id, transaction).with_field("handle_id", json!(handle_id)))
+                },
+                x if HANDLE_REQUESTS.contains(&x) => {
+                    match x {
+                        "handle_info" => { json::from_object::<HandleInfoParameters>(rest.clone())?; },
+                        "start_text2pcap" => { json::from_object::<Text2pcapParameters>(rest.clone())?; },
+                        _ => {}
+                    };
+
+                    let (url, backend_session, backend_handle) = match handle.backend().await {
+                        Some(x) => x,
+                        None if x == "handle_info" => {
+                            // Not talking to janus-gateway yet, only proxy knows about this handle
+                            let info = json!({
+                                "session_id": session_id,
+                                "handle_id": handle_id,
+                                "plugin": handle.plugin.get_name()
+                            });
+                            return Ok(JanusResponse::new("success", session_id, transaction)
+                                .with_field("handle_id", json!(handle_id))
+                                .with_field("info", info))
+                        },
+                        None => return Err(
+                            JanusError::new(JANUS_ERROR_GATEWAY_UNAVAILABLE, format!("Handle {} is not attached to any janus-gateway instance", handle_id))
+                        )
+                    };
+
+                    let mut request = IncomingRequestParameters::prepare(x.to_string(), None, None);
+                    request.transaction = transaction.clone();
+                    request.session_id = backend_session;
+                    request.handle_id = backend_handle;
+                    request.rest = rest;
+
+                    let mut response = self.admin_forward(&url, request).await?;
+
+                    // Translate backend ids back to proxy ids
+                    response.transaction = transaction;
+                    response.session_id = session_id;
+                    if response.rest.contains_key("handle_id") {
+                        response.rest.insert("handle_id".to_string(), json!(handle_id));
+                    }
+                    if let Some(info) = response.rest.get_mut("info").and_then(|x| x.as_object_mut()) {
+                        info.insert("session_id".to_string(), json!(session_id));
+                        info.insert("handle_id".to_string(), json!(handle_id));
+                    }
+                    Ok(response)
+                },
+                x => Err(
+                    JanusError::new(JANUS_ERROR_INVALID_REQUEST_PATH, format!("Unhandled request '{}' at this path", x))
+                )
+            }
+        };
+
+        response.await.unwrap_or_else(response_error)
+    }
+
+    fn verify_admin_secret(&self, rest: &mut JSON_OBJECT) -> Result<(), JanusError> {
+        let given = rest.remove("admin_secret");
+        let secret = match &self.admin.secret {
+            None => return Ok(()),
+            Some(x) => x
+        };
+
+        match given.as_ref().and_then(|x| x.as_str()) {
+            Some(x) if helper::secure_compare(x, secret) => Ok(()),
+            _ => Err(JanusError::new(JANUS_ERROR_UNAUTHORIZED, "Unauthorized request (wrong or missing secret/token)".to_string()))
+        }
+    }
+
+    fn verify_token_auth(&self) -> Result<(), JanusError> {
+        if !self.token_auth {
+            return Err(JanusError::new(JANUS_ERROR_UNKNOWN, "Stored-Token based authentication disabled".to_string()))
+        }
+        Ok(())
+    }
+
+    fn validate_admin_settings(request: &str, rest: &JSON_OBJECT) -> Result<(), JanusError> {
+        let rest = rest.clone();
+        match request {
+            "set_session_timeout" => { json::from_object::<TimeoutParameters>(rest)?; },
+            "set_log_level" => {
+                let params: LevelParameters = json::from_object(rest)?;
+                if params.level > 7 {
+                    return Err(JanusError::new(JANUS_ERROR_INVALID_ELEMENT_TYPE, "Invalid element type (level should be between 0 and 7)".to_string()))
+                }
+            },
+            "set_log_timestamps" => { json::from_object::<TimestampsParameters>(rest)?; },
+            "set_log_colors" => { json::from_object::<ColorsParameters>(rest)?; },
+            "set_locking_debug" | "set_refcount_debug" | "set_libnice_debug" => { json::from_object::<DebugParameters>(rest)?; },
+            "set_min_nack_queue" => { json::from_object::<MnqParameters>(rest)?; },
+            "set_no_media_timer" => { json::from_object::<NmtParameters>(rest)?; },
+            "set_slowlink_threshold" => { json::from_object::<StParameters>(rest)?; },
+            _ => {}
+        };
+        Ok(())
+    }
+
+    /**
+     * Send request to every janus-gateway Admin API, reply with the first response.
+     * It's applied to every instance which accepts it even if others fail, the error lists the failed ones.
+     */
+    async fn admin_broadcast(&self, request: &str, rest: JSON_OBJECT) -> Result<JanusResponse, JanusError> {
+        let mut urls = self.admin.backends.keys().cloned().collect::<Vec<String>>();
+        urls.sort();
+
+        let responses = join_all(urls.iter().map(|url| {
+            let mut params = IncomingRequestParameters::prepare(request.to_string(), None, None);
+            params.rest = rest.clone();
+            self.admin_forward(url, params)
+        })).await;
+
+        let mut result = None;
+        let mut failures = Vec::new();
+        for (url, response) in urls.iter().zip(responses) {
+            match response {
+                Ok(JanusResponse { error: Some(e), .. }) | Err(e) => failures.push((url, e)),
+                Ok(x) => {
+                    result.get_or_insert(x);
+                }
+            }
+        }
+
+        if let Some((_, first)) = failures.first() {
+            let reasons = failures.iter()
+                .map(|(url, e)| format!("janus-gateway \"{}\": {}", url, e.reason))
+                .collect::<Vec<_>>();
+            return Err(JanusError::new(first.code, format!(
+                "Failed on {} of {} janus-gateway instances, applied to the others: {}", failures.len(), urls.len(), reasons.join("; ")
+            )))
+        }
+        Ok(result.unwrap_or_else(|| JanusResponse::new("success", 0, String::new())))
+    }
+
+    async fn admin_forward(&self, url: &str, mut request: IncomingRequestParameters) -> Result<JanusResponse, JanusError> {
+        let backend = match self.admin.backends.get(url) {
+            Some(x) => x,
+            None => return Err(
+                JanusError::new(JANUS_ERROR_GATEWAY_UNAVAILABLE, format!("No admin endpoint configured for janus-gateway \"{}\"", url))
+            )
+        };
+
+        if let Some(secret) = &backend.secret {
+            request.rest.insert("admin_secret".to_string(), json!(secret));
+        }
+
+        let gateway = self.admin_gateway(url, backend).await?;
+        gateway.send(request, false).await
+    }
+
+    /** Admin connection to janus-gateway `url`, reopened once closed */
+    async fn admin_gateway(&self, url: &str, backend: &JanusAdminBackend) -> Result<Arc<JanusGateway>, JanusError> {
+        if let Some(x) = self.admin_gateways.lock().unwrap().get(url) {
+            if !x.is_closed() {
+                return Ok(Arc::clone(x))
+            }
+        }
+
+        let gateway = JanusGateway::connect_admin(backend.url.clone()).await?;
+        self.admin_gateways.lock().unwrap().insert(url.to_string(), Arc::clone(&gateway));
+        Ok(gateway)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use crate::janus::testing::{self, FakeJanus, WsClient};
+
+    /** Admin API of each of `backends`, at the same url as Janus API */
+    fn admin_config(secret: Option<&str>, backends: &[&FakeJanus]) -> JanusAdminConfig {
+        JanusAdminConfig {
+            secret: secret.map(String::from),
+            backends: backends.iter()
+                .map(|x| (x.url.clone(), JanusAdminBackend { url: x.url.clone(), secret: None }))
+                .collect()
+        }
+    }
+
+    fn request(janus: &str, fields: Value) -> Value {
+        let mut request = json!({"janus": janus, "transaction": janus});
+        request.as_object_mut().unwrap().extend(fields.as_object().unwrap().clone());
+        request
+    }
+
+    #[tokio::test]
+    async fn admin_secret_is_required() {
+        let janus = testing::proxy(&[]).await.with_admin(admin_config(Some("secret"), &[]));
+        let mut admin = WsClient::connect(&testing::listen_admin(Arc::new(janus)).await).await;
+
+        assert_eq!(admin.request(request("ping", json!({}))).await["janus"], "pong");
+        for fields in [json!({}), json!({"admin_secret": "wrong"})].iter() {
+            let response = admin.request(request("list_sessions", fields.clone())).await;
+            assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+        }
+        let response = admin.request(request("list_sessions", json!({"admin_secret": "secret"}))).await;
+        assert_eq!(response["sessions"], json!([]));
+    }
+
+    #[tokio::test]
+    async fn backend_ids_are_translated() {
+        let backend = FakeJanus::start().await;
+        let janus = Arc::new(testing::proxy(&[&backend.url]).await.with_admin(admin_config(None, &[&backend])));
+        let mut client = WsClient::connect(&testing::listen(Arc::clone(&janus)).await).await;
+        let mut admin = WsClient::connect(&testing::listen_admin(janus).await).await;
+        let session = client.create().await;
+        let handle = client.attach(session, "janus.plugin.videoroom").await;
+
+        let response = admin.request(request("list_sessions", json!({}))).await;
+        assert_eq!(response["sessions"], json!([session]));
+        let response = admin.request(request("list_handles", json!({"session_id": session}))).await;
+        assert_eq!(response["handles"], json!([handle]));
+
+        // Known to the proxy only so far
+        let response = admin.request(request("handle_info", json!({"session_id": session, "handle_id": handle}))).await;
+        assert_eq!(response["info"], json!({"session_id": session, "handle_id": handle, "plugin": "janus.plugin.videoroom"}));
+        assert_eq!(backend.requests("handle_info"), 0);
+
+        client.join(session, handle).await;
+        let response = admin.request(request("handle_info", json!({"session_id": session, "handle_id": handle}))).await;
+        assert_eq!(backend.requests("handle_info"), 1);
+        assert_eq!((&response["session_id"], &response["handle_id"]), (&json!(session), &json!(handle)));
+        assert_eq!((&response["info"]["session_id"], &response["info"]["handle_id"]), (&json!(session), &json!(handle)));
+    }
+
+    #[tokio::test]
+    async fn tokens_without_plugins_allow_all_plugins() {
+        let janus = Arc::new(testing::proxy(&[]).await.with_token_auth(true).with_admin(admin_config(None, &[])));
+        let mut client = WsClient::connect(&testing::listen(Arc::clone(&janus)).await).await;
+        let mut admin = WsClient::connect(&testing::listen_admin(janus).await).await;
+
+        let response = admin.request(request("add_token", json!({"token": "a", "plugins": ["janus.plugin.echotest"]}))).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_INVALID_ELEMENT_TYPE);
+        for (token, plugins) in [("a", json!({})), ("b", json!({"plugins": []}))].iter() {
+            let mut fields = json!({"token": token});
+            fields.as_object_mut().unwrap().extend(plugins.as_object().unwrap().clone());
+            let response = admin.request(request("add_token", fields)).await;
+            assert_eq!(response["data"]["plugins"], json!(["janus.plugin.videoroom"]));
+
+            let response = client.request(json!({"janus": "create", "token": token, "transaction": "c"})).await;
+            let session = response["data"]["id"].as_u64().unwrap();
+            let response = client.request(json!({"janus": "attach", "session_id": session, "plugin": "janus.plugin.videoroom", "token": token, "transaction": "a"})).await;
+            assert_eq!(response["janus"], "success");
+        }
+
+        let response = admin.request(request("list_tokens", json!({}))).await;
+        assert_eq!(response["data"]["tokens"], json!([
+            {"token": "a", "allowed_plugins": ["janus.plugin.videoroom"]},
+            {"token": "b", "allowed_plugins": ["janus.plugin.videoroom"]}
+        ]));
+    }
+
+    #[tokio::test]
+    async fn broadcast_goes_on_after_a_failure() {
+        let (a, b) = (FakeJanus::start().await, FakeJanus::start().await);
+        let janus = testing::proxy(&[]).await.with_admin(admin_config(None, &[&a, &b]));
+        let mut admin = WsClient::connect(&testing::listen_admin(Arc::new(janus)).await).await;
+        let failing = if a.url < b.url { &a } else { &b };
+        failing.fail("set_session_timeout", 1);
+
+        let response = admin.request(request("set_session_timeout", json!({"timeout": 30}))).await;
+        assert_eq!(response["janus"], "error");
+        let reason = response["error"]["reason"].as_str().unwrap();
+        assert!(reason.starts_with("Failed on 1 of 2") && reason.contains(&failing.url), "{}", reason);
+        assert_eq!((a.requests("set_session_timeout"), b.requests("set_session_timeout")), (1, 1));
+
+        let response = admin.request(request("set_session_timeout", json!({"timeout": 30}))).await;
+        assert_eq!(response["janus"], "success");
+    }
+}
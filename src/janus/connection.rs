@@ -1,27 +1,33 @@
 use tokio::net::{TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_tungstenite::WebSocketStream;
 use tokio_tungstenite::tungstenite::handshake::server::{Response, Request, ErrorResponse, Callback};
 use tokio_tungstenite::tungstenite::Error;
 
-struct WithProtocolHeader;
+pub(crate) static JANUS_PROTOCOL: &str = "janus-protocol";
+pub(crate) static JANUS_ADMIN_PROTOCOL: &str = "janus-admin-protocol";
+
+struct WithProtocolHeader(&'static str);
 
 impl<'a> Callback for WithProtocolHeader {
     fn on_request(self, _request: &Request, mut response: Response) -> Result<Response, ErrorResponse> {
         response.headers_mut()
-            .append("Sec-WebSocket-Protocol", "janus-protocol".parse().unwrap());
+            .append("Sec-WebSocket-Protocol", self.0.parse().unwrap());
         return Ok(response);
     }
 }
 
-pub(crate) async fn accept_ws<'a>(stream: TcpStream) -> Result<WebSocketStream<TcpStream>, Error> {
-    tokio_tungstenite::accept_hdr_async(stream, WithProtocolHeader).await
+pub(crate) async fn accept_ws<S>(stream: S, protocol: &'static str) -> Result<WebSocketStream<S>, Error>
+    where S: AsyncRead + AsyncWrite + Unpin
+{
+    tokio_tungstenite::accept_hdr_async(stream, WithProtocolHeader(protocol)).await
 }
 
-pub(crate) async fn new_backend_connection(janus_server: &str) -> Result<WebSocketStream<TcpStream>, Error> {
+pub(crate) async fn new_backend_connection(janus_server: &str, protocol: &str) -> Result<WebSocketStream<TcpStream>, Error> {
     let janus_request = Request::builder()
         .uri(janus_server)
         .method("GET")
-        .header("Sec-WebSocket-Protocol", "janus-protocol")
+        .header("Sec-WebSocket-Protocol", protocol)
         .body(())
         .unwrap();
 
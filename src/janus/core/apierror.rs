@@ -92,6 +92,9 @@ pub static JANUS_ERROR_GATEWAY_UNAVAILABLE: u32 = 502;
 /** Proxy error: janus-gateway connection closed */
 pub static JANUS_ERROR_GATEWAY_CONNECTION_CLOSED: u32 = 503;
 
+/** Proxy error: session is connected to another janus-gateway instance than the one required */
+pub static JANUS_ERROR_GATEWAY_MISMATCH: u32 = 504;
+
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JanusError {
@@ -5,17 +5,21 @@ pub mod ice;
 #[allow(dead_code)]
 pub mod apierror;
 
-use std::collections::HashMap;
+use futures::future::{BoxFuture, FutureExt};
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Weak};
-use tokio::sync::mpsc;
-use tokio::sync::RwLock;
+use tokio::sync::mpsc::{self, error::SendError};
+use tokio::sync::{Mutex, Notify, RwLock};
 use tokio::stream::StreamExt;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use tokio_tungstenite::tungstenite::Message;
+use tracing::{info, warn, info_span, Instrument};
 use super::plugin::{JanusPlugin, JanusPluginMessage};
 use super::response::JanusResponse;
 use super::gateway::JanusGateway;
+use super::events::{event_queue, is_droppable_message};
 use super::JanusProxy;
+use super::metrics;
 use self::apierror::*;
 use self::json::*;
 use self::request::IncomingRequestParameters;
@@ -27,147 +31,170 @@ pub struct Gateway {
     handle: u64
 }
 
+/** Transport a session is currently bound to, see `JanusSession::bind` */
+#[derive(Default)]
+struct SessionBinding {
+    /** Transport id and its event sender */
+    target: Option<(u64, mpsc::Sender<Message>)>,
+    /** Events not delivered yet: detached, transport is gone or busy */
+    pending: VecDeque<Message>,
+    /** Bumped on every bind/release, so stale reclaim timers can tell */
+    epoch: u64
+}
+
+impl SessionBinding {
+    /**
+     * Keep `message` for the next transport. Once `capacity` are kept, the oldest non-critical one ("media", "slowlink")
+     * is dropped, or `message` itself if it's one, otherwise the oldest one
+     */
+    fn keep(&mut self, message: Message, capacity: usize) {
+        if self.pending.len() >= capacity {
+            metrics::DROPPED_EVENTS.with_label_values(&["detached"]).inc();
+            match self.pending.iter().position(is_droppable_message) {
+                Some(i) => {
+                    self.pending.remove(i);
+                },
+                None if is_droppable_message(&message) => return,
+                None => {
+                    self.pending.pop_front();
+                }
+            }
+        }
+        self.pending.push_back(message);
+    }
+}
+
 pub struct JanusSession {
     pub id: u64,
     pub handles: RwLock<HashMap<u64, Arc<JanusHandle>>>,
 
-    /** State of the session, true when session actually created */
-    pub initialized: RwLock<bool>,
-
     /** App: for global state access */
     pub app: Arc<JanusProxy>,
 
-    /** Underlying pseudo websocket connection, impl by channel, send only */
+    /** Event queue of this session, delivered to whichever transport it's bound to, send only */
     pub connection: mpsc::Sender<Message>,
 
-    /** Janus gateway connection, only initialize once, so it's more like an `Arc` */
-    pub gateway: RwLock<Option<Gateway>>
+    binding: Arc<Mutex<SessionBinding>>,
+    /** Wake the delivery task on `bind`, so that pending events are flushed */
+    bound: Arc<Notify>,
+
+    /** Last time a client request (or long-poll) came in, see `JanusProxy::session_timeout` */
+    last_activity: Mutex<Instant>
 }
 
 impl JanusSession {
-    pub fn new(app: Arc<JanusProxy>, id: u64, connection: mpsc::Sender<Message>) -> JanusSession {
-        JanusSession {
-            id, connection, app,
-            handles: RwLock::new(HashMap::new()),
-            initialized: RwLock::new(false),
-            gateway: RwLock::new(None)
-        }
-    }
+    /** Create a detached session, events are queued until a transport `bind` it */
+    pub fn new(app: Arc<JanusProxy>, id: u64) -> JanusSession {
+        let (connection, mut rx) = mpsc::channel::<Message>(32);
+        let binding = Arc::new(Mutex::new(SessionBinding::default()));
 
-    pub async fn init_gateway(&self, plugin: &str, handle_id: u64) -> Result<(), JanusError> {
-        // TODO: support multiple janus-gateway instances, one per handle
-        if self.gateway.read().await.is_none() {
-            let url = match self.app.backend.get_backend() {
-                None => return Err(JanusError::new(JANUS_ERROR_GATEWAY_UNAVAILABLE, String::from("No janus-gateway instance available"))),
-                Some(x) => x
-            };
+        let bound = Arc::new(Notify::new());
 
-            // TODO: is unbounded safe?
-            let (tx, mut rx) = mpsc::unbounded_channel::<JanusResponse>();
-            let mut wtx = self.connection.clone();
-
-            let session_id = self.id;
-            tokio::spawn(async move {
-                while let Some(mut x) = rx.recv().await {
-                    if x.session_id != 0 {
-                        x.session_id = session_id;
-                    }
-                    if x.sender != 0 {
-                        x.sender = handle_id;
-                    }
-
-                    let text = Message::Text(x.stringify().unwrap());
-                    if wtx.send(text).await.is_err() {
-                        break;
-                    }
+        let capacity = app.event_queue_size;
+        let binding_ref = Arc::clone(&binding);
+        let bound_ref = Arc::clone(&bound);
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    x = rx.recv() => match x {
+                        Some(message) => binding_ref.lock().await.keep(message, capacity),
+                        None => break
+                    },
+                    _ = bound_ref.notified() => {}
                 }
-            });
+                Self::deliver(&binding_ref).await;
+            }
+        });
 
-            // TODO: modify session_id, sender
-            let backend = JanusGateway::connect(url, tx).await?;
-            let (session, handle) = Self::get_plugin_handle(&backend, plugin).await?;
+        JanusSession {
+            id, connection, app, binding, bound,
+            last_activity: Mutex::new(Instant::now()),
+            handles: RwLock::new(HashMap::new())
+        }
+    }
 
-            // TODO: This may block the above? YES!!!
-            let gateway = Arc::downgrade(&Arc::clone(&backend));
-            tokio::spawn(async move {
-                loop {
-                    tokio::time::delay_for(Duration::from_secs(15)).await;
-                    let gateway = match gateway.upgrade() {
-                        None => break,
-                        Some(x) => x
-                    };
-
-                    let mut request = IncomingRequestParameters::prepare("keepalive".to_string(), None, None);
-                    request.session_id = session;
-
-                    let response = gateway.send(request, false).await;
-
-                    // Stop ping
-                    if let Err(e) = response {
-                        if e.code == JANUS_ERROR_GATEWAY_CONNECTION_CLOSED {
-                            println!("Connection to janus-gateway closed, stop ping");
-                            break
-                        }
-                    }
+    /**
+     * Forward pending events to the bound transport until there's none left or it's gone.
+     * The binding isn't locked while waiting for the transport (e.g. a long-poll client lagging behind),
+     * requests need it meanwhile.
+     */
+    async fn deliver(binding: &Mutex<SessionBinding>) {
+        loop {
+            let (owner, mut tx, message) = {
+                let mut binding = binding.lock().await;
+                let (owner, tx) = match &binding.target {
+                    Some((owner, tx)) => (*owner, tx.clone()),
+                    None => return
+                };
+                match binding.pending.pop_front() {
+                    Some(x) => (owner, tx, x),
+                    None => return
                 }
-            });
+            };
 
-            *self.gateway.write().await = Some(Gateway {
-                instance: Arc::clone( &backend),
-                session, handle
-            });
+            if let Err(SendError(message)) = tx.send(message).await {
+                let mut binding = binding.lock().await;
+                binding.pending.push_front(message);
+                // Transport is gone but not released yet, keep the rest for `claim`. Otherwise it's been bound again
+                if matches!(&binding.target, Some((x, _)) if *x == owner) {
+                    return
+                }
+            }
         }
-        Ok(())
     }
 
-    async fn get_plugin_handle(gateway: &Arc<JanusGateway>, plugin: &str) -> Result<(u64, u64), JanusError> {
-        let session = {
-            let data = IncomingRequestParameters::prepare("create".to_string(), None, None);
-            let response = gateway.send(data, false).await?;
-            let session = match response.data {
-                None => 0,
-                Some(x) => x["id"].as_u64().unwrap_or(0)
-            };
+    /** Deliver events to transport `owner` from now on, including ones queued while detached */
+    pub async fn bind(&self, owner: u64, sender: mpsc::Sender<Message>) {
+        let mut binding = self.binding.lock().await;
+        binding.target = Some((owner, sender));
+        binding.epoch += 1;
+        self.bound.notify();
+    }
 
-            if session == 0 {
-                // TODO: may be print `response.error` if present
-                return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("Could not obtain janus-gateway session_id")))
-            }
-            session
-        };
+    /** Detach from transport `owner`, return the new epoch, or None if it's been claimed by another transport */
+    pub async fn release(&self, owner: u64) -> Option<u64> {
+        let mut binding = self.binding.lock().await;
+        match &binding.target {
+            Some((x, _)) if *x == owner => {
+                binding.target = None;
+                binding.epoch += 1;
+                Some(binding.epoch)
+            },
+            _ => None
+        }
+    }
 
-        let handle = {
-            let mut data = IncomingRequestParameters::prepare("attach".to_string(), None, None);
-            data.session_id = session;
-            data.rest = JSON_OBJECT::new();
-            data.rest.insert("plugin".to_string(), plugin.to_string().into());
+    pub async fn is_owned_by(&self, owner: u64) -> bool {
+        matches!(&self.binding.lock().await.target, Some((x, _)) if *x == owner)
+    }
 
-            let response = gateway.send(data, false).await?;
-            let handle = match response.data {
-                None => 0,
-                Some(x) => x["id"].as_u64().unwrap_or(0)
-            };
+    /** Whether the session is still detached since `release` returned `epoch` */
+    pub async fn is_released(&self, epoch: u64) -> bool {
+        let binding = self.binding.lock().await;
+        binding.target.is_none() && binding.epoch == epoch
+    }
 
-            if handle == 0 {
-                // TODO: may be print `response.error` if present
-                return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("Could not obtain janus-gateway handle_id")))
-            }
-            handle
+    /** Detach from whichever transport it's bound to, `last` is delivered before that */
+    pub async fn unbind(&self, last: Option<Message>) {
+        let target = {
+            let mut binding = self.binding.lock().await;
+            binding.epoch += 1;
+            binding.target.take()
         };
+        if let (Some((_, mut tx)), Some(message)) = (target, last) {
+            // Don't wait for a transport which doesn't keep up, its client may be gone already
+            tokio::spawn(async move {
+                let _ = tx.send(message).await;
+            });
+        }
+    }
 
-        Ok((session, handle))
+    pub async fn touch(&self) {
+        *self.last_activity.lock().await = Instant::now();
     }
 
-    // TODO: request &'static str
-    pub async fn forward(&self, mut request: IncomingRequestParameters, is_async: bool) -> Result<JanusResponse, JanusError> {
-        match &*self.gateway.read().await {
-            Some(x) => {
-                request.session_id = x.session;
-                request.handle_id = x.handle;
-                x.instance.send(request, is_async).await
-            },
-            None => return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, "janus-gateway connection hasn't been initialized".to_string()))
-        }
+    pub async fn idle_time(&self) -> Duration {
+        self.last_activity.lock().await.elapsed()
     }
 }
 
@@ -177,7 +204,10 @@ pub struct JanusHandle {
     session: Weak<JanusSession>,
 
     /** Push async message to processing queue (single for now) */
-    handler_thread: mpsc::Sender<JanusPluginMessage>
+    handler_thread: mpsc::Sender<JanusPluginMessage>,
+
+    /** Own janus-gateway connection, session and handle, only initialize once, on first use */
+    gateway: RwLock<Option<Gateway>>
 }
 
 impl JanusHandle {
@@ -188,7 +218,8 @@ impl JanusHandle {
         let handle = Arc::new(JanusHandle {
             id, plugin,
             session: Arc::downgrade(&session),
-            handler_thread: tx
+            handler_thread: tx,
+            gateway: RwLock::new(None)
         });
 
         // Process async message one-by-one, mimic janus-gateway implementation
@@ -202,9 +233,10 @@ impl JanusHandle {
 
                 // TODO: don't copy
                 let transaction = message.transaction.clone();
+                let span = info_span!("message", transaction = %transaction);
 
                 // TODO: Optimization - Stop process requests if no result???
-                let result = match handle.plugin.handle_async_message(message).await {
+                let result = match handle.plugin.handle_async_message(message).instrument(span).await {
                     Some(x) => x,
                     None => break
                 };
@@ -223,7 +255,7 @@ impl JanusHandle {
                     break
                 }
             }
-        });
+        }.instrument(info_span!(parent: None, "handle", session_id, handle_id = id)));
 
         handle
     }
@@ -234,19 +266,294 @@ impl JanusHandle {
         }
     }
 
-    pub async fn transport_gone(&self) -> bool {
-        self.session.upgrade().is_none()
+    fn get_session(&self) -> Result<Arc<JanusSession>, JanusError> {
+        match self.session.upgrade() {
+            Some(x) => Ok(x),
+            None => Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, String::from("Session closed")))
+        }
+    }
+
+    /** Connect to janus-gateway once, to `backend` if required, otherwise one chosen by `JanusBackendProvider` */
+    async fn init_gateway(self: &Arc<Self>, backend: Option<&str>) -> Result<(), JanusError> {
+        if let Some(gateway) = &*self.gateway.read().await {
+            return Self::check_gateway(gateway, backend)
+        }
+
+        // Held while connecting, so that concurrent requests don't connect twice
+        let mut lock = self.gateway.write().await;
+        if let Some(gateway) = &*lock {
+            return Self::check_gateway(gateway, backend)
+        }
+
+        let session = self.get_session()?;
+        let url = match backend {
+            Some(x) => Some(x.to_string()),
+            None => session.app.backend.get_backend().await
+        };
+        let url = match url {
+            None => return Err(JanusError::new(JANUS_ERROR_GATEWAY_UNAVAILABLE, String::from("No janus-gateway instance available"))),
+            Some(x) => x
+        };
+
+        let (tx, mut rx) = event_queue(session.app.event_queue_size, session.app.event_overflow);
+        let mut wtx = session.connection.clone();
+
+        let secret = session.app.backend_secrets.get(&url).cloned();
+        let backend = session.app.gateways.get(&url, secret, &session.app.transaction_ids).await?;
+        let (backend_session, backend_handle) = Self::get_plugin_handle(&backend, self.plugin.get_name()).await?;
+        backend.subscribe(backend_session, tx)?;
+        session.app.state.set_handle_backend(&self.id, (url.clone(), backend_session)).await;
+        session.app.backend.session_opened(backend.url()).await;
+        metrics::BACKEND_SESSIONS.with_label_values(&[backend.url()]).inc();
+
+        // Events end with the subscription, whether the connection has been lost or it's been destroyed on purpose
+        let session_id = session.id;
+        let handle_id = self.id;
+        let handle_ref = Arc::downgrade(self);
+        let instance = Arc::downgrade(&backend);
+        tokio::spawn(async move {
+            while let Some(mut x) = rx.recv().await {
+                // Backend ids are only meaningful on its own connection
+                if x.session_id != 0 {
+                    x.session_id = session_id;
+                }
+                if x.sender != 0 {
+                    x.sender = handle_id;
+                }
+
+                let text = Message::Text(x.stringify().unwrap());
+                if wtx.send(text).await.is_err() {
+                    return;
+                }
+            }
+            let handle = match handle_ref.upgrade() {
+                Some(x) => x,
+                None => return
+            };
+            if rx.is_overflowed() {
+                if let Ok(session) = handle.get_session() {
+                    session.app.disconnect_session(&session).await;
+                }
+                return
+            }
+            handle.on_gateway_lost(instance).await;
+        }.instrument(info_span!(parent: None, "session", session_id, handle_id)));
+
+        *lock = Some(Gateway {
+            instance: Arc::clone( &backend),
+            session: backend_session,
+            handle: backend_handle
+        });
+        Ok(())
+    }
+
+    /** Whether `gateway` is janus-gateway `backend`, if one is required */
+    fn check_gateway(gateway: &Gateway, backend: Option<&str>) -> Result<(), JanusError> {
+        match backend {
+            Some(url) if gateway.instance.url() != url => Err(JanusError::new(
+                JANUS_ERROR_GATEWAY_MISMATCH,
+                format!("Handle is connected to janus-gateway \"{}\", not \"{}\"", gateway.instance.url(), url)
+            )),
+            _ => Ok(())
+        }
     }
 
-    pub async fn forward_message(&self, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<(JSON_ANY, Option<JSON_ANY>), JanusError> {
+    async fn get_plugin_handle(gateway: &Arc<JanusGateway>, plugin: &str) -> Result<(u64, u64), JanusError> {
+        let session = {
+            let data = IncomingRequestParameters::prepare("create".to_string(), None, None);
+            let response = gateway.send(data, false).await?;
+            let session = match response.data {
+                None => 0,
+                Some(x) => x["id"].as_u64().unwrap_or(0)
+            };
+
+            if session == 0 {
+                // TODO: may be print `response.error` if present
+                return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("Could not obtain janus-gateway session_id")))
+            }
+            session
+        };
+
+        let handle = {
+            let mut data = IncomingRequestParameters::prepare("attach".to_string(), None, None);
+            data.session_id = session;
+            data.rest = JSON_OBJECT::new();
+            data.rest.insert("plugin".to_string(), plugin.to_string().into());
+
+            let response = gateway.send(data, false).await?;
+            let handle = match response.data {
+                None => 0,
+                Some(x) => x["id"].as_u64().unwrap_or(0)
+            };
+
+            if handle == 0 {
+                // TODO: may be print `response.error` if present
+                return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("Could not obtain janus-gateway handle_id")))
+            }
+            handle
+        };
+
+        Ok((session, handle))
+    }
+
+    /** Counterpart of `get_plugin_handle`, best effort */
+    async fn release_plugin_handle(&self, gateway: &Arc<JanusGateway>, session: u64, handle: u64) {
+        let mut request = IncomingRequestParameters::prepare("detach".to_string(), None, None);
+        request.session_id = session;
+        request.handle_id = handle;
+        if let Err(e) = gateway.send(request, false).await {
+            warn!(handle_id = self.id, backend_handle_id = handle, reason = %e.reason, "Could not detach janus-gateway handle");
+        }
+
+        let mut request = IncomingRequestParameters::prepare("destroy".to_string(), None, None);
+        request.session_id = session;
+        if let Err(e) = gateway.send(request, false).await {
+            warn!(handle_id = self.id, backend_session_id = session, reason = %e.reason, "Could not destroy janus-gateway session");
+        }
+    }
+
+    /** Detach plugin handle and destroy session on janus-gateway, best effort */
+    pub async fn destroy_gateway(&self) {
+        let gateway = match self.gateway.write().await.take() {
+            Some(x) => x,
+            None => return
+        };
+
+        self.release_plugin_handle(&gateway.instance, gateway.session, gateway.handle).await;
+        gateway.instance.unsubscribe(gateway.session);
+        if let Some(session) = self.session.upgrade() {
+            session.app.backend.session_closed(gateway.instance.url()).await;
+        }
+        metrics::BACKEND_SESSIONS.with_label_values(&[gateway.instance.url()]).dec();
+    }
+
+    /**
+     * Connection to janus-gateway `instance` is gone without `destroy_gateway`, so are its session and handle.
+     * The handle gets "hangup" and keeps working if `JanusProxy::backend_reconnect`, it's "detached" otherwise.
+     * Reconnect right away to the same backend if it's still healthy (e.g. connection reset),
+     * otherwise on next use, so plugins may choose another one (e.g. VideoRoom room affinity).
+     */
+    // Boxed since it may `init_gateway`, which spawns it: opaque future types would be cyclic
+    fn on_gateway_lost(self: Arc<Self>, instance: Weak<JanusGateway>) -> BoxFuture<'static, ()> {
+        async move { self.reset_gateway(&instance).await }.boxed()
+    }
+
+    async fn reset_gateway(self: &Arc<Self>, instance: &Weak<JanusGateway>) {
+        let gateway = {
+            let mut lock = self.gateway.write().await;
+            match &*lock {
+                Some(x) if Weak::ptr_eq(&Arc::downgrade(&x.instance), instance) => lock.take().unwrap(),
+                // Destroyed on purpose, or replaced already
+                _ => return
+            }
+        };
         let session = match self.session.upgrade() {
             Some(x) => x,
-            None => return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("Session closed")))
+            None => return
         };
-        session.init_gateway(self.plugin.get_name(), self.id).await?;
 
-        let request = IncomingRequestParameters::prepare("message".to_string(), Some(body), jsep);
-        let response = session.forward(request, is_async).await?;
+        let url = gateway.instance.url().to_string();
+        warn!(url = %url, backend_session_id = gateway.session, "Connection to janus-gateway lost");
+        session.app.backend.session_closed(&url).await;
+        metrics::BACKEND_SESSIONS.with_label_values(&[&url]).dec();
+
+        self.plugin.reset().await;
+        let mut connection = session.connection.clone();
+        let mut event = JanusResponse::new("hangup", session.id, String::new())
+            .with_field("reason", "janus-gateway connection lost".into());
+        event.sender = self.id;
+        let _ = connection.send(event.into()).await;
+
+        if !session.app.backend_reconnect {
+            session.app.state.remove_handle(&self.id).await;
+            if session.handles.write().await.remove(&self.id).is_some() {
+                metrics::HANDLES.dec();
+            }
+
+            let mut event = JanusResponse::new("detached", session.id, String::new());
+            event.sender = self.id;
+            let _ = connection.send(event.into()).await;
+            return
+        }
+
+        if session.app.backend.list_backends().await.contains(&url) {
+            match self.init_gateway(Some(&url)).await {
+                Ok(_) => info!(url = %url, "Reconnected to janus-gateway"),
+                Err(e) => warn!(url = %url, reason = %e.reason, "Could not reconnect to janus-gateway, retry on next request")
+            }
+        }
+    }
+
+    /** Identity of this handle on janus-gateway: (url, session_id, handle_id) */
+    pub async fn backend(&self) -> Option<(String, u64, u64)> {
+        self.gateway.read().await.as_ref().map(|x| (x.instance.url().to_string(), x.session, x.handle))
+    }
+
+    // TODO: request &'static str
+    async fn forward(&self, mut request: IncomingRequestParameters, is_async: bool) -> Result<JanusResponse, JanusError> {
+        match &*self.gateway.read().await {
+            Some(x) => {
+                request.session_id = x.session;
+                request.handle_id = x.handle;
+                x.instance.send(request, is_async).await
+            },
+            None => return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, "janus-gateway connection hasn't been initialized".to_string()))
+        }
+    }
+
+    /** Make later `forward_message` go to janus-gateway `url`, fail if it's been connected to another one */
+    pub async fn pin_backend(self: &Arc<Self>, url: &str) -> Result<(), JanusError> {
+        self.init_gateway(Some(url)).await
+    }
+
+    /** A healthy janus-gateway for new resources, see `JanusBackendProvider::get_backend` */
+    pub async fn select_backend(&self) -> Option<String> {
+        self.session.upgrade()?.app.backend.get_backend().await
+    }
+
+    pub async fn is_backend_up(&self, url: &str) -> bool {
+        match self.session.upgrade() {
+            Some(x) => x.app.backend.list_backends().await.iter().any(|x| x == url),
+            None => false
+        }
+    }
+
+    pub async fn transport_gone(&self) -> bool {
+        self.session.upgrade().is_none()
+    }
+
+    /** Send a plugin message to janus-gateway, on behalf of client request `transaction` */
+    pub async fn forward_message(self: &Arc<Self>, transaction: &str, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<(JSON_ANY, Option<JSON_ANY>), JanusError> {
+        self.init_gateway(None).await?;
+
+        let mut request = IncomingRequestParameters::prepare("message".to_string(), Some(body), jsep);
+        request.transaction = transaction.to_string();
+        let response = self.forward(request, is_async).await?;
+        self.plugin_result(response)
+    }
+
+    /**
+     * Send a synchronous plugin message to janus-gateway `url` on a session and handle of its own, released right after,
+     * so that this handle isn't connected there (e.g. to manage a room hosted by another backend).
+     * janus-gateway times them out if this instance is gone meanwhile.
+     */
+    pub async fn forward_oneshot(&self, url: &str, transaction: &str, body: JSON_ANY) -> Result<(JSON_ANY, Option<JSON_ANY>), JanusError> {
+        let session = self.get_session()?;
+        let secret = session.app.backend_secrets.get(url).cloned();
+        let gateway = session.app.gateways.get(url, secret, &session.app.transaction_ids).await?;
+        let (backend_session, backend_handle) = Self::get_plugin_handle(&gateway, self.plugin.get_name()).await?;
+
+        let mut request = IncomingRequestParameters::prepare("message".to_string(), Some(body), None);
+        request.transaction = transaction.to_string();
+        request.session_id = backend_session;
+        request.handle_id = backend_handle;
+        let response = gateway.send(request, false).await;
+        self.release_plugin_handle(&gateway, backend_session, backend_handle).await;
+        self.plugin_result(response?)
+    }
+
+    /** Plugin data and JSEP of a janus-gateway response to a plugin message */
+    fn plugin_result(&self, response: JanusResponse) -> Result<(JSON_ANY, Option<JSON_ANY>), JanusError> {
         if let Some(e) = response.error {
             return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, format!("janus-gateway error: {:?}", e)))
         }
@@ -264,14 +571,12 @@ impl JanusHandle {
         }
     }
 
-    pub async fn trickle(&self, item: JanusIceTrickle) -> Result<(), JanusError> {
-        let session = match self.session.upgrade() {
-            Some(x) => x,
-            None => return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("Session closed")))
-        };
+    pub async fn trickle(&self, transaction: &str, item: JanusIceTrickle) -> Result<(), JanusError> {
+        self.get_session()?;
 
         // TODO: store trickle if janus-gateway not connected yet?
         let mut request = IncomingRequestParameters::prepare("trickle".to_string(), None, None);
+        request.transaction = transaction.to_string();
         request.rest.insert("candidate".to_string(), match serde_json::to_value(item) {
             Ok(x) => x,
             Err(_) => return Err(
@@ -279,8 +584,111 @@ impl JanusHandle {
             )
         });
 
-        session.forward(request, false).await?;
+        self.forward(request, false).await?;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::future::join_all;
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::time::Duration;
+    use tokio_tungstenite::tungstenite::Message;
+    use super::SessionBinding;
+    use crate::janus::{JanusProxy, EventOverflowPolicy};
+    use crate::janus::testing::{self, FakeJanus, WsClient};
+
+    /** Client with a VideoRoom handle joined to a room, so that it's got a janus-gateway handle: (session, handle) */
+    async fn join(janus: Arc<JanusProxy>) -> (WsClient, u64, u64) {
+        let url = testing::listen(janus).await;
+        let mut client = WsClient::connect(&url).await;
+        let session = client.create().await;
+        let handle = client.attach(session, "janus.plugin.videoroom").await;
+        client.join(session, handle).await;
+        (client, session, handle)
+    }
+
+    #[tokio::test]
+    async fn concurrent_requests_connect_once() {
+        let backend = FakeJanus::start().await;
+        let janus = Arc::new(testing::proxy(&[&backend.url]).await);
+        let mut client = WsClient::connect(&testing::listen(Arc::clone(&janus)).await).await;
+        let session = client.create().await;
+        let handle = client.attach(session, "janus.plugin.videoroom").await;
+        let handle = Arc::clone(&janus.sessions.read().await[&session].handles.read().await[&handle]);
+
+        let results = join_all((0..3).map(|_| handle.pin_backend(&backend.url))).await;
+        assert!(results.iter().all(Result::is_ok), "{:?}", results.iter().map(|x| x.as_ref().err().map(|e| &e.reason)).collect::<Vec<_>>());
+        assert_eq!((backend.requests("attach"), backend.sessions()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn lost_backend_detaches_handle() {
+        let backend = FakeJanus::start().await;
+        let (mut client, session, handle) = join(Arc::new(testing::proxy(&[&backend.url]).await.with_backend_reconnect(false))).await;
+
+        backend.disconnect();
+        for janus in ["hangup", "detached"].iter() {
+            let event = client.event().await.unwrap();
+            assert_eq!((&event["janus"], &event["sender"]), (&json!(janus), &json!(handle)));
+        }
+
+        let request = json!({"janus": "message", "session_id": session, "handle_id": handle, "transaction": "m", "body": {"request": "list"}});
+        assert_eq!(client.request(request).await["janus"], "error");
+    }
+
+    #[tokio::test]
+    async fn lost_backend_is_reconnected() {
+        let backend = FakeJanus::start().await;
+        let (mut client, session, handle) = join(Arc::new(testing::proxy(&[&backend.url]).await)).await;
+        assert_eq!(backend.requests("attach"), 1);
+
+        backend.disconnect();
+        let event = client.event().await.unwrap();
+        assert_eq!((&event["janus"], &event["sender"]), (&json!("hangup"), &json!(handle)));
+
+        // Right away, backend is still up
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        assert_eq!(backend.requests("attach"), 2);
+        let request = json!({"janus": "message", "session_id": session, "handle_id": handle, "transaction": "m", "body": {"request": "list"}});
+        assert_eq!(client.request(request).await["janus"], "success");
+        assert!(client.event().await.is_none());
+    }
+
+    #[test]
+    fn droppable_events_are_kept_last() {
+        let event = |janus: &str| Message::Text(json!({"janus": janus}).to_string());
+        let mut binding = SessionBinding::default();
+        for janus in ["event", "media", "webrtcup"].iter() {
+            binding.keep(event(janus), 2);
+        }
+        // Nothing left to drop but itself
+        binding.keep(event("slowlink"), 2);
+        assert_eq!(binding.pending, vec![event("event"), event("webrtcup")]);
+
+        binding.keep(event("hangup"), 2);
+        assert_eq!(binding.pending, vec![event("webrtcup"), event("hangup")]);
+    }
+
+    #[tokio::test]
+    async fn overflowing_client_is_disconnected() {
+        let backend = FakeJanus::start().await;
+        let janus = Arc::new(testing::proxy(&[&backend.url]).await.with_event_queue(1, EventOverflowPolicy::Disconnect));
+        let (mut client, session, handle) = join(Arc::clone(&janus)).await;
+        let handle = janus.sessions.read().await[&session].handles.read().await[&handle].clone();
+        let (_, backend_session, backend_handle) = handle.backend().await.unwrap();
+
+        // Faster than they're forwarded
+        for _ in 0..100 {
+            backend.push(json!({"janus": "media", "session_id": backend_session, "sender": backend_handle, "type": "video", "receiving": true}));
+        }
+        while client.event().await.is_some() {}
+
+        assert!(janus.sessions.read().await.is_empty());
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(backend.sessions(), 0);
+    }
+}
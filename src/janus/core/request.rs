@@ -1,7 +1,6 @@
 use serde::{Serialize, Deserialize};
 use serde_with::skip_serializing_none;
 use super::json::*;
-use crate::janus::helper;
 use crate::janus::core::ice::JanusIceTrickle;
 
 #[skip_serializing_none]
@@ -22,6 +21,9 @@ pub struct IncomingRequestParameters {
 	pub body: Option<JSON_ANY>,
 	pub jsep: Option<JSON_ANY>,
 
+	/** Shared secret, never forwarded as-is: janus-gateway instances have their own */
+	pub apisecret: Option<JSON_STRING>,
+
 	#[serde(flatten)]
 	pub rest: JSON_OBJECT
 }
@@ -29,13 +31,15 @@ pub struct IncomingRequestParameters {
 impl IncomingRequestParameters {
 	pub fn prepare(request: String, body: Option<JSON_ANY>, jsep: Option<JSON_ANY>) -> IncomingRequestParameters {
 		IncomingRequestParameters {
-			transaction: helper::rand_id().to_string(),     // TODO: conflict resolution
+			// Backend transactions are allocated by `JanusGateway::send`
+			transaction: String::new(),
 			janus: request,
 			id: 0,
 			session_id: 0,
 			handle_id: 0,
 			body,
 			jsep,
+			apisecret: None,
 			rest: Default::default()
 		}
 	}
@@ -77,7 +81,7 @@ pub struct JsepParameters {
 #[derive(Deserialize)]
 pub struct AddTokenParameters {
 	pub token: JSON_STRING, // JANUS_JSON_PARAM_REQUIRED
-	pub plugins: JSON_ARRAY<JSON_STRING>
+	pub plugins: Option<JSON_ARRAY<JSON_STRING>>
 }
 
 #[skip_serializing_none]
@@ -93,13 +97,16 @@ pub struct AdminParameters {
 	pub janus: JSON_STRING, // JANUS_JSON_PARAM_REQUIRED
 }
 
+// Admin requests forwarded to janus-gateway as is, these are only parsed to validate them first
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct DebugParameters {
 	pub debug: JSON_BOOL, // JANUS_JSON_PARAM_REQUIRED
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct TimeoutParameters {
 	pub timeout: JSON_POSITIVE_INTEGER, // JANUS_JSON_PARAM_REQUIRED
@@ -112,30 +119,35 @@ pub struct LevelParameters {
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct TimestampsParameters {
 	pub timestamps: JSON_BOOL, // JANUS_JSON_PARAM_REQUIRED
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct ColorsParameters {
 	pub colors: JSON_BOOL, // JANUS_JSON_PARAM_REQUIRED
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct MnqParameters {
 	pub min_nack_queue: JSON_POSITIVE_INTEGER, // JANUS_JSON_PARAM_REQUIRED
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct NmtParameters {
 	pub no_media_timer: JSON_POSITIVE_INTEGER, // JANUS_JSON_PARAM_REQUIRED
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct StParameters {
 	pub slowlink_threshold: JSON_POSITIVE_INTEGER, // JANUS_JSON_PARAM_REQUIRED
@@ -183,17 +195,19 @@ pub struct CustomLoglineParameters {
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct Text2pcapParameters {
-	pub folder: JSON_STRING,
-	pub filename: JSON_STRING,
-	pub truncate: JSON_POSITIVE_INTEGER
+	pub folder: Option<JSON_STRING>,
+	pub filename: Option<JSON_STRING>,
+	pub truncate: Option<JSON_POSITIVE_INTEGER>
 }
 
 #[skip_serializing_none]
+#[allow(dead_code)]
 #[derive(Deserialize)]
 pub struct HandleInfoParameters {
-	pub plugin_only: JSON_BOOL
+	pub plugin_only: Option<JSON_BOOL>
 }
 
 #[skip_serializing_none]
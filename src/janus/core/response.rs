@@ -35,7 +35,11 @@ pub struct JanusResponse {
     pub plugindata: Option<PluginResultWrapper>,
 
     /** JSEP SDP */
-    pub jsep: Option<JSON_ANY>
+    pub jsep: Option<JSON_ANY>,
+
+    /** Request specific fields, e.g. "reason" of hangup event, "info" of admin handle_info */
+    #[serde(flatten)]
+    pub rest: JSON_OBJECT
 }
 
 impl JanusResponse {
@@ -49,6 +53,16 @@ impl JanusResponse {
         self
     }
 
+    pub fn with_field(mut self, key: &str, value: JSON_ANY) -> JanusResponse {
+        self.rest.insert(key.to_string(), value);
+        self
+    }
+
+    pub fn with_fields(mut self, fields: JSON_OBJECT) -> JanusResponse {
+        self.rest.extend(fields);
+        self
+    }
+
     pub fn with_plugindata(mut self, handle: &Arc<JanusHandle>, data: JSON_ANY, jsep: Option<JSON_ANY>) -> JanusResponse {
         self.sender = handle.id;
         self.plugindata = Some(PluginResultWrapper {
@@ -69,7 +83,8 @@ impl JanusResponse {
             sender: 0,
             data: None,
             plugindata: None,
-            jsep: None
+            jsep: None,
+            rest: JSON_OBJECT::new()
         }
     }
 
@@ -82,7 +97,8 @@ impl JanusResponse {
             sender: 0,
             data: None,
             plugindata: None,
-            jsep: None
+            jsep: None,
+            rest: JSON_OBJECT::new()
         }
     }
 
@@ -0,0 +1,270 @@
+use serde::Deserialize;
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use super::core::response::JanusResponse;
+use super::metrics;
+
+/** Events a client can miss without breaking its state, the next one supersedes them */
+static DROPPABLE_EVENTS: [&str; 2] = ["media", "slowlink"];
+
+/** What happens to backend events of a client which doesn't keep up, i.e. its queue is full */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventOverflowPolicy {
+    /** Drop the oldest non-critical event ("media", "slowlink"), disconnect if there is none */
+    DropOldest,
+    /** Destroy the session and close its transport */
+    Disconnect,
+    /**
+     * Stop reading from janus-gateway until there's room, responses on the connection wait too:
+     * it mustn't be shared with other sessions (see `JanusGatewayPool`)
+     */
+    Backpressure
+}
+
+/** Policy by configuration name: "drop_oldest", "disconnect" or "backpressure" */
+pub fn overflow_policy(name: &str) -> Option<EventOverflowPolicy> {
+    match name {
+        "drop_oldest" => Some(EventOverflowPolicy::DropOldest),
+        "disconnect" => Some(EventOverflowPolicy::Disconnect),
+        "backpressure" => Some(EventOverflowPolicy::Backpressure),
+        _ => None
+    }
+}
+
+fn is_droppable(event: &JanusResponse) -> bool {
+    DROPPABLE_EVENTS.contains(&&event.janus[..])
+}
+
+/** Same as `is_droppable`, for an event already serialized for a transport */
+pub(crate) fn is_droppable_message(message: &Message) -> bool {
+    #[derive(Deserialize)]
+    struct Event {
+        janus: String
+    }
+    match message {
+        Message::Text(x) => serde_json::from_str::<Event>(x).is_ok_and(|x| DROPPABLE_EVENTS.contains(&&x.janus[..])),
+        _ => false
+    }
+}
+
+#[derive(Default)]
+struct QueueState {
+    events: VecDeque<JanusResponse>,
+    /** Either end is gone */
+    closed: bool,
+    /** Closed because it's been full, see `EventOverflowPolicy::Disconnect` */
+    overflowed: bool
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    capacity: usize,
+    policy: EventOverflowPolicy,
+    /** Wake the receiver on push or close */
+    readable: Notify,
+    /** Wake a sender waiting for room (`Backpressure`) on pop or close */
+    writable: Notify
+}
+
+impl Queue {
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify();
+        self.writable.notify();
+    }
+}
+
+/** Close the queue once every `EventSender` clone is dropped */
+struct SenderGuard(Arc<Queue>);
+
+impl Drop for SenderGuard {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+/** Backend side of an event queue, see `event_queue` */
+#[derive(Clone)]
+pub(crate) struct EventSender(Arc<SenderGuard>);
+
+/** Client side of an event queue, see `event_queue` */
+pub(crate) struct EventReceiver(Arc<Queue>);
+
+/** Bounded queue of backend events to a client, `policy` applies once `capacity` events are waiting */
+pub(crate) fn event_queue(capacity: usize, policy: EventOverflowPolicy) -> (EventSender, EventReceiver) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState::default()),
+        capacity: capacity.max(1),
+        policy,
+        readable: Notify::new(),
+        writable: Notify::new()
+    });
+    (EventSender(Arc::new(SenderGuard(Arc::clone(&queue)))), EventReceiver(queue))
+}
+
+impl EventSender {
+    /** Queue `event`, or give it back if the receiver is gone or it's been disconnected on overflow */
+    pub(crate) async fn send(&self, event: JanusResponse) -> Result<(), JanusResponse> {
+        let queue = &(self.0).0;
+        loop {
+            {
+                let mut state = queue.state.lock().unwrap();
+                if state.closed {
+                    return Err(event)
+                }
+                if state.events.len() < queue.capacity {
+                    state.events.push_back(event);
+                    queue.readable.notify();
+                    return Ok(())
+                }
+
+                match queue.policy {
+                    EventOverflowPolicy::Backpressure => {},
+                    EventOverflowPolicy::DropOldest => {
+                        if let Some(i) = state.events.iter().position(is_droppable) {
+                            state.events.remove(i);
+                            state.events.push_back(event);
+                            metrics::DROPPED_EVENTS.with_label_values(&["queue_full"]).inc();
+                            return Ok(())
+                        }
+                        if is_droppable(&event) {
+                            metrics::DROPPED_EVENTS.with_label_values(&["queue_full"]).inc();
+                            return Ok(())
+                        }
+                        // Only critical events are waiting, the client is hopeless
+                        return Err(Self::overflow(queue, &mut state, event))
+                    },
+                    EventOverflowPolicy::Disconnect => return Err(Self::overflow(queue, &mut state, event))
+                }
+            }
+            queue.writable.notified().await;
+        }
+    }
+
+    fn overflow(queue: &Queue, state: &mut QueueState, event: JanusResponse) -> JanusResponse {
+        metrics::DROPPED_EVENTS.with_label_values(&["disconnect"]).inc_by(state.events.len() as u64 + 1);
+        state.events.clear();
+        state.closed = true;
+        state.overflowed = true;
+        queue.readable.notify();
+        event
+    }
+}
+
+impl EventReceiver {
+    /** Next event, None once the sender is gone and the queue drained, or on overflow */
+    pub(crate) async fn recv(&mut self) -> Option<JanusResponse> {
+        loop {
+            {
+                let mut state = self.0.state.lock().unwrap();
+                if let Some(x) = state.events.pop_front() {
+                    self.0.writable.notify();
+                    return Some(x)
+                }
+                if state.closed {
+                    return None
+                }
+            }
+            self.0.readable.notified().await;
+        }
+    }
+
+    /** Whether it's been closed because the client didn't keep up, rather than by the sender */
+    pub(crate) fn is_overflowed(&self) -> bool {
+        self.0.state.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        self.0.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+
+    fn event(janus: &'static str, session_id: u64) -> JanusResponse {
+        JanusResponse::new(janus, session_id, String::new())
+    }
+
+    async fn drain(rx: &mut EventReceiver) -> Vec<(String, u64)> {
+        let mut events = Vec::new();
+        while let Ok(Some(x)) = tokio::time::timeout(Duration::from_millis(50), rx.recv()).await {
+            events.push((x.janus, x.session_id));
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn oldest_droppable_events_are_dropped() {
+        let (tx, mut rx) = event_queue(3, EventOverflowPolicy::DropOldest);
+        tx.send(event("event", 1)).await.unwrap();
+        tx.send(event("media", 2)).await.unwrap();
+        tx.send(event("slowlink", 3)).await.unwrap();
+        tx.send(event("webrtcup", 4)).await.unwrap();
+        tx.send(event("hangup", 5)).await.unwrap();
+        // Nothing left to drop but itself
+        tx.send(event("media", 6)).await.unwrap();
+
+        let events = drain(&mut rx).await;
+        assert_eq!(events, vec![
+            ("event".to_string(), 1), ("webrtcup".to_string(), 4), ("hangup".to_string(), 5)
+        ]);
+        assert!(!rx.is_overflowed());
+
+        // Full of critical events
+        for i in 0..3 {
+            tx.send(event("event", i)).await.unwrap();
+        }
+        assert!(tx.send(event("event", 3)).await.is_err());
+        assert!(rx.recv().await.is_none());
+        assert!(rx.is_overflowed());
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_disconnected() {
+        let (tx, mut rx) = event_queue(2, EventOverflowPolicy::Disconnect);
+        tx.send(event("media", 1)).await.unwrap();
+        tx.send(event("media", 2)).await.unwrap();
+        assert!(tx.send(event("media", 3)).await.is_err());
+        assert!(tx.send(event("event", 4)).await.is_err());
+        assert!(rx.recv().await.is_none());
+        assert!(rx.is_overflowed());
+    }
+
+    #[tokio::test]
+    async fn full_queue_holds_sender_back() {
+        let (tx, mut rx) = event_queue(1, EventOverflowPolicy::Backpressure);
+        tx.send(event("media", 1)).await.unwrap();
+        let blocked = tokio::time::timeout(Duration::from_millis(50), tx.send(event("media", 2))).await;
+        assert!(blocked.is_err());
+
+        let sender = tokio::spawn(async move {
+            tx.send(event("media", 3)).await.unwrap();
+        });
+        assert_eq!(rx.recv().await.unwrap().session_id, 1);
+        sender.await.unwrap();
+        assert_eq!(drain(&mut rx).await, vec![("media".to_string(), 3)]);
+        assert!(!rx.is_overflowed());
+    }
+
+    #[tokio::test]
+    async fn closed_when_either_end_is_gone() {
+        let (tx, mut rx) = event_queue(4, EventOverflowPolicy::Backpressure);
+        tx.send(event("event", 1)).await.unwrap();
+        drop(tx);
+        // Queued events are still delivered
+        assert_eq!(rx.recv().await.unwrap().session_id, 1);
+        assert!(rx.recv().await.is_none());
+        assert!(!rx.is_overflowed());
+
+        let (tx, rx) = event_queue(4, EventOverflowPolicy::Backpressure);
+        drop(rx);
+        assert!(tx.send(event("event", 1)).await.is_err());
+    }
+}
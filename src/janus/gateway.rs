@@ -1,61 +1,115 @@
 use futures::{StreamExt, SinkExt};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::mpsc;
 use tokio::sync::oneshot;
-use tokio::time::Duration;
+use tokio::time::{Duration, Instant};
 use tokio_tungstenite::tungstenite::{Message, Error};
-use std::sync::Arc;
+use std::sync::{Arc, Weak, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::collections::HashMap;
+use tracing::{debug, warn, debug_span, Instrument};
 use super::core::json;
 use super::core::request::IncomingRequestParameters;
 use super::core::response::JanusResponse;
-use super::connection::new_backend_connection;
+use super::connection::{new_backend_connection, JANUS_PROTOCOL, JANUS_ADMIN_PROTOCOL};
 use super::core::apierror::*;
-
-struct JanusGatewayRequest {
-    callback: oneshot::Sender<JanusResponse>,
-    asynchronous: bool      // may or may not ignore "ack" response
-}
+use super::metrics::{self, GaugeGuard};
+use super::provider::{IdGenerator, RandomIdGenerator};
+use super::transaction::TransactionManager;
+use super::events::EventSender;
 
 pub struct JanusGateway {
-    // url: String,     // TODO: should store this?
+    url: String,
+    /** `apisecret` required by this janus-gateway instance */
+    secret: Option<String>,
     queue: mpsc::Sender<Message>,
-    requests: RwLock<HashMap<String, JanusGatewayRequest>>
+    /** Requests waiting for a response */
+    transactions: TransactionManager,
+    /** Event receivers by backend session_id, so a connection can be shared (see `JanusGatewayPool`) */
+    subscribers: Mutex<HashMap<u64, EventSender>>,
+    /** Connection is gone, subscribers have been dropped */
+    closed: AtomicBool
 }
 
 impl JanusGateway {
-    async fn on_websocket_message(&self, message: Message, event: &mut mpsc::UnboundedSender<JanusResponse>) {
+    async fn on_websocket_message(&self, message: Message) {
         if let Message::Text(text) = &message {
-            // TODO: handle unwrap - malformed response or struct definition error
-            let response = json::parse::<JanusResponse>(text).unwrap();
-
-            let mut lock = self.requests.write().await;
-            if lock.contains_key(&response.transaction) {
-                let asynchronous = lock.get(&response.transaction).unwrap().asynchronous;
-                if asynchronous && response.janus == "ack" {
-                    return
-                }
-
-                // Note: unwrap is safe here
-                let requester = lock.remove(&response.transaction).unwrap();
-                drop(lock);
+            // The connection is shared, one bad frame mustn't take it down
+            let response = match json::parse::<JanusResponse>(text) {
+                Ok(x) => x,
+                Err(e) => return warn!(reason = %e.reason, message = %text, "Malformed message from janus-gateway, ignored")
+            };
 
-                if let Err(_) = requester.callback.send(response) {
-                    // TODO: do what??
-                }
-            } else {
+            if let Some(response) = self.transactions.complete(response) {
                 // TODO: should send "ack"?
-                drop(lock);
+                debug!(janus = %response.janus, session_id = response.session_id, "Event from janus-gateway");
 
-                if let Err(_) = event.send(response) {
-                    // TODO: ignore or what?
+                // May wait for room in the queue, see `EventOverflowPolicy::Backpressure`
+                let subscriber = self.subscribers.lock().unwrap().get(&response.session_id).cloned();
+                match subscriber {
+                    Some(x) => if x.send(response).await.is_err() {
+                        debug!("Subscriber is gone, event dropped");
+                    },
+                    None => debug!("No subscriber, event dropped")
                 }
             }
         }
     }
 
-    pub async fn connect(url: String, mut event: mpsc::UnboundedSender<JanusResponse>) -> Result<Arc<JanusGateway>, JanusError> {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /** Connection is gone: pending requests fail, subscribers are dropped so that their handles find out */
+    fn close(&self) {
+        debug!("Connection to janus-gateway closed");
+        self.transactions.clear();
+        // Even after a panic while it was held
+        let mut subscribers = self.subscribers.lock().unwrap_or_else(|e| e.into_inner());
+        self.closed.store(true, Ordering::SeqCst);
+        subscribers.clear();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /** Deliver events of backend `session` to `event`, until `unsubscribe` or the connection is gone (`event` is dropped then) */
+    pub(crate) fn subscribe(&self, session: u64, event: EventSender) -> Result<(), JanusError> {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        if self.is_closed() {
+            return Err(JanusError::new(JANUS_ERROR_GATEWAY_CONNECTION_CLOSED, String::from("connection to janus-gateway closed")))
+        }
+        subscribers.insert(session, event);
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, session: u64) {
+        self.subscribers.lock().unwrap().remove(&session);
+    }
+
+    /** How many backend sessions are subscribed on this connection */
+    pub(crate) fn subscriber_count(&self) -> usize {
+        self.subscribers.lock().unwrap().len()
+    }
+
+    /** Backend sessions subscribed on this connection */
+    pub fn sessions(&self) -> Vec<u64> {
+        self.subscribers.lock().unwrap().keys().cloned().collect()
+    }
+
+    /** Connect to janus-gateway Janus API, events of sessions not subscribed (yet) are dropped */
+    pub async fn connect(url: String, secret: Option<String>, ids: Arc<Box<dyn IdGenerator>>) -> Result<Arc<JanusGateway>, JanusError> {
+        Self::connect_with_protocol(url, JANUS_PROTOCOL, secret, ids).await
+    }
+
+    /** Connect to janus-gateway Admin API, there is no event on this connection */
+    pub async fn connect_admin(url: String) -> Result<Arc<JanusGateway>, JanusError> {
+        Self::connect_with_protocol(url, JANUS_ADMIN_PROTOCOL, None, Arc::new(Box::new(RandomIdGenerator))).await
+    }
+
+    async fn connect_with_protocol(url: String, protocol: &str, secret: Option<String>, ids: Arc<Box<dyn IdGenerator>>) -> Result<Arc<JanusGateway>, JanusError> {
         // TODO: try again with different url
-        let ws = match new_backend_connection(&url).await {
+        let ws = match new_backend_connection(&url, protocol).await {
             Ok(x) => x,
             // TODO: handle all error types
             Err(_) => return Err(JanusError::new(
@@ -64,17 +118,25 @@ impl JanusGateway {
             ))
         };
 
+        let span = debug_span!("janus_gateway", url = %url);
         let (mut wtx, mut wrx) = ws.split();
         let (tx, mut rx) = mpsc::channel::<Message>(32);
         let instance = JanusGateway {
-            // url,
+            url,
+            secret,
             queue: tx,
-            requests: RwLock::new(HashMap::new())
+            transactions: TransactionManager::new(ids),
+            subscribers: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false)
         };
         let instance = Arc::new(instance);
         let gateway = Arc::downgrade(&Arc::clone(&instance));
+        let connected = GaugeGuard::new(metrics::BACKEND_CONNECTIONS.with_label_values(&[&instance.url]));
 
         tokio::spawn(async move {
+            let _connected = connected;
+            // However the reader stops
+            let _closing = Closing(Weak::clone(&gateway));
             loop {
                 let read_next = wrx.next();
                 let queue_next = rx.recv();
@@ -91,7 +153,7 @@ impl JanusGateway {
                                 };
 
                                 match item {
-                                    Ok(message) => gateway.on_websocket_message(message, &mut event).await,
+                                    Ok(message) => gateway.on_websocket_message(message).await,
                                     // TODO: handle socket error properly
                                     Err(e) => match e {
                                         Error::ConnectionClosed => {}
@@ -127,45 +189,70 @@ impl JanusGateway {
                     }
                 }
             }
-
-            let gateway = match gateway.upgrade() {
-                None => return,
-                Some(x) => x
-            };
-            gateway.requests.write().await.clear();
             // NOTE: No need to close websocket manually
-        });
+        }.instrument(span));
 
         Ok(instance)
     }
 
-    pub async fn send(&self, params: IncomingRequestParameters, is_asynchronous: bool) -> Result<JanusResponse, JanusError> {
-        let (tx, rx) = oneshot::channel::<JanusResponse>();
-        let request = JanusGatewayRequest {
-            callback: tx,
-            asynchronous: is_asynchronous
+    /**
+     * Send `params` on behalf of the client request of transaction `params.transaction` (empty for proxy's own),
+     * the response comes back with it whatever the transaction on this connection
+     */
+    pub async fn send(&self, mut params: IncomingRequestParameters, is_asynchronous: bool) -> Result<JanusResponse, JanusError> {
+        params.apisecret = self.secret.clone();
+
+        let client = std::mem::take(&mut params.transaction);
+        let (transaction, rx) = self.transactions.begin(client.clone(), is_asynchronous)?;
+        params.transaction = transaction;
+        let json = match json::stringify(&params) {
+            Ok(x) => x,
+            Err(e) => {
+                self.transactions.cancel(&params.transaction);
+                return Err(e)
+            }
         };
 
-        let json = json::stringify(&params)?;
-        let transaction = params.transaction;
+        // Inside the client request span (if any), so both transactions correlate
+        let span = debug_span!("backend", url = %self.url, janus = %params.janus, backend_transaction = %params.transaction, client_transaction = %client);
+        self.send_text(&params.janus, json, params.transaction, rx).instrument(span).await
+    }
 
-        self.requests.write().await.insert(transaction.clone(), request);    // TODO: avoid copy?
+    async fn send_text(&self, janus: &str, json: String, transaction: String, rx: oneshot::Receiver<JanusResponse>) -> Result<JanusResponse, JanusError> {
+        debug!("Request to janus-gateway");
+        let start = Instant::now();
         if self.queue.clone().send(Message::Text(json)).await.is_err() {
+            self.transactions.cancel(&transaction);
             return Err(JanusError::new(JANUS_ERROR_GATEWAY_CONNECTION_CLOSED, String::from("connection to janus-gateway closed")))
         }
 
         match tokio::time::timeout(Duration::from_secs(5), rx).await {
             Ok(x) => match x {
-                Ok(x) => Ok(x),     // NOTE: leave `response.error` for caller
-                Err(_) => {
-                    self.requests.write().await.remove(&transaction);
-                    Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("janus-gateway send() oneshot channel is closed")))
-                }
+                Ok(x) => {
+                    debug!(response = %x.janus, "Response from janus-gateway");
+                    metrics::BACKEND_REQUEST_DURATION.with_label_values(&[&self.url, janus]).observe(start.elapsed().as_secs_f64());
+                    Ok(x)       // NOTE: leave `response.error` for caller
+                },
+                // Pending requests are dropped when the connection is gone
+                Err(_) => Err(JanusError::new(JANUS_ERROR_GATEWAY_CONNECTION_CLOSED, String::from("connection to janus-gateway closed")))
             },
             Err(_) => {
-                self.requests.write().await.remove(&transaction);
+                warn!("Request to janus-gateway timed out");
+                metrics::BACKEND_TIMEOUTS.with_label_values(&[&self.url]).inc();
+                self.transactions.cancel(&transaction);
                 Err(JanusError::new(JANUS_ERROR_GATEWAY_TIMED_OUT, String::from("Request to janus-gateway backend timed out")))
             }
         }
     }
 }
+
+/** Close a connection once its reader is done, see `JanusGateway::close` */
+struct Closing(Weak<JanusGateway>);
+
+impl Drop for Closing {
+    fn drop(&mut self) {
+        if let Some(x) = self.0.upgrade() {
+            x.close();
+        }
+    }
+}
@@ -0,0 +1,244 @@
+use futures::future::join_all;
+use tokio::time::Duration;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::{debug, info, warn};
+use super::{JanusProxy, ProxyState};
+use super::core::apierror::*;
+use super::core::request::IncomingRequestParameters;
+use super::gateway::JanusGateway;
+use super::metrics;
+
+/** Attempts at destroying the session of a probe before it's left to janus-gateway `session_timeout` */
+static PROBE_DESTROY_ATTEMPTS: u32 = 3;
+
+/** Active health checking of janus-gateway instances, see `JanusProxy::with_health_check` */
+pub struct JanusHealthCheckConfig {
+    /** Every configured backend, including ones currently down */
+    pub backends: Vec<String>,
+    /** Time between two probes of a backend */
+    pub interval: Duration,
+    /** A probe taking longer is a failure */
+    pub timeout: Duration,
+    /** Consecutive successes before a down backend is up again */
+    pub rise: u32,
+    /** Consecutive failures before an up backend is down */
+    pub fall: u32,
+    /** Also create (then destroy) a janus-gateway session, not only `info` */
+    pub probe_session: bool
+}
+
+impl Default for JanusHealthCheckConfig {
+    fn default() -> JanusHealthCheckConfig {
+        JanusHealthCheckConfig {
+            backends: Vec::new(),
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(3),
+            rise: 2,
+            fall: 2,
+            probe_session: false
+        }
+    }
+}
+
+/** Status of a backend with hysteresis, unknown until the first probe */
+#[derive(Default)]
+pub(crate) struct BackendHealth {
+    up: Option<bool>,
+    /** Consecutive probe results contradicting `up` */
+    streak: u32
+}
+
+impl BackendHealth {
+    /** Record a probe result, return the new status if it has changed */
+    fn record(&mut self, success: bool, rise: u32, fall: u32) -> Option<bool> {
+        let up = match self.up {
+            // First probe decides, nothing to be hysteretic about yet
+            None => {
+                self.up = Some(success);
+                return Some(success)
+            },
+            Some(x) => x
+        };
+
+        if success == up {
+            self.streak = 0;
+            return None
+        }
+
+        self.streak += 1;
+        if self.streak < if up { fall } else { rise } {
+            return None
+        }
+        self.streak = 0;
+        self.up = Some(success);
+        Some(success)
+    }
+}
+
+impl JanusProxy {
+    /** Probe backends periodically, only one checker per instance */
+    pub(crate) fn start_health_check(janus: &Arc<JanusProxy>) {
+        if janus.health_check.is_none() || janus.health_check_started.swap(true, Ordering::SeqCst) {
+            return
+        }
+
+        let janus = Arc::downgrade(janus);
+        tokio::spawn(async move {
+            loop {
+                let janus = match janus.upgrade() {
+                    None => break,
+                    Some(x) => x
+                };
+                let config = janus.health_check.as_ref().unwrap();
+                join_all(config.backends.iter().map(|url| janus.check_backend(url, config))).await;
+
+                let interval = config.interval;
+                tokio::select! {
+                    _ = tokio::time::delay_for(interval) => {},
+                    _ = janus.wait_for(ProxyState::Stopped) => break
+                }
+            }
+        });
+    }
+
+    async fn check_backend(&self, url: &str, config: &JanusHealthCheckConfig) {
+        let result = match tokio::time::timeout(config.timeout, self.probe_backend(url, config.probe_session)).await {
+            Ok(x) => x,
+            Err(_) => Err(JanusError::new(JANUS_ERROR_GATEWAY_TIMED_OUT, "Health check timed out".to_string()))
+        };
+        if let Err(e) = &result {
+            debug!(url, reason = %e.reason, "Health check failed");
+        }
+
+        let changed = self.backend_health.lock().unwrap()
+            .entry(url.to_string())
+            .or_default()
+            .record(result.is_ok(), config.rise, config.fall);
+
+        if let Some(up) = changed {
+            match &result {
+                Ok(_) => info!(url, "janus-gateway is up"),
+                Err(e) => warn!(url, reason = %e.reason, "janus-gateway is down")
+            }
+            metrics::BACKEND_UP.with_label_values(&[url]).set(up as i64);
+            self.backend.update_backend(url.to_string(), up).await;
+        }
+    }
+
+    /** Connect, then `info`, then optionally `create` and `destroy` a session */
+    async fn probe_backend(&self, url: &str, probe_session: bool) -> Result<(), JanusError> {
+        let secret = self.backend_secrets.get(url).cloned();
+        let gateway = JanusGateway::connect(url.to_string(), secret, Arc::clone(&self.transaction_ids)).await?;
+
+        let response = gateway.send(IncomingRequestParameters::prepare("info".to_string(), None, None), false).await?;
+        if response.janus != "server_info" {
+            return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, format!("Unexpected response '{}' to info", response.janus)))
+        }
+        if !probe_session {
+            return Ok(())
+        }
+
+        let response = gateway.send(IncomingRequestParameters::prepare("create".to_string(), None, None), false).await?;
+        let session = match response.data {
+            Some(x) => x["id"].as_u64().unwrap_or(0),
+            None => 0
+        };
+        if session == 0 {
+            return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, "Could not obtain janus-gateway session_id".to_string()))
+        }
+
+        // Apart from the probe, so that it goes on even if the probe times out meanwhile
+        let url = url.to_string();
+        match tokio::spawn(Self::destroy_probe_session(gateway, url, session)).await {
+            Ok(x) => x,
+            Err(_) => Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, "Could not destroy probe session".to_string()))
+        }
+    }
+
+    /** Destroy a session created by `probe_backend`, retried on failure. Report its id if it's leaked anyway */
+    async fn destroy_probe_session(gateway: Arc<JanusGateway>, url: String, session: u64) -> Result<(), JanusError> {
+        let mut attempt = 1;
+        loop {
+            let mut request = IncomingRequestParameters::prepare("destroy".to_string(), None, None);
+            request.session_id = session;
+            let e = match gateway.send(request, false).await {
+                Ok(x) => match x.error {
+                    // Gone already is just as fine
+                    Some(e) if e.code != JANUS_ERROR_SESSION_NOT_FOUND => e,
+                    _ => return Ok(())
+                },
+                Err(e) => e
+            };
+
+            if attempt >= PROBE_DESTROY_ATTEMPTS || gateway.is_closed() {
+                warn!(url = %url, session_id = session, reason = %e.reason, "Could not destroy health check session, leaked until it times out");
+                return Err(e)
+            }
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::janus::testing::{self, FakeJanus};
+
+    #[test]
+    fn first_probe_decides() {
+        let mut health = BackendHealth::default();
+        assert_eq!(health.record(false, 2, 2), Some(false));
+        let mut health = BackendHealth::default();
+        assert_eq!(health.record(true, 2, 2), Some(true));
+        assert_eq!(health.record(true, 2, 2), None);
+    }
+
+    #[test]
+    fn status_changes_after_consecutive_probes() {
+        let mut health = BackendHealth::default();
+        health.record(true, 2, 3);
+
+        // Down after `fall` failures in a row, a success in between starts over
+        assert_eq!(health.record(false, 2, 3), None);
+        assert_eq!(health.record(false, 2, 3), None);
+        assert_eq!(health.record(true, 2, 3), None);
+        assert_eq!(health.record(false, 2, 3), None);
+        assert_eq!(health.record(false, 2, 3), None);
+        assert_eq!(health.record(false, 2, 3), Some(false));
+        assert_eq!(health.record(false, 2, 3), None);
+
+        // Up after `rise` successes in a row
+        assert_eq!(health.record(true, 2, 3), None);
+        assert_eq!(health.record(false, 2, 3), None);
+        assert_eq!(health.record(true, 2, 3), None);
+        assert_eq!(health.record(true, 2, 3), Some(true));
+    }
+
+    #[test]
+    fn single_probe_thresholds() {
+        let mut health = BackendHealth::default();
+        health.record(true, 1, 1);
+        assert_eq!(health.record(false, 1, 1), Some(false));
+        assert_eq!(health.record(true, 1, 1), Some(true));
+    }
+
+    #[tokio::test]
+    async fn probe_session_is_destroyed() {
+        let backend = FakeJanus::start().await;
+        let janus = testing::proxy(&[]).await;
+
+        janus.probe_backend(&backend.url, true).await.unwrap();
+        assert_eq!((backend.requests("create"), backend.sessions()), (1, 0));
+
+        // Retried on failure
+        backend.fail("destroy", 1);
+        janus.probe_backend(&backend.url, true).await.unwrap();
+        assert_eq!((backend.requests("destroy"), backend.sessions()), (3, 0));
+
+        // Leaked if it keeps failing, the probe fails
+        backend.fail("destroy", PROBE_DESTROY_ATTEMPTS as usize);
+        assert!(janus.probe_backend(&backend.url, true).await.is_err());
+        assert_eq!(backend.sessions(), 1);
+    }
+}
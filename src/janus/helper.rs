@@ -1,12 +1,14 @@
-use rand::prelude::*;
+use super::provider::{IdGenerator, RandomIdGenerator};
 
-// TODO: u64 not fit Javascript Number (i53)
+/** Random id fitting a JavaScript Number, where no `IdGenerator` is configured */
 pub fn rand_id() -> u64 {
-    let mut rng = thread_rng();     // TODO: this may affect performances?
-    loop {
-        let n: u64 = rng.next_u32() as u64;
-        if n != 0 {
-            return n;
-        }
+    RandomIdGenerator.next_id()
+}
+
+/** Compare secrets in constant time, like janus-gateway `janus_strcmp_const_time` */
+pub fn secure_compare(a: &str, b: &str) -> bool {
+    if a.len() != b.len() {
+        return false
     }
+    a.bytes().zip(b.bytes()).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
 }
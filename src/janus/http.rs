@@ -0,0 +1,431 @@
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{self, HeaderValue};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use async_trait::async_trait;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tracing::{error, info_span, Instrument};
+use url::form_urlencoded;
+use super::{JanusProxy, JanusTransport, ProxyState, next_transport_id};
+use super::core::JanusSession;
+use super::metrics::{self, GaugeGuard};
+use super::core::json::{self, JSON_OBJECT};
+use super::core::apierror::*;
+use super::core::request::IncomingRequestParameters;
+use super::core::response::JanusResponse;
+
+/** Same as janus-gateway `base_path` default */
+static BASE_PATH: &str = "/janus";
+
+/** How long a long-poll request wait for events before replying with "keepalive" */
+static LONG_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/** Session created through http transport, events are queued until fetched by long-poll */
+struct HttpSession {
+    session: Arc<JanusSession>,
+    events: Mutex<mpsc::Receiver<Message>>
+}
+
+/** Janus REST API, ported from janus-gateway `transports/janus_http.c` */
+struct HttpTransport {
+    id: u64,
+    janus: Arc<JanusProxy>,
+    sessions: RwLock<HashMap<u64, Arc<HttpSession>>>
+}
+
+#[async_trait]
+impl JanusTransport for HttpTransport {
+    async fn add_session(&self, id: u64) -> Arc<JanusSession> {
+        let session = Arc::new(JanusSession::new(Arc::clone(&self.janus), id));
+        self.claim_session(Arc::clone(&session)).await;
+        session
+    }
+
+    async fn claim_session(&self, session: Arc<JanusSession>) {
+        // Keep the existing queue, events in it haven't been fetched yet
+        if self.get_session(session.id).await.is_some() {
+            return
+        }
+
+        let (tx, rx) = mpsc::channel::<Message>(32);
+        session.bind(self.id, tx).await;
+        self.sessions.write().await.insert(session.id, Arc::new(HttpSession {
+            session,
+            events: Mutex::new(rx)
+        }));
+    }
+
+    async fn get_session(&self, id: u64) -> Option<Arc<JanusSession>> {
+        let session = self.sessions.read().await.get(&id).map(|x| Arc::clone(&x.session))?;
+        if session.is_owned_by(self.id).await {
+            Some(session)
+        } else {
+            None
+        }
+    }
+
+    async fn remove_session(&self, id: u64) -> Option<Arc<JanusSession>> {
+        self.sessions.write().await.remove(&id).map(|x| Arc::clone(&x.session))
+    }
+}
+
+/**
+ * Unlike websocket, keep accepting connections while draining: each request may come with a new connection.
+ * Stop once sessions are torn down.
+ */
+pub(crate) async fn serve(janus: Arc<JanusProxy>, listener: TcpListener) {
+    let stopped = {
+        let janus = Arc::clone(&janus);
+        async move { janus.wait_for(ProxyState::Stopped).await }
+    };
+    let transport = Arc::new(HttpTransport {
+        id: next_transport_id(),
+        janus,
+        sessions: RwLock::new(HashMap::new())
+    });
+
+    // Sessions belong to the transport rather than a connection, `id` is the same for all
+    let service = make_service_fn(move |conn: &TcpStream| {
+        let transport = Arc::clone(&transport);
+        let peer = conn.peer_addr().ok();
+        // Service lives as long as the connection
+        let connected = GaugeGuard::new(metrics::CONNECTIONS.with_label_values(&["http"]));
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let _ = &connected;
+                let transport = Arc::clone(&transport);
+                let span = info_span!("connection", id = transport.id, peer = ?peer);
+                async move {
+                    Ok::<_, Infallible>(transport.handle(request).await)
+                }.instrument(span)
+            }))
+        }
+    });
+
+    let server = Server::builder(accept::from_stream(listener))
+        .serve(service)
+        .with_graceful_shutdown(stopped);
+    if let Err(e) = server.await {
+        error!(error = %e, "Internal error: http server stopped")
+    }
+}
+
+impl HttpTransport {
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let path = match request.uri().path().strip_prefix(BASE_PATH) {
+            Some(x) if x.is_empty() || x.starts_with('/') => x.to_string(),
+            _ => return Self::reply(StatusCode::NOT_FOUND, Body::from("Not found"))
+        };
+
+        // Map path segments onto `session_id`, `handle_id`
+        let segments = path.split('/').filter(|x| !x.is_empty()).collect::<Vec<&str>>();
+        if segments.len() > 2 {
+            return Self::reply(StatusCode::NOT_FOUND, Body::from("Not found"))
+        }
+
+        // "GET /janus/info" is the only non-numeric path allowed
+        if segments.len() == 1 && segments[0] == "info" && request.method() == Method::GET {
+            let request = IncomingRequestParameters::prepare("info".to_string(), None, None);
+            return Self::reply_json(self.janus.handle_request(self, request).await)
+        }
+
+        let mut ids = [0u64; 2];
+        for (i, x) in segments.iter().enumerate() {
+            match x.parse::<u64>() {
+                Ok(id) if id != 0 => ids[i] = id,
+                _ => return Self::reply(StatusCode::NOT_FOUND, Body::from("Not found"))
+            }
+        }
+        let [session_id, handle_id] = ids;
+
+        match *request.method() {
+            Method::OPTIONS => Self::reply(StatusCode::OK, Body::empty()),
+            Method::GET => {
+                if session_id == 0 || handle_id != 0 {
+                    let error = JanusError::new(JANUS_ERROR_INVALID_REQUEST_PATH, "Unhandled request 'GET' at this path".to_string());
+                    return Self::reply_json(JanusResponse::bad_request(error))
+                }
+                let query = request.uri().query();
+                // At least one, like janus-gateway
+                let maxev = Self::query_param(query, "maxev")
+                    .and_then(|x| x.parse::<usize>().ok())
+                    .unwrap_or(1)
+                    .max(1);
+
+                // Credentials come with query string, e.g. "?token=..." or "?apisecret=..."
+                let mut credentials = JSON_OBJECT::new();
+                if let Some(x) = Self::query_param(query, "token") {
+                    credentials.insert("token".to_string(), x.into());
+                }
+                if let Err(e) = self.janus.authorize(Self::query_param(query, "apisecret").as_deref(), &credentials).await {
+                    return Self::reply_json(JanusResponse::new("error", session_id, String::new()).with_err(e))
+                }
+
+                self.long_poll(session_id, maxev).await
+            },
+            Method::POST => {
+                let body = match hyper::body::to_bytes(request.into_body()).await {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let error = JanusError::new(JANUS_ERROR_TRANSPORT_SPECIFIC, format!("Error reading request body: {}", e));
+                        return Self::reply_json(JanusResponse::bad_request(error))
+                    }
+                };
+                let text = match std::str::from_utf8(&body) {
+                    Ok(x) => x,
+                    Err(_) => {
+                        let error = JanusError::new(JANUS_ERROR_INVALID_JSON, "Invalid JSON: request is not utf-8".to_string());
+                        return Self::reply_json(JanusResponse::bad_request(error))
+                    }
+                };
+
+                let mut request: IncomingRequestParameters = match json::parse(text) {
+                    Ok(x) => x,
+                    Err(e) => return Self::reply_json(JanusResponse::bad_request(e))
+                };
+
+                // Path segments take precedence over body, like janus-gateway
+                if session_id != 0 {
+                    request.session_id = session_id;
+                }
+                if handle_id != 0 {
+                    request.handle_id = handle_id;
+                }
+
+                Self::reply_json(self.janus.handle_request(self, request).await)
+            },
+            _ => Self::reply(StatusCode::METHOD_NOT_ALLOWED, Body::empty())
+        }
+    }
+
+    async fn long_poll(&self, session_id: u64, maxev: usize) -> Response<Body> {
+        let session = self.sessions.read().await.get(&session_id).map(Arc::clone);
+        let session = match session {
+            Some(x) if x.session.is_owned_by(self.id).await => x,
+            Some(x) => {
+                // Claimed by another transport or timed out, hand out what's left (e.g. "timeout" event) then forget it
+                self.sessions.write().await.remove(&session_id);
+                if let Ok(Message::Text(text)) = x.events.lock().await.try_recv() {
+                    return Self::reply(StatusCode::OK, Body::from(text))
+                }
+                let error = JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("No such session {}", session_id));
+                return Self::reply_json(JanusResponse::bad_request(error))
+            },
+            None => {
+                let error = JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("No such session {}", session_id));
+                return Self::reply_json(JanusResponse::bad_request(error))
+            }
+        };
+        // Long-poll counts as activity, like janus-gateway
+        session.session.touch().await;
+
+        let mut events = Vec::new();
+        {
+            let mut queue = session.events.lock().await;
+            if let Ok(Some(Message::Text(x))) = tokio::time::timeout(LONG_POLL_TIMEOUT, queue.recv()).await {
+                events.push(x);
+                while events.len() < maxev {
+                    match queue.try_recv() {
+                        Ok(Message::Text(x)) => events.push(x),
+                        Ok(_) => continue,
+                        Err(_) => break
+                    }
+                }
+            }
+        }
+
+        if events.is_empty() {
+            events.push(JanusResponse::new("keepalive", 0, String::new()).stringify().unwrap());
+        }
+
+        let text = if maxev == 1 {
+            events.remove(0)
+        } else {
+            format!("[{}]", events.join(","))
+        };
+        Self::reply(StatusCode::OK, Body::from(text))
+    }
+
+    /** Value of `name` in query string `query`, percent-decoded */
+    fn query_param(query: Option<&str>, name: &str) -> Option<String> {
+        form_urlencoded::parse(query?.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    fn reply_json(response: JanusResponse) -> Response<Body> {
+        match response.stringify() {
+            Ok(text) => Self::reply(StatusCode::OK, Body::from(text)),
+            Err(e) => Self::reply(StatusCode::INTERNAL_SERVER_ERROR, Body::from(e.reason))
+        }
+    }
+
+    fn reply(status: StatusCode, body: Body) -> Response<Body> {
+        let mut response = Response::new(body);
+        *response.status_mut() = status;
+
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, HeaderValue::from_static("GET, POST, OPTIONS"));
+        headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, HeaderValue::from_static("Origin, Content-Type, Accept"));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+    use crate::janus::testing;
+
+    async fn transport() -> HttpTransport {
+        transport_of(testing::proxy(&[]).await)
+    }
+
+    fn transport_of(janus: JanusProxy) -> HttpTransport {
+        HttpTransport {
+            id: next_transport_id(),
+            janus: Arc::new(janus),
+            sessions: RwLock::new(HashMap::new())
+        }
+    }
+
+    async fn call(transport: &HttpTransport, method: Method, uri: &str, body: Option<Value>) -> (StatusCode, Value) {
+        let body = body.map_or_else(Body::empty, |x| Body::from(x.to_string()));
+        let request = Request::builder().method(method).uri(uri).body(body).unwrap();
+        let response = transport.handle(request).await;
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&bytes).unwrap_or(Value::Null))
+    }
+
+    async fn create(transport: &HttpTransport) -> u64 {
+        let (_, response) = call(transport, Method::POST, "/janus", Some(json!({"janus": "create", "transaction": "c"}))).await;
+        response["data"]["id"].as_u64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn path_maps_to_session_and_handle() {
+        let transport = transport().await;
+        let session = create(&transport).await;
+
+        let attach = json!({"janus": "attach", "plugin": "janus.plugin.videoroom", "transaction": "a"});
+        let (_, response) = call(&transport, Method::POST, &format!("/janus/{}", session), Some(attach)).await;
+        assert_eq!(response["session_id"], session);
+        let handle = response["data"]["id"].as_u64().unwrap();
+
+        // Handle-level request at session path
+        let detach = json!({"janus": "detach", "transaction": "d"});
+        let (_, response) = call(&transport, Method::POST, &format!("/janus/{}", session), Some(detach.clone())).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_INVALID_REQUEST_PATH);
+
+        // Path wins over body
+        let mut body = detach.clone();
+        body["session_id"] = json!(1);
+        body["handle_id"] = json!(1);
+        let (_, response) = call(&transport, Method::POST, &format!("/janus/{}/{}", session, handle), Some(body)).await;
+        assert_eq!((&response["janus"], &response["session_id"]), (&json!("success"), &json!(session)));
+
+        let (_, response) = call(&transport, Method::POST, &format!("/janus/{}/{}", session, handle), Some(detach)).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_HANDLE_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn malformed_paths_are_not_found() {
+        let transport = transport().await;
+        for uri in ["/", "/janusx", "/janus/abc", "/janus/0", "/janus/1/2/3"].iter() {
+            let (status, _) = call(&transport, Method::POST, uri, Some(json!({"janus": "ping", "transaction": "p"}))).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn long_poll_requires_session_path() {
+        let transport = transport().await;
+        let session = create(&transport).await;
+        for uri in [String::from("/janus"), format!("/janus/{}/1", session)].iter() {
+            let (_, response) = call(&transport, Method::GET, uri, None).await;
+            assert_eq!(response["error"]["code"], JANUS_ERROR_INVALID_REQUEST_PATH, "{}", uri);
+        }
+
+        let (_, response) = call(&transport, Method::GET, "/janus/1", None).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_SESSION_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn long_poll_batches_up_to_maxev() {
+        let transport = transport().await;
+        let id = create(&transport).await;
+        let session = transport.get_session(id).await.unwrap();
+        for i in 1..=3 {
+            let event = JanusResponse::new("event", id, format!("e{}", i));
+            session.connection.clone().send(event.into()).await.unwrap();
+        }
+        // Let events reach the transport queue
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let (_, response) = call(&transport, Method::GET, &format!("/janus/{}?maxev=2", id), None).await;
+        let transactions = response.as_array().unwrap().iter().map(|x| x["transaction"].clone()).collect::<Vec<_>>();
+        assert_eq!(transactions, vec![json!("e1"), json!("e2")]);
+
+        // A single object without `maxev`, or with less than one
+        let (_, response) = call(&transport, Method::GET, &format!("/janus/{}", id), None).await;
+        assert_eq!(response["transaction"], "e3");
+        session.connection.clone().send(JanusResponse::new("event", id, "e4".to_string()).into()).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        let (_, response) = call(&transport, Method::GET, &format!("/janus/{}?maxev=0", id), None).await;
+        assert_eq!(response["transaction"], "e4");
+    }
+
+    #[tokio::test]
+    async fn long_poll_credentials_are_decoded() {
+        let secret = "p+ss%w&rd";
+        let transport = transport_of(testing::proxy(&[]).await.with_api_secret(Some(secret.to_string())));
+        let create = json!({"janus": "create", "apisecret": secret, "transaction": "c"});
+        let (_, response) = call(&transport, Method::POST, "/janus", Some(create)).await;
+        let id = response["data"]["id"].as_u64().unwrap();
+
+        let (_, response) = call(&transport, Method::GET, &format!("/janus/{}?apisecret={}", id, secret), None).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+
+        let session = transport.get_session(id).await.unwrap();
+        session.connection.clone().send(JanusResponse::new("event", id, "e".to_string()).into()).await.unwrap();
+        let (_, response) = call(&transport, Method::GET, &format!("/janus/{}?maxev=1&apisecret=p%2Bss%25w%26rd", id), None).await;
+        assert_eq!(response["transaction"], "e");
+    }
+
+    #[tokio::test]
+    async fn lagging_long_poll_does_not_block_session() {
+        let transport = transport().await;
+        let id = create(&transport).await;
+        let session = transport.get_session(id).await.unwrap();
+        // More than the transport queue holds
+        for i in 0..40 {
+            let event = JanusResponse::new("event", id, format!("e{}", i));
+            session.connection.clone().send(event.into()).await.unwrap();
+        }
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+
+        let keepalive = json!({"janus": "keepalive", "transaction": "k"});
+        let uri = format!("/janus/{}", id);
+        let request = call(&transport, Method::POST, &uri, Some(keepalive));
+        let (_, response) = tokio::time::timeout(Duration::from_secs(1), request).await.unwrap();
+        assert_eq!(response["janus"], "ack");
+
+        let uri = format!("/janus/{}?maxev=10", id);
+        let mut transactions = Vec::new();
+        while transactions.len() < 40 {
+            let request = call(&transport, Method::GET, &uri, None);
+            let (_, response) = tokio::time::timeout(Duration::from_secs(1), request).await.unwrap();
+            transactions.extend(response.as_array().unwrap().iter().map(|x| x["transaction"].clone()));
+        }
+        assert_eq!(transactions, (0..40).map(|i| json!(format!("e{}", i))).collect::<Vec<_>>());
+    }
+}
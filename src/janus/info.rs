@@ -0,0 +1,165 @@
+use futures::future::join_all;
+use tokio::sync::RwLock;
+use tokio::time::{Duration, Instant};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::warn;
+use super::JanusProxy;
+use super::core::json::*;
+use super::core::apierror::*;
+use super::core::request::IncomingRequestParameters;
+use super::gateway::JanusGateway;
+
+/** How long a janus-gateway `info` response is reused */
+static BACKEND_INFO_TTL: Duration = Duration::from_secs(60);
+/** How long an unreachable janus-gateway is reported as such before trying again */
+static BACKEND_FAILURE_TTL: Duration = Duration::from_secs(5);
+
+static VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
+
+/** Cached `info` responses of janus-gateway instances, keyed by url, None if it couldn't be fetched */
+#[derive(Default)]
+pub(crate) struct BackendInfoCache {
+    entries: RwLock<HashMap<String, (Instant, Option<JSON_OBJECT>)>>
+}
+
+/** Same numbering as janus-gateway, e.g. "0.10.5" is 1005 */
+fn version() -> u64 {
+    VERSION_STRING.split('.')
+        .take(3)
+        .map(|x| x.parse::<u64>().unwrap_or(0))
+        .fold(0, |acc, x| acc * 100 + x)
+}
+
+fn transport_info(name: &str) -> JSON_ANY {
+    let description = match name {
+        "janus.transport.websockets" => "Janus API over WebSockets, served by janus-proxy",
+        "janus.transport.http" => "Janus API over HTTP/REST with long-poll, served by janus-proxy",
+        _ => "Served by janus-proxy"
+    };
+    json!({
+        "name": name,
+        "author": "osddeitf",
+        "description": description,
+        "version_string": VERSION_STRING,
+        "version": version()
+    })
+}
+
+impl JanusProxy {
+    /**
+     * Build `server_info` like janus-gateway `janus_info`: fields of a healthy janus-gateway instance,
+     * overridden with what the proxy actually serves (transports, plugins, authentication).
+     */
+    pub(crate) async fn server_info(&self) -> JSON_OBJECT {
+        let mut urls = self.backend.list_backends().await;
+        urls.sort();
+
+        let backends = join_all(urls.iter().map(|url| self.backend_info(url))).await;
+        let backends = urls.into_iter().zip(backends).collect::<Vec<(String, Option<JSON_OBJECT>)>>();
+
+        // Base on the first reachable instance, so that version fields reflect real janus-gateway
+        let mut info = backends.iter()
+            .find_map(|(_, x)| x.clone())
+            .unwrap_or_default();
+
+        let mut plugins = JSON_OBJECT::new();
+        for name in self.plugins.names() {
+            let descriptor = backends.iter()
+                .find_map(|(_, x)| x.as_ref()?.get("plugins")?.get(&name).cloned());
+
+            let mut plugin = match descriptor {
+                Some(JSON_ANY::Object(x)) => x,
+                _ => JSON_OBJECT::new()
+            };
+            // Flag plugins no healthy backend actually supports
+            plugin.insert("available".to_string(), json!(!plugin.is_empty()));
+            plugins.insert(name, JSON_ANY::Object(plugin));
+        }
+
+        let mut transports = JSON_OBJECT::new();
+        for name in self.transports.lock().unwrap().iter() {
+            transports.insert(name.to_string(), transport_info(name));
+        }
+
+        let mut proxy_backends = JSON_OBJECT::new();
+        for (url, x) in backends.iter() {
+            proxy_backends.insert(url.clone(), json!({
+                "reachable": x.is_some(),
+                "version_string": x.as_ref().and_then(|x| x.get("version_string").cloned())
+            }));
+        }
+
+        info.insert("name".to_string(), json!("Janus WebRTC Server"));
+        info.insert("api_secret".to_string(), json!(self.api_secret.is_some()));
+        info.insert("auth_token".to_string(), json!(self.token_auth));
+        info.insert("accepting-new-sessions".to_string(), json!(self.accepting_sessions.load(Ordering::SeqCst)));
+        info.insert("session-timeout".to_string(), json!(self.session_timeout.as_secs()));
+        info.insert("reclaim-session-timeout".to_string(), json!(self.reclaim_timeout.as_secs()));
+        info.insert("transports".to_string(), JSON_ANY::Object(transports));
+        info.insert("plugins".to_string(), JSON_ANY::Object(plugins));
+        info.insert("proxy".to_string(), json!({
+            "name": "janus-proxy",
+            "version_string": VERSION_STRING,
+            "version": version(),
+            "backends": proxy_backends
+        }));
+        info
+    }
+
+    /** `info` of a janus-gateway instance, None if unreachable */
+    async fn backend_info(&self, url: &str) -> Option<JSON_OBJECT> {
+        if let Some((time, info)) = self.backend_info.entries.read().await.get(url) {
+            let ttl = if info.is_some() { BACKEND_INFO_TTL } else { BACKEND_FAILURE_TTL };
+            if time.elapsed() < ttl {
+                return info.clone()
+            }
+        }
+
+        // Failures are kept too, so that `info` requests don't dial unreachable instances every time
+        let info = match self.fetch_backend_info(url).await {
+            Ok(info) => Some(info),
+            Err(e) => {
+                warn!(url, reason = %e.reason, "Could not get info of janus-gateway");
+                None
+            }
+        };
+        self.backend_info.entries.write().await.insert(url.to_string(), (Instant::now(), info.clone()));
+        info
+    }
+
+    async fn fetch_backend_info(&self, url: &str) -> Result<JSON_OBJECT, JanusError> {
+        let secret = self.backend_secrets.get(url).cloned();
+        let gateway = JanusGateway::connect(url.to_string(), secret, Arc::clone(&self.transaction_ids)).await?;
+
+        let response = gateway.send(IncomingRequestParameters::prepare("info".to_string(), None, None), false).await?;
+        if let Some(e) = response.error {
+            return Err(e)
+        }
+        if response.janus != "server_info" {
+            return Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, format!("Unexpected response '{}'", response.janus)))
+        }
+        Ok(response.rest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::janus::testing;
+
+    #[tokio::test]
+    async fn unreachable_backends_are_cached() {
+        let url = "ws://127.0.0.1:1";
+        let janus = testing::proxy(&[url]).await;
+
+        let info = janus.server_info().await;
+        assert_eq!(info["proxy"]["backends"][url]["reachable"], false);
+        let (time, info) = janus.backend_info.entries.read().await.get(url).cloned().unwrap();
+        assert!(info.is_none());
+
+        janus.server_info().await;
+        assert_eq!(janus.backend_info.entries.read().await.get(url).unwrap().0, time);
+    }
+}
@@ -0,0 +1,202 @@
+/**
+ * Prometheus metrics, served as text exposition format at `GET /metrics` on the metrics listener.
+ * Metrics are process-wide, like janus-gateway counters.
+ */
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::header::{self, HeaderValue};
+use hyper::server::accept;
+use hyper::service::{make_service_fn, service_fn};
+use lazy_static::lazy_static;
+use prometheus::{Encoder, TextEncoder, IntGauge, IntGaugeVec, IntCounter, IntCounterVec, HistogramVec};
+use prometheus::{register_int_gauge, register_int_gauge_vec, register_int_counter, register_int_counter_vec, register_histogram_vec};
+use tokio::net::TcpListener;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tracing::error;
+use super::{JanusProxy, ProxyState};
+
+/** Requests are counted by `janus` verb, anything else is "unknown" to keep label cardinality bounded */
+static KNOWN_REQUESTS: [&str; 11] = [
+    "ping", "info", "create", "destroy", "keepalive", "claim",
+    "attach", "detach", "hangup", "message", "trickle"
+];
+
+lazy_static! {
+    pub(crate) static ref CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "janus_proxy_connections", "Active client connections", &["transport"]
+    ).unwrap();
+
+    pub(crate) static ref SESSIONS: IntGauge = register_int_gauge!(
+        "janus_proxy_sessions", "Active Janus sessions"
+    ).unwrap();
+
+    pub(crate) static ref HANDLES: IntGauge = register_int_gauge!(
+        "janus_proxy_handles", "Active plugin handles"
+    ).unwrap();
+
+    pub(crate) static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "janus_proxy_requests_total", "Client requests by janus verb", &["janus"]
+    ).unwrap();
+
+    pub(crate) static ref REQUEST_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "janus_proxy_request_errors_total", "Client requests answered with an error, by janus verb and error code", &["janus", "code"]
+    ).unwrap();
+
+    pub(crate) static ref BACKEND_REQUEST_DURATION: HistogramVec = register_histogram_vec!(
+        "janus_proxy_backend_request_duration_seconds", "Latency of requests to janus-gateway", &["url", "janus"]
+    ).unwrap();
+
+    pub(crate) static ref BACKEND_TIMEOUTS: IntCounterVec = register_int_counter_vec!(
+        "janus_proxy_backend_timeouts_total", "Requests to janus-gateway timed out (JANUS_ERROR_GATEWAY_TIMED_OUT)", &["url"]
+    ).unwrap();
+
+    pub(crate) static ref BACKEND_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "janus_proxy_backend_connections", "Open websocket connections to janus-gateway", &["url"]
+    ).unwrap();
+
+    pub(crate) static ref BACKEND_SESSIONS: IntGaugeVec = register_int_gauge_vec!(
+        "janus_proxy_backend_sessions", "Sessions opened on janus-gateway by this instance", &["url"]
+    ).unwrap();
+
+    pub(crate) static ref BACKEND_UP: IntGaugeVec = register_int_gauge_vec!(
+        "janus_proxy_backend_up", "Whether janus-gateway passes health checks", &["url"]
+    ).unwrap();
+
+    pub(crate) static ref DROPPED_EVENTS: IntCounterVec = register_int_counter_vec!(
+        "janus_proxy_dropped_events_total", "Events which never reached their client, by reason: \"queue_full\", \"disconnect\" or \"detached\"", &["reason"]
+    ).unwrap();
+
+    pub(crate) static ref EVENT_OVERFLOW_DISCONNECTS: IntCounter = register_int_counter!(
+        "janus_proxy_event_overflow_disconnects_total", "Sessions destroyed because their client didn't keep up with events"
+    ).unwrap();
+
+    pub(crate) static ref VIDEOROOM_ROOMS: IntGauge = register_int_gauge!(
+        "janus_proxy_videoroom_rooms", "VideoRoom rooms created"
+    ).unwrap();
+
+    pub(crate) static ref VIDEOROOM_PARTICIPANTS: IntGaugeVec = register_int_gauge_vec!(
+        "janus_proxy_videoroom_participants", "VideoRoom participants by type", &["ptype"]
+    ).unwrap();
+}
+
+/** Label value for a client `janus` verb */
+pub(crate) fn request_label(janus: &str) -> &str {
+    if KNOWN_REQUESTS.contains(&janus) {
+        janus
+    } else {
+        "unknown"
+    }
+}
+
+/** Decrement `gauge` when dropped, for values tied to an object lifetime */
+pub(crate) struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub(crate) fn new(gauge: IntGauge) -> GaugeGuard {
+        gauge.inc();
+        GaugeGuard(gauge)
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+fn render() -> Response<Body> {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        error!(error = %e, "Could not encode metrics");
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+        return response
+    }
+
+    let mut response = Response::new(Body::from(buffer));
+    response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain; version=0.0.4"));
+    response
+}
+
+async fn handle(request: Request<Body>) -> Response<Body> {
+    if request.method() == Method::GET && request.uri().path() == "/metrics" {
+        return render()
+    }
+    let mut response = Response::new(Body::from("Not found"));
+    *response.status_mut() = StatusCode::NOT_FOUND;
+    response
+}
+
+/** Stay available while draining, stop with the other listeners */
+pub(crate) async fn serve(janus: Arc<JanusProxy>, listener: TcpListener) {
+    let stopped = async move { janus.wait_for(ProxyState::Stopped).await };
+    let service = make_service_fn(|_conn| async {
+        Ok::<_, Infallible>(service_fn(|request| async {
+            Ok::<_, Infallible>(handle(request).await)
+        }))
+    });
+
+    let server = Server::builder(accept::from_stream(listener))
+        .serve(service)
+        .with_graceful_shutdown(stopped);
+    if let Err(e) = server.await {
+        error!(error = %e, "Internal error: metrics server stopped")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Client;
+    use serde_json::json;
+    use crate::janus::testing::{self, FakeJanus, WsClient};
+
+    async fn scrape(url: &str, method: Method, path: &str) -> (StatusCode, Option<HeaderValue>, String) {
+        let request = Request::builder().method(method).uri(format!("{}{}", url, path)).body(Body::empty()).unwrap();
+        let response = Client::new().request(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers().get(header::CONTENT_TYPE).cloned();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    /** Value of the sample named `name` (labels included), metrics are shared with concurrent tests */
+    fn sample(text: &str, name: &str) -> Option<i64> {
+        text.lines()
+            .find_map(|x| x.strip_prefix(name)?.strip_prefix(' '))
+            .map(|x| x.parse().unwrap())
+    }
+
+    #[tokio::test]
+    async fn metrics_are_scraped() {
+        let backend = FakeJanus::start().await;
+        let janus = Arc::new(testing::proxy(&[&backend.url]).await);
+        let mut client = WsClient::connect(&testing::listen(Arc::clone(&janus)).await).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(JanusProxy::listen_metrics(janus, listener));
+        let backend_sessions = format!("janus_proxy_backend_sessions{{url=\"{}\"}}", backend.url);
+
+        let session = client.create().await;
+        let handle = client.attach(session, "janus.plugin.videoroom").await;
+        client.join(session, handle).await;
+        let (status, content_type, text) = scrape(&url, Method::GET, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(content_type, Some(HeaderValue::from_static("text/plain; version=0.0.4")));
+        assert!(text.contains("# HELP janus_proxy_sessions Active Janus sessions\n# TYPE janus_proxy_sessions gauge\n"), "{}", text);
+        assert!(text.contains("# TYPE janus_proxy_requests_total counter\n"), "{}", text);
+        assert!(sample(&text, "janus_proxy_sessions").unwrap() >= 1);
+        assert!(sample(&text, "janus_proxy_handles").unwrap() >= 1);
+        assert_eq!(sample(&text, &backend_sessions), Some(1));
+
+        let response = client.request(json!({"janus": "destroy", "session_id": session, "transaction": "d"})).await;
+        assert_eq!(response["janus"], "success");
+        let (_, _, text) = scrape(&url, Method::GET, "/metrics").await;
+        assert_eq!(sample(&text, &backend_sessions), Some(0));
+
+        for (method, path) in [(Method::GET, "/"), (Method::GET, "/metrics/x"), (Method::POST, "/metrics")].iter() {
+            let (status, _, _) = scrape(&url, method.clone(), path).await;
+            assert_eq!(status, StatusCode::NOT_FOUND, "{} {}", method, path);
+        }
+    }
+}
@@ -2,38 +2,129 @@ mod core;
 mod connection;
 mod helper;
 mod gateway;
+mod http;
+mod websocket;
+mod info;
+mod health;
+mod metrics;
+mod pool;
+mod sweeper;
+mod transaction;
+mod events;
+#[cfg(test)]
+mod testing;
+pub mod admin;
+pub mod tls;
 pub mod plugin;
 pub mod provider;
 
 /**
 * Request types are ported from janus-gateway v0.10.5
 */
-use futures::{StreamExt, SinkExt};
+use futures::future::join_all;
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
-use tokio_tungstenite::tungstenite::{Message, Error};
+use tokio::sync::{watch, RwLock};
+use tokio::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
 use serde_json::json;
-use std::sync::Arc;
+use async_trait::async_trait;
+use tracing::{debug, info, warn, info_span, Instrument};
+use std::collections::{HashMap, BTreeSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use self::core::*;
 use self::core::apierror::*;
 use self::request::*;
 use self::response::*;
-use self::provider::{ProxyStateProvider, JanusBackendProvider};
-use self::connection::accept_ws;
+use self::json::JSON_OBJECT;
+use self::provider::{ProxyStateProvider, JanusBackendProvider, IdGenerator, RandomIdGenerator};
+use self::admin::JanusAdminConfig;
+use self::info::BackendInfoCache;
+use self::health::BackendHealth;
+use self::pool::JanusGatewayPool;
+use self::gateway::JanusGateway;
+pub use self::health::JanusHealthCheckConfig;
+pub use self::events::{EventOverflowPolicy, overflow_policy};
+use self::tls::TlsAcceptor;
 use self::plugin::{JanusPluginProvider, JanusPluginResultType::*, JanusPluginMessage};
 
-// TODO: add gracefully shutdown
+/** Session bookkeeping of a transport, e.g. a websocket connection or http long-poll queues */
+#[async_trait]
+pub(crate) trait JanusTransport: Send + Sync {
+    /** Bind a newly created session to this transport, events are delivered through it */
+    async fn add_session(&self, id: u64) -> Arc<JanusSession>;
+    /** Bind an existing session to this transport, i.e. `claim` */
+    async fn claim_session(&self, session: Arc<JanusSession>);
+    /** Sessions claimed by other transports must not be returned */
+    async fn get_session(&self, id: u64) -> Option<Arc<JanusSession>>;
+    async fn remove_session(&self, id: u64) -> Option<Arc<JanusSession>>;
+}
+
+/** Unique id of a transport instance, sessions are bound to it */
+pub(crate) fn next_transport_id() -> u64 {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/** Lifecycle of a proxy instance, see `JanusProxy::shutdown` */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum ProxyState {
+    Running,
+    /** No new connections, existing sessions go on */
+    Draining,
+    /** Sessions torn down, client connections are being closed */
+    Stopped
+}
+
 pub struct JanusProxy {
-    // /** Local mapping from connection_id -> session_id. TODO: Is there any better way? */
-    // connections: RwLock<HashMap<u64, Option<u64>>>,
-    // /** Local sessions (managed by this instance, corresponding to a websocket connection) store */
-    // sessions: RwLock<HashMap<u64, JanusSession>>,
+    /** Local sessions (managed by this instance), regardless of transport */
+    sessions: RwLock<HashMap<u64, Arc<JanusSession>>>,
     /** Shared state between proxy instances: include "session_ids" and "handle_ids" */
     state: Arc<Box<dyn ProxyStateProvider>>,
     /** Stored backend, like `state` above */
     backend: Arc<Box<dyn JanusBackendProvider>>,
     /** Plugin resolver */
-    plugins: JanusPluginProvider
+    plugins: JanusPluginProvider,
+    /** Admin API settings */
+    admin: JanusAdminConfig,
+    /** Admin connections to janus-gateway instances, keyed by janus-gateway url, opened on first use */
+    admin_gateways: Mutex<HashMap<String, Arc<JanusGateway>>>,
+    /** Whether stored tokens (see `ProxyStateProvider::add_token`) are required */
+    token_auth: bool,
+    /** Shared secret clients must provide, mirror janus-gateway `api_secret` */
+    api_secret: Option<String>,
+    /** `api_secret` of each janus-gateway instance, keyed by url */
+    backend_secrets: HashMap<String, String>,
+    /** How long a session outlives its transport waiting for `claim`, zero to destroy immediately */
+    reclaim_timeout: Duration,
+    /** Sessions without any request for that long are destroyed, zero to disable */
+    session_timeout: Duration,
+    /** Connections to janus-gateway instances, shared by handles */
+    gateways: JanusGatewayPool,
+    /** Transactions of requests to janus-gateway instances */
+    transaction_ids: Arc<Box<dyn IdGenerator>>,
+    /** Keep handles when their janus-gateway connection is lost, see `JanusHandle::on_gateway_lost` */
+    backend_reconnect: bool,
+    /** Backend events waiting for a client, per handle, and events kept for a detached session */
+    event_queue_size: usize,
+    event_overflow: EventOverflowPolicy,
+    watchdog_started: AtomicBool,
+    /** Reclaim leases of instances gone without shutdown that often, zero to disable */
+    sweep_interval: Duration,
+    sweeper_started: AtomicBool,
+    /** Whether `create` is allowed, mirror janus-gateway `accept_new_sessions` */
+    accepting_sessions: AtomicBool,
+    lifecycle: watch::Sender<ProxyState>,
+    lifecycle_rx: watch::Receiver<ProxyState>,
+    /** Serve `wss://` on client listener when present */
+    tls: Option<Arc<TlsAcceptor>>,
+    /** Transport plugins served by this instance, registered by `listen*` */
+    transports: Mutex<BTreeSet<&'static str>>,
+    backend_info: BackendInfoCache,
+    /** Probe backends and keep `backend` up to date when present */
+    health_check: Option<JanusHealthCheckConfig>,
+    health_check_started: AtomicBool,
+    backend_health: Mutex<HashMap<String, BackendHealth>>
 }
 
 impl JanusProxy {
@@ -42,96 +133,306 @@ impl JanusProxy {
         state_provider: Arc<Box<dyn ProxyStateProvider>>,
         backend_provider: Arc<Box<dyn JanusBackendProvider>>
     ) -> JanusProxy {
+        let (lifecycle, lifecycle_rx) = watch::channel(ProxyState::Running);
         JanusProxy {
-            // connections: RwLock::new(HashMap::new()),
-            // sessions: RwLock::new(HashMap::new()),
+            sessions: RwLock::new(HashMap::new()),
             state: state_provider,
             backend: backend_provider,
-            plugins: plugin_provider
+            plugins: plugin_provider,
+            admin: JanusAdminConfig::default(),
+            admin_gateways: Mutex::new(HashMap::new()),
+            token_auth: false,
+            api_secret: None,
+            backend_secrets: HashMap::new(),
+            reclaim_timeout: Duration::from_secs(0),
+            session_timeout: Duration::from_secs(60),
+            gateways: JanusGatewayPool::new(100),
+            transaction_ids: Arc::new(Box::new(RandomIdGenerator)),
+            backend_reconnect: true,
+            event_queue_size: 64,
+            event_overflow: EventOverflowPolicy::DropOldest,
+            watchdog_started: AtomicBool::new(false),
+            sweep_interval: Duration::from_secs(30),
+            sweeper_started: AtomicBool::new(false),
+            accepting_sessions: AtomicBool::new(true),
+            lifecycle, lifecycle_rx,
+            tls: None,
+            transports: Mutex::new(BTreeSet::new()),
+            backend_info: BackendInfoCache::default(),
+            health_check: None,
+            health_check_started: AtomicBool::new(false),
+            backend_health: Mutex::new(HashMap::new())
         }
     }
 
-    pub async fn listen(proxy: JanusProxy, mut listener: TcpListener) {
-        let janus = Arc::new(proxy);
-
-        while let Ok((stream, _addr)) = listener.accept().await {
-            let ws = accept_ws(stream).await.unwrap();
-            let (mut wtx, mut wrx) = ws.split();
-            let (mut tx, mut rx) = mpsc::channel::<Message>(32);
-
-            println!("New connection");
-            tokio::spawn(async move {
-                while let Some(message) = rx.recv().await {
-                    if let Err(e) = wtx.send(message).await {
-                        // TODO: more properly error handling
-                        match e {
-                            Error::ConnectionClosed => println!("Connection closed"),
-                            Error::AlreadyClosed => eprintln!("Internal error: connection already closed"),
-                            // Error::Io(_) => {}
-                            // Error::Tls(_) => {}
-                            // Error::Capacity(_) => {}
-                            // Error::Protocol(_) => {}
-                            // Error::SendQueueFull(_) => {}
-                            // Error::Utf8 => {}
-                            // Error::Url(_) => {}
-                            // Error::Http(_) => {}
-                            // Error::HttpFormat(_) => {}
-                            _ => continue
-                        }
-                        break
+    pub fn with_admin(mut self, config: JanusAdminConfig) -> JanusProxy {
+        self.admin = config;
+        self
+    }
+
+    pub fn with_token_auth(mut self, enabled: bool) -> JanusProxy {
+        self.token_auth = enabled;
+        self
+    }
+
+    pub fn with_api_secret(mut self, secret: Option<String>) -> JanusProxy {
+        self.api_secret = secret;
+        self
+    }
+
+    pub fn with_backend_secret(mut self, url: String, secret: String) -> JanusProxy {
+        self.backend_secrets.insert(url, secret);
+        self
+    }
+
+    /** Same as janus-gateway `reclaim_session_timeout` */
+    pub fn with_reclaim_timeout(mut self, timeout: Duration) -> JanusProxy {
+        self.reclaim_timeout = timeout;
+        self
+    }
+
+    /** Same as janus-gateway `session_timeout`, default 60 seconds */
+    pub fn with_session_timeout(mut self, timeout: Duration) -> JanusProxy {
+        self.session_timeout = timeout;
+        self
+    }
+
+    /** Handles sharing a janus-gateway connection before another one is opened, default 100 */
+    pub fn with_backend_pool(mut self, sessions_per_connection: usize) -> JanusProxy {
+        self.gateways = JanusGatewayPool::new(sessions_per_connection);
+        self
+    }
+
+    /** Recreate janus-gateway session and handle when connection is lost (default), otherwise handles are detached */
+    pub fn with_backend_reconnect(mut self, enabled: bool) -> JanusProxy {
+        self.backend_reconnect = enabled;
+        self
+    }
+
+    /** Backend events waiting for a slow client before `overflow` applies, default 64 and `DropOldest` */
+    pub fn with_event_queue(mut self, size: usize, overflow: EventOverflowPolicy) -> JanusProxy {
+        self.event_queue_size = size.max(1);
+        self.event_overflow = overflow;
+        self
+    }
+
+    /** Terminate TLS on websocket listener (see `listen`) */
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> JanusProxy {
+        self.tls = Some(Arc::new(acceptor));
+        self
+    }
+
+    /** Source of transactions of requests to janus-gateway instances, random by default */
+    pub fn with_transaction_ids(mut self, ids: Box<dyn IdGenerator>) -> JanusProxy {
+        self.transaction_ids = Arc::new(ids);
+        self
+    }
+
+    /** Check for expired leases of other proxy instances that often, see `ProxyStateProvider::sweep` */
+    pub fn with_sweep_interval(mut self, interval: Duration) -> JanusProxy {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /** Mark backends up/down from periodic probes, instead of trusting `JanusBackendProvider` as is */
+    pub fn with_health_check(mut self, config: JanusHealthCheckConfig) -> JanusProxy {
+        self.health_check = Some(config);
+        self
+    }
+
+    /** Serve Janus API over websocket (janus-protocol) on `listener`, `wss://` if configured `with_tls` */
+    pub async fn listen(janus: Arc<JanusProxy>, listener: TcpListener) {
+        Self::start_watchdog(&janus);
+        Self::start_health_check(&janus);
+        Self::start_sweeper(&janus);
+        if let Some(x) = &janus.tls {
+            TlsAcceptor::watch(x);
+        }
+        janus.transports.lock().unwrap().insert("janus.transport.websockets");
+        websocket::serve(janus, listener).await
+    }
+
+    /** Serve Janus REST API (with long-poll events) on `listener` */
+    pub async fn listen_http(janus: Arc<JanusProxy>, listener: TcpListener) {
+        Self::start_watchdog(&janus);
+        Self::start_health_check(&janus);
+        Self::start_sweeper(&janus);
+        janus.transports.lock().unwrap().insert("janus.transport.http");
+        http::serve(janus, listener).await
+    }
+
+    /** Serve Janus Admin API over websocket (janus-admin-protocol) on `listener` */
+    pub async fn listen_admin(janus: Arc<JanusProxy>, listener: TcpListener) {
+        admin::serve(janus, listener).await
+    }
+
+    /** Serve Prometheus metrics at `GET /metrics` on `listener` */
+    pub async fn listen_metrics(janus: Arc<JanusProxy>, listener: TcpListener) {
+        metrics::serve(janus, listener).await
+    }
+
+    /** Allow or refuse `create` with JANUS_ERROR_NOT_ACCEPTING_SESSIONS */
+    pub fn accept_new_sessions(&self, accept: bool) {
+        self.accepting_sessions.store(accept, Ordering::SeqCst);
+    }
+
+    /**
+     * Graceful shutdown: stop accepting connections (and new sessions too if `refuse_sessions`),
+     * wait up to `deadline` for sessions to end, then tear down the rest concurrently and close client connections.
+     * Teardown is given up after `deadline` too, e.g. when janus-gateway doesn't answer.
+     * `listen*` return once it's done.
+     */
+    pub async fn shutdown(&self, deadline: Duration, refuse_sessions: bool) {
+        if refuse_sessions {
+            self.accept_new_sessions(false);
+        }
+        let _ = self.lifecycle.broadcast(ProxyState::Draining);
+
+        let start = Instant::now();
+        loop {
+            let count = self.sessions.read().await.len();
+            if count == 0 || start.elapsed() >= deadline {
+                break
+            }
+            info!(sessions = count, "Shutting down, waiting for sessions to end");
+            tokio::time::delay_for(Duration::from_secs(1)).await;
+        }
+
+        // Detach backend handles and destroy backend sessions
+        let sessions = self.sessions.read().await.values().map(Arc::clone).collect::<Vec<_>>();
+        let teardown = join_all(sessions.iter().map(|session| async move {
+            session.unbind(None).await;
+            self.remove_session(session).await;
+        }));
+        if tokio::time::timeout(deadline, teardown).await.is_err() {
+            warn!(sessions = sessions.len(), "Shutting down, teardown timed out");
+        }
+
+        let _ = self.lifecycle.broadcast(ProxyState::Stopped);
+    }
+
+    /** Resolve once lifecycle reaches `state` */
+    pub(crate) async fn wait_for(&self, state: ProxyState) {
+        let mut rx = self.lifecycle_rx.clone();
+        while let Some(x) = rx.recv().await {
+            if x >= state {
+                return
+            }
+        }
+    }
+
+    /** Expire inactive sessions, mimic janus-gateway `janus_sessions_watchdog`. Only one per instance */
+    fn start_watchdog(janus: &Arc<JanusProxy>) {
+        if janus.session_timeout == Duration::from_secs(0) || janus.watchdog_started.swap(true, Ordering::SeqCst) {
+            return
+        }
+
+        let janus = Arc::downgrade(janus);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(Duration::from_secs(2)).await;
+                let janus = match janus.upgrade() {
+                    None => break,
+                    Some(x) => x
+                };
+
+                let sessions = janus.sessions.read().await.values().map(Arc::clone).collect::<Vec<_>>();
+                for session in sessions.into_iter() {
+                    if session.idle_time().await < janus.session_timeout {
+                        continue
                     }
-                }
-            });
 
-            // Each connection correspond to a session, which may or may not have an id.
-            // Each process websocket message in synchronous fashion, response before next request.
-            // And, may emit event back to websocket connection.
-            let janus = Arc::clone(&janus);
-            tokio::spawn(async move {
-                // Assign session id beforehand
-                let id = janus.state.new_session();
-                let session = JanusSession::new(Arc::clone(&janus), id, tx.clone());
-                let session = Arc::new(session);  // for WeakRef from handle
-
-                while let Some(item) = wrx.next().await {
-                    match item {
-                        Ok(message) => {
-                            let res = janus.handle_websocket(&session, message).await;
-                            if tx.send(res).await.is_err() {
-                                break     // channel closed
-                            }
-                        },
-                        Err(e) => eprintln!("Internal error: {}", e)
-                    };
+                    info!(session_id = session.id, "Timeout expired");
+                    let event = JanusResponse::new("timeout", session.id, String::new());
+                    session.unbind(Some(event.into())).await;
+                    janus.remove_session(&session).await;
                 }
+            }
+        });
+    }
 
-                // This clean up session (if present) and any resources associated (owned) with it
-                janus.state.remove_session(&id);
-            });
+    /** Release shared state owned by a session that no longer belongs to any transport */
+    async fn remove_session(&self, session: &Arc<JanusSession>) {
+        if self.sessions.write().await.remove(&session.id).is_some() {
+            metrics::SESSIONS.dec();
+        }
+        let handles = session.handles.write().await.drain().map(|(_, x)| x).collect::<Vec<_>>();
+        for handle in handles.iter() {
+            self.state.remove_handle(&handle.id).await;
+            metrics::HANDLES.dec();
+        }
+        self.state.remove_session(&session.id).await;
+        for handle in handles.iter() {
+            handle.destroy_gateway().await;
         }
     }
 
-    async fn handle_websocket(&self, session: &Arc<JanusSession>, item: Message) -> Message {
-        if let Message::Text(data) = item {
-            self.handle_request(session, data).await.into()
-        }
-        else {
-            item
+    /** Destroy a session whose client doesn't keep up with events, and close its transport (websocket only) */
+    async fn disconnect_session(&self, session: &Arc<JanusSession>) {
+        warn!(session_id = session.id, "Event queue full, disconnecting");
+        metrics::EVENT_OVERFLOW_DISCONNECTS.inc();
+        session.unbind(Some(Message::Close(None))).await;
+        self.remove_session(session).await;
+    }
+
+    /**
+     * Called when transport `owner` is gone, the session is destroyed unless claimed within `reclaim_timeout`.
+     * Do nothing if another transport has claimed it already.
+     */
+    async fn release_session(&self, session: Arc<JanusSession>, owner: u64) {
+        let epoch = match session.release(owner).await {
+            Some(x) => x,
+            None => return
+        };
+
+        if self.reclaim_timeout == Duration::from_secs(0) {
+            self.remove_session(&session).await;
+            return
         }
+
+        let timeout = self.reclaim_timeout;
+        tokio::spawn(async move {
+            tokio::time::delay_for(timeout).await;
+            if session.is_released(epoch).await {
+                info!(session_id = session.id, "Session not claimed in time, destroying");
+                session.app.remove_session(&session).await;
+            }
+        });
     }
 
-    async fn handle_request(&self, session: &Arc<JanusSession>, text: String) -> JanusResponse {
-        let request: IncomingRequestParameters = match json::parse(&text) {
-            Ok(x) => x,
-            Err(e) => return JanusResponse::bad_request(e)
+    /**
+     * Check credentials of a request, either `apisecret` or stored token is enough.
+     * Return the stored token if it's the one authorized.
+     */
+    async fn authorize(&self, apisecret: Option<&str>, rest: &JSON_OBJECT) -> Result<Option<String>, JanusError> {
+        if self.api_secret.is_none() && !self.token_auth {
+            return Ok(None)
+        }
+
+        let secret_authorized = match (&self.api_secret, apisecret) {
+            (Some(secret), Some(x)) => helper::secure_compare(x, secret),
+            _ => false
         };
 
+        let token = match rest.get("token").and_then(|x| x.as_str()) {
+            Some(x) if self.token_auth && self.state.check_token(x).await => Some(x.to_string()),
+            _ => None
+        };
+
+        if !secret_authorized && token.is_none() {
+            return Err(JanusError::new(JANUS_ERROR_UNAUTHORIZED, "Unauthorized request (wrong or missing secret/token)".to_string()))
+        }
+        Ok(token)
+    }
+
+    async fn handle_request(&self, transport: &dyn JanusTransport, request: IncomingRequestParameters) -> JanusResponse {
         let IncomingRequestParameters {
             transaction,
             janus: message_text,
             session_id,
             handle_id,
             body, jsep,
+            apisecret,
             rest,
             ..
         } = request;
@@ -139,18 +440,37 @@ impl JanusProxy {
         // Some trade-off occur here, I don't wanna add lifecycle to JanusResponse.
         // TODO: prevent memory copy as soon as possible: verify `transaction` length.
         let response_transaction = transaction.clone();
+        let verb = metrics::request_label(&message_text);
+        metrics::REQUESTS.with_label_values(&[verb]).inc();
         let response_error = |e: JanusError| {
+            debug!(code = e.code, reason = %e.reason, "Request failed");
+            metrics::REQUEST_ERRORS.with_label_values(&[verb, &e.code.to_string()]).inc();
             JanusResponse::new("error", session_id, response_transaction).with_err(e)
         };
 
+        // Backend requests made meanwhile are nested, see `JanusGateway::send`
+        let span = info_span!("request", janus = %message_text, transaction = %transaction, session_id, handle_id);
+
         let response = async {
-            if !*session.initialized.read().await {
+            // Like janus-gateway, only `ping` and `info` are allowed without a secret or token
+            let token = match &message_text[..] {
+                "ping" | "info" => None,
+                _ => self.authorize(apisecret.as_deref(), &rest).await?
+            };
+
+            if session_id == 0 && handle_id == 0 {
                 let response = match &message_text[..] {
                     "ping" => JanusResponse::new("pong", 0, transaction),
-                    "info" => JanusResponse::new("server_info", 0, transaction).with_data(json!({})), // TODO: response server info
+                    "info" => JanusResponse::new("server_info", 0, transaction).with_fields(self.server_info().await),
                     "create" => {
-                        *session.initialized.write().await = true;
-                        let json = json!({ "id": session.id });
+                        if !self.accepting_sessions.load(Ordering::SeqCst) {
+                            return Err(JanusError::new(JANUS_ERROR_NOT_ACCEPTING_SESSIONS, "Janus is currently not accepting new sessions".to_string()))
+                        }
+                        let id = self.state.new_session().await?;
+                        let session = transport.add_session(id).await;
+                        self.sessions.write().await.insert(id, session);
+                        metrics::SESSIONS.inc();
+                        let json = json!({ "id": id });
                         JanusResponse::new("success", 0, transaction).with_data(json)
                     }
                     x => return Err(
@@ -160,17 +480,27 @@ impl JanusProxy {
                 return Ok(response)
             }
 
-            // TODO: Currently not support multiple session per websocket connection, reasonable?
-            if session.id != session_id {
-                // return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("Invalid session")))
-                return Err(JanusError::new(JANUS_ERROR_TRANSPORT_SPECIFIC, format!("Invalid session, support only one per websocket connection")))
-            }
+            let session = match transport.get_session(session_id).await {
+                Some(x) => x,
+                // Session may be claimed from another transport, or one that's gone
+                None if message_text == "claim" => match self.sessions.read().await.get(&session_id) {
+                    Some(x) => Arc::clone(x),
+                    None => return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("No such session {}", session_id)))
+                },
+                None => return Err(JanusError::new(JANUS_ERROR_SESSION_NOT_FOUND, format!("No such session {}", session_id)))
+            };
 
             /* Both session-level and handle-level request */
+            session.touch().await;
             if message_text == "keepalive" {
                 return Ok(JanusResponse::new("ack", session_id, transaction))
             }
-            if message_text == "claim" {    //TODO: implement later
+
+            if message_text == "claim" {
+                if handle_id != 0 {
+                    return Err(JanusError::new(JANUS_ERROR_INVALID_REQUEST_PATH, "Unhandled request 'claim' at this path".to_string()))
+                }
+                transport.claim_session(session).await;
                 return Ok(JanusResponse::new("success", session_id, transaction))
             }
 
@@ -178,22 +508,28 @@ impl JanusProxy {
             return if handle_id == 0 {
                 let response = match &message_text[..] {
                     "attach" => {
-                        // TODO: verify `token`, `opaque_id`
+                        // TODO: verify `opaque_id`
                         let params: AttachParameters = json::from_object(rest)?;
-                        let id = self.state.new_handle();
+                        if let Some(token) = &token {
+                            if !self.state.check_token_plugin(token, &params.plugin).await {
+                                return Err(JanusError::new(JANUS_ERROR_UNAUTHORIZED_PLUGIN, format!("Provided token can't access plugin '{}'", params.plugin)))
+                            }
+                        }
+                        let id = self.state.new_handle().await?;
                         let plugin = self.plugins.resolve(params.plugin)?;
 
                         let session_ref = Arc::clone(&session);
                         let handle = JanusHandle::new(id, session_ref, plugin);
 
                         session.handles.write().await.insert(id, handle);
+                        metrics::HANDLES.inc();
 
                         let json = json!({ "id": id });
                         JanusResponse::new("success", session_id, transaction).with_data(json)
                     },
                     "destroy" => {
-                        // TODO: should reset session id?
-                        *session.initialized.write().await = false;
+                        transport.remove_session(session_id).await;
+                        self.remove_session(&session).await;
                         // TODO: notify event handlers. Btw, what is 'event handler'
                         JanusResponse::new("success", session_id, transaction)
                     },
@@ -215,9 +551,12 @@ impl JanusProxy {
 
                 let response = match &message_text[..] {
                     "detach" => {
-                        // TODO: clean-up?
-                        self.state.remove_handle(&handle_id);
-                        session.handles.write().await.remove(&handle_id);
+                        self.state.remove_handle(&handle_id).await;
+                        let handle = session.handles.write().await.remove(&handle_id);
+                        if let Some(handle) = handle {
+                            metrics::HANDLES.dec();
+                            handle.destroy_gateway().await;
+                        }
                         JanusResponse::new("success", session_id, transaction)
                     },
                     "message" => {
@@ -257,7 +596,7 @@ impl JanusProxy {
                         if let Some(candidate) = params.candidate {
                             candidate.validate()?;
                             let handle = Arc::clone(session.handles.read().await.get(&handle_id).unwrap());
-                            handle.trickle(candidate).await?;
+                            handle.trickle(&transaction, candidate).await?;
                         }
                         else if let Some(candidates) = params.candidates {
                             let err = candidates.iter().find_map(|x| x.validate().err());
@@ -267,7 +606,7 @@ impl JanusProxy {
 
                             let handle = Arc::clone(session.handles.read().await.get(&handle_id).unwrap());
                             for x in candidates.into_iter() {
-                                handle.trickle(x).await?
+                                handle.trickle(&transaction, x).await?
                             }
                         }
                         else {
@@ -287,6 +626,126 @@ impl JanusProxy {
             }
         };
 
-        response.await.unwrap_or_else(response_error)
+        async {
+            debug!("Request received");
+            response.await.unwrap_or_else(response_error)
+        }.instrument(span).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::sync::Arc;
+    use tokio::time::Duration;
+    use super::core::apierror::*;
+    use super::testing::{self, FakeJanus, WsClient};
+
+    #[tokio::test]
+    async fn token_is_checked_before_session_lookup() {
+        let janus = testing::proxy(&[]).await.with_token_auth(true);
+        janus.state.add_token("secret-token", vec![]).await;
+        let url = testing::listen(Arc::new(janus)).await;
+        let mut client = WsClient::connect(&url).await;
+
+        let response = client.request(json!({"janus": "ping", "transaction": "p"})).await;
+        assert_eq!(response["janus"], "pong");
+        let response = client.request(json!({"janus": "create", "transaction": "c"})).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+
+        let response = client.request(json!({"janus": "create", "token": "secret-token", "transaction": "c"})).await;
+        let session = response["data"]["id"].as_u64().unwrap();
+        for id in [session, session + 1].iter() {
+            let response = client.request(json!({"janus": "keepalive", "session_id": id, "transaction": "k"})).await;
+            assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+        }
+        let response = client.request(json!({"janus": "keepalive", "session_id": session, "token": "secret-token", "transaction": "k"})).await;
+        assert_eq!(response["janus"], "ack");
+    }
+
+    #[tokio::test]
+    async fn keepalive_requires_api_secret() {
+        let janus = testing::proxy(&[]).await.with_api_secret(Some("janusrocks".to_string()));
+        let url = testing::listen(Arc::new(janus)).await;
+        let mut client = WsClient::connect(&url).await;
+
+        let response = client.request(json!({"janus": "create", "apisecret": "janusrocks", "transaction": "c"})).await;
+        let session = response["data"]["id"].as_u64().unwrap();
+        let response = client.request(json!({"janus": "keepalive", "session_id": session, "transaction": "k"})).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+        let response = client.request(json!({"janus": "keepalive", "session_id": session, "apisecret": "wrong", "transaction": "k"})).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_UNAUTHORIZED);
+        let response = client.request(json!({"janus": "keepalive", "session_id": session, "apisecret": "janusrocks", "transaction": "k"})).await;
+        assert_eq!(response["janus"], "ack");
+    }
+
+    #[tokio::test]
+    async fn idle_sessions_time_out() {
+        let janus = testing::proxy(&[]).await.with_session_timeout(Duration::from_secs(1));
+        let url = testing::listen(Arc::new(janus)).await;
+        let mut idle = WsClient::connect(&url).await;
+        let mut active = WsClient::connect(&url).await;
+        let idle_session = idle.create().await;
+        let active_session = active.create().await;
+
+        // Watchdog checks every 2 seconds
+        for _ in 0..6 {
+            tokio::time::delay_for(Duration::from_millis(500)).await;
+            let response = active.request(json!({"janus": "keepalive", "session_id": active_session, "transaction": "k"})).await;
+            assert_eq!(response["janus"], "ack");
+        }
+
+        let event = idle.event().await.unwrap();
+        assert_eq!((&event["janus"], &event["session_id"]), (&json!("timeout"), &json!(idle_session)));
+        let response = idle.request(json!({"janus": "keepalive", "session_id": idle_session, "transaction": "k"})).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_SESSION_NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_then_tears_down() {
+        let janus = Arc::new(testing::proxy(&[]).await);
+        let url = testing::listen(Arc::clone(&janus)).await;
+        let mut client = WsClient::connect(&url).await;
+        let session = client.create().await;
+
+        let shutdown = tokio::spawn({
+            let janus = Arc::clone(&janus);
+            async move { janus.shutdown(Duration::from_secs(1), true).await }
+        });
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+
+        // Existing sessions go on while draining, new ones are refused
+        let response = client.request(json!({"janus": "create", "transaction": "c"})).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_NOT_ACCEPTING_SESSIONS);
+        let response = client.request(json!({"janus": "keepalive", "session_id": session, "transaction": "k"})).await;
+        assert_eq!(response["janus"], "ack");
+
+        // Torn down at the deadline even though the session is still there
+        tokio::time::timeout(Duration::from_secs(3), shutdown).await.unwrap().unwrap();
+        assert!(janus.sessions.read().await.is_empty());
+        assert!(client.event().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn shutdown_tears_down_concurrently_until_deadline() {
+        let backend = FakeJanus::start().await;
+        let janus = Arc::new(testing::proxy(&[&backend.url]).await);
+        let url = testing::listen(Arc::clone(&janus)).await;
+        let mut clients = Vec::new();
+        for _ in 0..3 {
+            let mut client = WsClient::connect(&url).await;
+            let session = client.create().await;
+            let handle = client.attach(session, "janus.plugin.videoroom").await;
+            client.join(session, handle).await;
+            clients.push(client);
+        }
+        backend.stall("detach");
+
+        // Each detach would time out after 5 seconds
+        let start = tokio::time::Instant::now();
+        janus.shutdown(Duration::from_millis(500), true).await;
+        assert!(start.elapsed() < Duration::from_secs(2), "{:?}", start.elapsed());
+        assert_eq!(backend.requests("detach"), 3);
+        assert!(janus.sessions.read().await.is_empty());
     }
 }
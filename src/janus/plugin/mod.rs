@@ -2,6 +2,7 @@ mod videoroom;
 mod provider;
 
 pub use self::provider::*;
+pub use self::videoroom::{VideoRoomPluginFactory, FileVideoRoomState};
 use std::sync::Arc;
 use async_trait::async_trait;
 use crate::janus::core::json::*;
@@ -28,6 +29,8 @@ pub trait JanusPlugin: Send + Sync {
     fn get_name(&self) -> &'static str;
     async fn handle_message(&self, message: JanusPluginMessage) -> JanusPluginResult;
     async fn handle_async_message(&self, message: JanusPluginMessage) -> Option<JanusPluginResult>;
+    /** janus-gateway handle is gone (i.e. "hangup"), forget whatever has been set up on it */
+    async fn reset(&self) {}
     // fn set_opaque_id(&mut self, opaque_id: &str);
 }
 
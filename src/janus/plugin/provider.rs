@@ -29,11 +29,30 @@ impl JanusPluginProvider {
         provider.add(String::from("janus.plugin.videoroom"), Box::new(VideoRoomPluginFactory::new()))
     }
 
+    /** Factory of a plugin shipped with the proxy, by name */
+    pub fn builtin(name: &str) -> Option<Box<dyn JanusPluginFactory>> {
+        match name {
+            "janus.plugin.videoroom" => Some(Box::new(VideoRoomPluginFactory::new())),
+            _ => None
+        }
+    }
+
     pub fn add(mut self, name: String, factory: Box<dyn JanusPluginFactory>) -> JanusPluginProvider {
         self.plugins.insert(name, factory);
         self
     }
 
+    /** Names of registered plugins */
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self.plugins.keys().cloned().collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.plugins.contains_key(name)
+    }
+
     /** Resolve plugin by name */
     pub fn resolve(&self, name: String) -> Result<BoxedPlugin, JanusError> {
         let factory = match self.plugins.get(&name) {
@@ -47,6 +47,10 @@ impl VideoroomError {
     pub fn new(code: u32, reason: String) -> VideoroomError {
         VideoroomError { code, reason }
     }
+
+    pub fn code(&self) -> u32 {
+        self.code
+    }
 }
 
 impl From<serde_json::Error> for VideoroomError {
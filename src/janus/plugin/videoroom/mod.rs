@@ -10,21 +10,27 @@ mod provider;
 mod constant;
 mod response;
 
+use std::path::Path;
 use std::sync::Arc;
 use serde::Serialize;
 use serde::de::DeserializeOwned;
 use serde_json::json;
 use async_trait::async_trait;
 use tokio::sync::RwLock;
+use tracing::warn;
 use self::constant::*;
 use self::error::*;
-use self::request::{CreateParameters, JoinParameters, ExistsParameters};
+use self::request::{CreateParameters, EditParameters, DestroyParameters, JoinParameters, ExistsParameters};
+use self::request_mixin::{Identity, RoomParameters};
 use self::response::VideoroomResponse;
 use self::provider::{VideoRoomStateProvider, MemoryVideoRoomState};
+pub use self::provider::FileVideoRoomState;
 use super::{JanusPluginFactory, BoxedPlugin};
 use crate::janus::plugin::{JanusPlugin, JanusPluginResult, JanusPluginMessage};
 use crate::janus::core::json::*;
 use crate::janus::core::JanusHandle;
+use crate::janus::provider::IdGenerator;
+use crate::janus::metrics::{self, GaugeGuard};
 
 pub struct VideoRoomPluginFactory {
     provider: Arc<Box<dyn VideoRoomStateProvider>>
@@ -36,6 +42,23 @@ impl VideoRoomPluginFactory {
             provider: Arc::new(Box::new(MemoryVideoRoomState::new()))
         }
     }
+
+    /** Room ids from `ids`, permanent rooms kept in `rooms_file` if any: rooms already there are available right away */
+    pub fn open(rooms_file: Option<&Path>, ids: Box<dyn IdGenerator>) -> Result<VideoRoomPluginFactory, String> {
+        let provider: Box<dyn VideoRoomStateProvider> = match rooms_file {
+            Some(path) => Box::new(FileVideoRoomState::open(path)?.with_id_generator(ids)),
+            None => Box::new(MemoryVideoRoomState::new().with_id_generator(ids))
+        };
+        Ok(VideoRoomPluginFactory {
+            provider: Arc::new(provider)
+        })
+    }
+}
+
+impl Default for VideoRoomPluginFactory {
+    fn default() -> VideoRoomPluginFactory {
+        Self::new()
+    }
 }
 
 impl JanusPluginFactory for VideoRoomPluginFactory {
@@ -47,16 +70,23 @@ impl JanusPluginFactory for VideoRoomPluginFactory {
 
 struct VideoRoomSession {
     participant_type: u8,
-    // participant: Option<?>
+    /** Counted in `janus_proxy_videoroom_participants` while the handle lives */
+    participant: Option<GaugeGuard>,
     // gateway: Websocket connection to janus-gateway
 }
 
 impl VideoRoomSession {
     pub fn new() -> VideoRoomSession {
         VideoRoomSession {
-            participant_type: JANUS_VIDEOROOM_P_TYPE_NONE
+            participant_type: JANUS_VIDEOROOM_P_TYPE_NONE,
+            participant: None
         }
     }
+
+    fn set_participant_type(&mut self, participant_type: u8, label: &str) {
+        self.participant_type = participant_type;
+        self.participant = Some(GaugeGuard::new(metrics::VIDEOROOM_PARTICIPANTS.with_label_values(&[label])));
+    }
 }
 
 
@@ -87,6 +117,11 @@ impl JanusPlugin for VideoRoomPlugin {
         }
     }
 
+    /** Participant is gone with janus-gateway handle, it may join again */
+    async fn reset(&self) {
+        *self.session.write().await = VideoRoomSession::new();
+    }
+
     async fn handle_async_message(&self, message: JanusPluginMessage) -> Option<JanusPluginResult> {
         match self.process_message_async(message).await {
             Ok(x) => Some(x),
@@ -104,8 +139,8 @@ impl VideoRoomPlugin {
 
         match request_text {
             "create" => self.create_room(serde_json::from_value(message.body)?),
-            // "edit" => (),
-            // "destroy" => (),
+            "edit" => self.edit_room(&message).await,
+            "destroy" => self.destroy_room(&message).await,
             "list" => {
                 let rooms = self.state.list_rooms().into_iter()
                     .map(|x| json!({ "room": x }))
@@ -144,14 +179,17 @@ impl VideoRoomPlugin {
         }
     }
 
-    async fn gateway_forward(handle: &Arc<JanusHandle>, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<JanusPluginResult, VideoroomError> {
-        let (res, jsep) = handle.forward_message(body, jsep, is_async).await?;
+    async fn gateway_forward(handle: &Arc<JanusHandle>, transaction: &str, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<JanusPluginResult, VideoroomError> {
+        let (res, jsep) = handle.forward_message(transaction, body, jsep, is_async).await?;
         Ok(JanusPluginResult::ok(res).with_jsep(jsep))
     }
 
-    async fn gateway_request<T: DeserializeOwned + Serialize>(handle: &Arc<JanusHandle>, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<(VideoroomResponse<T>, Option<JSON_ANY>), VideoroomError>{
-        let (response, jsep) = handle.forward_message(body, jsep, is_async).await?;
+    async fn gateway_request<T: DeserializeOwned + Serialize>(handle: &Arc<JanusHandle>, transaction: &str, body: JSON_ANY, jsep: Option<JSON_ANY>, is_async: bool) -> Result<(VideoroomResponse<T>, Option<JSON_ANY>), VideoroomError>{
+        let (response, jsep) = handle.forward_message(transaction, body, jsep, is_async).await?;
+        Ok((Self::parse_response(response)?, jsep))
+    }
 
+    fn parse_response<T: DeserializeOwned + Serialize>(response: JSON_ANY) -> Result<VideoroomResponse<T>, VideoroomError> {
         // Parse with JSON_ANY to check "error" first (T may have required field)
         let response: VideoroomResponse = match serde_json::from_value(response) {
             Ok(x) => x,
@@ -171,13 +209,11 @@ impl VideoRoomPlugin {
             )
         };
 
-        let response = VideoroomResponse {
+        Ok(VideoroomResponse {
             videoroom: response.videoroom,
             error: None,
             data
-        };
-
-        Ok((response, jsep))
+        })
     }
 
     async fn process_message_async(&self, message: JanusPluginMessage) -> Result<JanusPluginResult, VideoroomError> {
@@ -199,26 +235,38 @@ impl VideoRoomPlugin {
                     // TODO: set display name
                     // TODO: set user id (or random?)
                     let handle = message.handle;
-
-                    // Create room. TODO: check created.
-                    let room_params = self.state.get_room_parameters(&params.room);
-                    Self::gateway_request::<JSON_ANY>(&handle, serde_json::from_str(&room_params)?, None, false).await?;
+                    let room_params = match self.state.get_room_parameters(&params.room) {
+                        Some(x) => x,
+                        None => return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_NO_SUCH_ROOM, format!("No such room ({})", params.room)))
+                    };
+                    self.join_room_backend(&handle, &params.room).await?;
+
+                    // Create room, the first publisher on its backend does it
+                    match Self::gateway_request::<JSON_ANY>(&handle, &message.transaction, serde_json::from_str(&room_params)?, None, false).await {
+                        Err(e) if e.code() != JANUS_VIDEOROOM_ERROR_ROOM_EXISTS => return Err(e),
+                        _ => ()
+                    }
 
                     // Actually join
                     let params = serde_json::to_value(params)?;
-                    let (response, jsep) = Self::gateway_request::<JSON_ANY>(&handle, params, None, true).await?;
+                    let (response, jsep) = Self::gateway_request::<JSON_ANY>(&handle, &message.transaction, params, None, true).await?;
 
-                    self.session.write().await.participant_type = JANUS_VIDEOROOM_P_TYPE_PUBLISHER;
+                    self.session.write().await.set_participant_type(JANUS_VIDEOROOM_P_TYPE_PUBLISHER, "publisher");
 
                     // TODO: return list of available publishers
                     Ok(JanusPluginResult::ok(serde_json::to_value(response)?).with_jsep(jsep))
                 },
                 // "listener" is deprecated
                 "subscriber" | "listener" => {
+                    if !self.state.has_room(&params.room) {
+                        return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_NO_SUCH_ROOM, format!("No such room ({})", params.room)))
+                    }
+                    self.join_room_backend(&message.handle, &params.room).await?;
+
                     let params = serde_json::to_value(params)?;
-                    let (response, jsep) = Self::gateway_request::<JSON_ANY>(&message.handle, params, None, true).await?;
+                    let (response, jsep) = Self::gateway_request::<JSON_ANY>(&message.handle, &message.transaction, params, None, true).await?;
 
-                    self.session.write().await.participant_type = JANUS_VIDEOROOM_P_TYPE_SUBSCRIBER;
+                    self.session.write().await.set_participant_type(JANUS_VIDEOROOM_P_TYPE_SUBSCRIBER, "subscriber");
 
                     Ok(JanusPluginResult::ok(serde_json::to_value(response)?).with_jsep(jsep))
                 },
@@ -233,13 +281,13 @@ impl VideoRoomPlugin {
                     Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_ALREADY_JOINED, String::from("Already in as a publisher on this handle")))
                 }
                 "configure" | "publish" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "unpublish" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "leave" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 _ => {
                     Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_INVALID_REQUEST, format!("Unknown request '{}'", request_text)))
@@ -252,19 +300,19 @@ impl VideoRoomPlugin {
                     Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_ALREADY_JOINED, String::from("Already in as a subscriber on this handle")))
                 },
                 "start" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "configure" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "pause" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "switch" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 "leave" => {
-                    Self::gateway_forward(&message.handle, message.body, message.jsep, true).await
+                    Self::gateway_forward(&message.handle, &message.transaction, message.body, message.jsep, true).await
                 },
                 _ => {
                     Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_INVALID_REQUEST, format!("Unknown request '{}'", request_text)))
@@ -274,6 +322,32 @@ impl VideoRoomPlugin {
         Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, String::from("Unexpected server error, plugin state malformed")))
     }
 
+    /** Bind `handle` to the janus-gateway hosting `room`, pin the room to a backend on first use */
+    async fn join_room_backend(&self, handle: &Arc<JanusHandle>, room: &Identity) -> Result<(), VideoroomError> {
+        let url = loop {
+            match self.state.get_room_backend(room) {
+                Some(url) if handle.is_backend_up(&url).await => break url,
+                Some(url) => {
+                    // Participants left on it are lost anyway, move the room to a healthy one
+                    warn!(room = %room, url = %url, "Room backend is down, pinning another one");
+                    self.state.unpin_room_backend(room, &url);
+                },
+                None => {
+                    let url = match handle.select_backend().await {
+                        Some(x) => x,
+                        None => return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, String::from("No janus-gateway instance available")))
+                    };
+                    // Another handle may have pinned it meanwhile
+                    break self.state.pin_room_backend(room, url)
+                }
+            }
+        };
+
+        // Core error codes don't belong in a plugin response
+        handle.pin_backend(&url).await
+            .map_err(|e| VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, e.reason))
+    }
+
     /** This function only validate and store the room for later creation */
     fn create_room(&self, mut params: CreateParameters) -> Result<JanusPluginResult, VideoroomError>{
         if let Some(audiocodec) = &params.audiocodec {
@@ -291,30 +365,208 @@ impl VideoRoomPlugin {
             }
         }
 
-        // TODO: permanent check, for now, ignore it
-        params.permanent = None;
+        // Saved by the state provider, janus-gateway instances only get the room when it's used
+        let permanent = params.permanent.take().unwrap_or(false);
 
         // TODO: support string id, for now, only integer are supported
         let room = match params.room {
             Some(room) => {
-                if self.state.has_room(&room) {
+                if !self.state.reserve_room(&room) {
                     return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_ROOM_EXISTS, format!("Room {} already exists", room)))
                 }
                 room
             },
-            None => self.state.new_room_id()
+            None => self.state.new_room_id().map_err(|e| VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, e))?
         };
-        params.room = Some(room);
+        params.room = Some(room.clone());
 
         let result = JanusPluginResult::ok(json!({
             "videoroom": "created",
             "room": room,
-            "permanent": params.permanent.is_some()
+            "permanent": permanent
         }));
 
         // TODO: store params to send to backend later
-        self.state.save_room_parameters(params);
+        if let Err(e) = self.state.save_room_parameters(params, permanent) {
+            // Not created after all, release its id
+            let _ = self.state.remove_room(&room, false);
+            return Err(Self::save_error(e))
+        }
 
         Ok(result)
     }
+
+    /** A permanent change couldn't be saved, see `VideoRoomStateProvider` */
+    fn save_error(reason: String) -> VideoroomError {
+        VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, format!("Error saving room: {}", reason))
+    }
+
+    /** Stored parameters of `room`, if `secret` allows modifying it */
+    fn access_room(&self, room: &Identity, secret: &Option<String>) -> Result<CreateParameters, VideoroomError> {
+        let params: CreateParameters = match self.state.get_room_parameters(room) {
+            Some(x) => serde_json::from_str(&x)?,
+            None => return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_NO_SUCH_ROOM, format!("No such room ({})", room)))
+        };
+        if params.secret.is_some() && params.secret != *secret {
+            return Err(VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNAUTHORIZED, String::from("Unauthorized (wrong secret)")))
+        }
+        Ok(params)
+    }
+
+    /**
+     * Apply a synchronous request to `room` on its backend too, if it's been created there already.
+     * It's sent apart from `handle`, which may be bound to another backend or join another room later.
+     */
+    async fn forward_room_request(handle: &Arc<JanusHandle>, transaction: &str, url: Option<String>, mut body: JSON_ANY) -> Result<(), VideoroomError> {
+        let url = match url {
+            Some(x) if handle.is_backend_up(&x).await => x,
+            _ => return Ok(())
+        };
+
+        // Persisted by the proxy, not by janus-gateway
+        if let Some(x) = body.as_object_mut() {
+            x.remove("permanent");
+        }
+        // Core error codes don't belong in a plugin response
+        let (response, _) = handle.forward_oneshot(&url, transaction, body).await
+            .map_err(|e| VideoroomError::new(JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR, e.reason))?;
+        match Self::parse_response::<JSON_ANY>(response) {
+            Err(e) if e.code() != JANUS_VIDEOROOM_ERROR_NO_SUCH_ROOM => Err(e),
+            _ => Ok(())
+        }
+    }
+
+    async fn edit_room(&self, message: &JanusPluginMessage) -> Result<JanusPluginResult, VideoroomError> {
+        let body = message.body.clone();
+        let room = serde_json::from_value::<RoomParameters>(body.clone())?.room;
+        let edit: EditParameters = serde_json::from_value(body.clone())?;
+        let mut params = self.access_room(&room, &edit.secret)?;
+
+        params.description = edit.new_description.or(params.description);
+        params.is_private = edit.new_is_private.or(params.is_private);
+        params.secret = edit.new_secret.or(params.secret);
+        params.pin = edit.new_pin.or(params.pin);
+        params.require_pvtid = edit.new_require_pvtid.or(params.require_pvtid);
+        params.bitrate = edit.new_bitrate.or(params.bitrate);
+        params.fir_freq = edit.new_fir_freq.or(params.fir_freq);
+        params.publishers = edit.new_publishers.or(params.publishers);
+        params.lock_record = edit.new_lock_record.or(params.lock_record);
+
+        Self::forward_room_request(&message.handle, &message.transaction, self.state.get_room_backend(&room), body).await?;
+
+        let permanent = edit.permanent.unwrap_or(false);
+        self.state.save_room_parameters(params, permanent).map_err(Self::save_error)?;
+
+        Ok(JanusPluginResult::ok(json!({
+            "videoroom": "edited",
+            "room": room,
+            "permanent": permanent
+        })))
+    }
+
+    async fn destroy_room(&self, message: &JanusPluginMessage) -> Result<JanusPluginResult, VideoroomError> {
+        let body = message.body.clone();
+        let room = serde_json::from_value::<RoomParameters>(body.clone())?.room;
+        let destroy: DestroyParameters = serde_json::from_value(body.clone())?;
+        self.access_room(&room, &destroy.secret)?;
+
+        // Participants are notified by janus-gateway
+        Self::forward_room_request(&message.handle, &message.transaction, self.state.get_room_backend(&room), body).await?;
+
+        let permanent = destroy.permanent.unwrap_or(false);
+        self.state.remove_room(&room, permanent).map_err(Self::save_error)?;
+
+        Ok(JanusPluginResult::ok(json!({
+            "videoroom": "destroyed",
+            "room": room,
+            "permanent": permanent
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use std::sync::Arc;
+    use crate::janus::testing::{self, FakeJanus, WsClient};
+
+    fn message(session: u64, handle: u64, body: Value) -> Value {
+        json!({"janus": "message", "session_id": session, "handle_id": handle, "transaction": format!("m{}", handle), "body": body})
+    }
+
+    #[tokio::test]
+    async fn participants_of_a_room_share_its_backend() {
+        let a = FakeJanus::start().await;
+        let b = FakeJanus::start().await;
+        // Round robin would spread handles otherwise
+        let url = testing::listen(Arc::new(testing::proxy(&[&a.url, &b.url]).await)).await;
+        let mut client = WsClient::connect(&url).await;
+        let session = client.create().await;
+        let first = client.attach(session, "janus.plugin.videoroom").await;
+        let second = client.attach(session, "janus.plugin.videoroom").await;
+
+        let response = client.request(message(session, first, json!({"request": "create"}))).await;
+        let room = response["plugindata"]["data"]["room"].clone();
+        assert!(room.is_u64());
+
+        for (handle, ptype) in [(first, "publisher"), (second, "subscriber")].iter() {
+            let response = client.request(message(session, *handle, json!({"request": "join", "room": room, "ptype": ptype}))).await;
+            assert_eq!(response["janus"], "ack");
+            let event = client.event().await.unwrap();
+            assert_eq!((&event["sender"], &event["plugindata"]["data"]["videoroom"]), (&json!(handle), &json!("joined")));
+        }
+
+        let attached = (a.requests("attach"), b.requests("attach"));
+        assert!(attached == (2, 0) || attached == (0, 2), "{:?}", attached);
+    }
+
+    #[tokio::test]
+    async fn no_backend_is_a_videoroom_error() {
+        let url = testing::listen(Arc::new(testing::proxy(&[]).await)).await;
+        let mut client = WsClient::connect(&url).await;
+        let session = client.create().await;
+        let handle = client.attach(session, "janus.plugin.videoroom").await;
+
+        let response = client.request(message(session, handle, json!({"request": "create"}))).await;
+        let room = response["plugindata"]["data"]["room"].clone();
+        client.request(message(session, handle, json!({"request": "join", "room": room, "ptype": "publisher"}))).await;
+        let event = client.event().await.unwrap();
+        assert_eq!(event["plugindata"]["data"]["error_code"], super::JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_managed_apart_from_the_handle_backend() {
+        let a = FakeJanus::start().await;
+        let b = FakeJanus::start().await;
+        let url = testing::listen(Arc::new(testing::proxy(&[&a.url, &b.url]).await)).await;
+        let mut client = WsClient::connect(&url).await;
+        let session = client.create().await;
+        let first = client.attach(session, "janus.plugin.videoroom").await;
+        let second = client.attach(session, "janus.plugin.videoroom").await;
+
+        let mut room = json!(null);
+        let mut on_a = Vec::new();
+        for handle in [first, second].iter() {
+            let response = client.request(message(session, *handle, json!({"request": "create"}))).await;
+            room = response["plugindata"]["data"]["room"].clone();
+            client.request(message(session, *handle, json!({"request": "join", "room": room, "ptype": "publisher"}))).await;
+            assert_eq!(client.event().await.unwrap()["plugindata"]["data"]["videoroom"], "joined");
+            on_a.push(a.requests("attach"));
+        }
+        // Round robin: each room on its own backend
+        assert_eq!((a.requests("attach"), b.requests("attach")), (1, 1));
+        let backend = if on_a == vec![0, 1] { &a } else { &b };
+
+        // Sent by the first handle, bound to the other backend
+        backend.fail("create", 1);
+        let response = client.request(message(session, first, json!({"request": "edit", "room": room, "new_description": "x"}))).await;
+        assert_eq!(response["plugindata"]["data"]["error_code"], super::JANUS_VIDEOROOM_ERROR_UNKNOWN_ERROR);
+
+        let response = client.request(message(session, first, json!({"request": "destroy", "room": room}))).await;
+        assert_eq!(response["plugindata"]["data"]["videoroom"], "destroyed");
+        assert_eq!(backend.requests("attach"), 2);
+        assert_eq!(backend.requests("detach"), 1);
+        // Only the second handle's session is left
+        assert_eq!(backend.sessions(), 1);
+    }
 }
@@ -1,61 +1,386 @@
 use std::collections::{HashSet, HashMap};
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
-use crate::janus::helper;
+use tracing::error;
+use crate::janus::metrics;
+use crate::janus::provider::{IdGenerator, RandomIdGenerator, MAX_ATTEMPTS};
 use super::request::CreateParameters;
+use super::request_mixin::Identity;
 
+/**
+ * Rooms of the VideoRoom plugin and the janus-gateway instance each one is pinned to, kept by each proxy instance:
+ * unlike `ProxyStateProvider`, nothing is shared, so a room is only known to the instance it's been created on.
+ */
 pub trait VideoRoomStateProvider: Send + Sync {
-    fn new_room_id(&self) -> u64;
-    fn has_room(&self, id: &u64) -> bool;
+    /** Reserve a new room id (see `reserve_room`), fail after `MAX_ATTEMPTS` taken ones */
+    fn new_room_id(&self) -> Result<Identity, String>;
+    /** Reserve `id` for a room about to be saved, false if it's taken already */
+    fn reserve_room(&self, id: &Identity) -> bool;
+    fn has_room(&self, id: &Identity) -> bool;
 
-    fn list_rooms(&self) -> Vec<u64>;
-    fn save_room_parameters(&self, room: CreateParameters);
-    fn get_room_parameters(&self, room: &u64) -> String;
+    fn list_rooms(&self) -> Vec<Identity>;
+    /** Create or replace `room`, `permanent` rooms are kept across restarts: fail if it can't be saved */
+    fn save_room_parameters(&self, room: CreateParameters, permanent: bool) -> Result<(), String>;
+    fn get_room_parameters(&self, room: &Identity) -> Option<String>;
+    /** Destroy `room`, a permanent room comes back on restart unless `permanent`: fail if that can't be saved */
+    fn remove_room(&self, room: &Identity, permanent: bool) -> Result<(), String>;
+
+    /** janus-gateway instance hosting `room`, if already pinned */
+    fn get_room_backend(&self, room: &Identity) -> Option<String>;
+    /** Pin `room` to `url` unless already pinned, return the backend it's pinned to */
+    fn pin_room_backend(&self, room: &Identity, url: String) -> String;
+    /** Forget the backend of `room`, only if it's still pinned to `url` */
+    fn unpin_room_backend(&self, room: &Identity, url: &str);
 }
 
 pub struct MemoryVideoRoomState {
-    rooms: Mutex<HashSet<u64>>,
-    params: Mutex<HashMap<u64, String>>
+    ids: Box<dyn IdGenerator>,
+    /** Counted in `janus_proxy_videoroom_rooms` while this provider lives */
+    rooms: Mutex<HashSet<Identity>>,
+    params: Mutex<HashMap<Identity, String>>,
+    backends: Mutex<HashMap<Identity, String>>
 }
 
 impl MemoryVideoRoomState {
     pub fn new() -> MemoryVideoRoomState {
         MemoryVideoRoomState {
+            ids: Box::new(RandomIdGenerator),
             rooms: Mutex::new(HashSet::new()),
-            params: Mutex::new(HashMap::new())
+            params: Mutex::new(HashMap::new()),
+            backends: Mutex::new(HashMap::new())
         }
     }
+
+    /** Source of ids of rooms created without one, random by default */
+    pub fn with_id_generator(mut self, ids: Box<dyn IdGenerator>) -> MemoryVideoRoomState {
+        self.ids = ids;
+        self
+    }
 }
 
 impl VideoRoomStateProvider for MemoryVideoRoomState {
-    fn new_room_id(&self) -> u64 {
-        loop {
-            let id = helper::rand_id();
-            let mut rooms = self.rooms.lock().unwrap();
-            if rooms.insert(id) {
-                return id
+    fn new_room_id(&self) -> Result<Identity, String> {
+        let mut rooms = self.rooms.lock().unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            let id = Identity::from(self.ids.next_id());
+            if rooms.insert(id.clone()) {
+                metrics::VIDEOROOM_ROOMS.inc();
+                return Ok(id)
             }
         }
+        Err("Could not allocate a unique room id".to_string())
+    }
+
+    fn reserve_room(&self, id: &Identity) -> bool {
+        let reserved = self.rooms.lock().unwrap().insert(id.clone());
+        if reserved {
+            metrics::VIDEOROOM_ROOMS.inc();
+        }
+        reserved
     }
 
-    fn has_room(&self, id: &u64) -> bool {
+    fn has_room(&self, id: &Identity) -> bool {
         self.rooms.lock().unwrap().contains(id)
     }
 
-    fn list_rooms(&self) -> Vec<u64> {
-        self.rooms.lock().unwrap().iter().map(|x| *x).collect()
+    fn list_rooms(&self) -> Vec<Identity> {
+        self.rooms.lock().unwrap().iter().cloned().collect()
     }
 
-    fn save_room_parameters(&self, room: CreateParameters) {
-        // TODO: json stringify error handling
+    fn save_room_parameters(&self, room: CreateParameters, _permanent: bool) -> Result<(), String> {
         // TODO: more efficient storing method
-        self.params.lock().unwrap().insert(room.room.unwrap(), serde_json::to_string(&room).unwrap());
+        let json = serde_json::to_string(&room).map_err(|e| e.to_string())?;
+        let id = room.room.unwrap();
+        if self.rooms.lock().unwrap().insert(id.clone()) {
+            metrics::VIDEOROOM_ROOMS.inc();
+        }
+        self.params.lock().unwrap().insert(id, json);
+        Ok(())
     }
 
-    fn get_room_parameters(&self, room: &u64) -> String {
+    fn get_room_parameters(&self, room: &Identity) -> Option<String> {
         // TODO: do NOT copy
-        self.params.lock().unwrap().get(room).unwrap().clone()
+        self.params.lock().unwrap().get(room).cloned()
+    }
+
+    fn remove_room(&self, room: &Identity, _permanent: bool) -> Result<(), String> {
+        if self.rooms.lock().unwrap().remove(room) {
+            metrics::VIDEOROOM_ROOMS.dec();
+        }
+        self.params.lock().unwrap().remove(room);
+        self.backends.lock().unwrap().remove(room);
+        Ok(())
+    }
+
+    fn get_room_backend(&self, room: &Identity) -> Option<String> {
+        self.backends.lock().unwrap().get(room).cloned()
+    }
+
+    fn pin_room_backend(&self, room: &Identity, url: String) -> String {
+        self.backends.lock().unwrap().entry(room.clone()).or_insert(url).clone()
+    }
+
+    fn unpin_room_backend(&self, room: &Identity, url: &str) {
+        let mut backends = self.backends.lock().unwrap();
+        if backends.get(room).map(String::as_str) == Some(url) {
+            backends.remove(room);
+        }
+    }
+}
+
+impl Drop for MemoryVideoRoomState {
+    fn drop(&mut self) {
+        metrics::VIDEOROOM_ROOMS.sub(self.rooms.lock().unwrap().len() as i64);
+    }
+}
+
+/**
+ * Permanent rooms are written to a JSON file, loaded again on startup.
+ * Other rooms, and backends rooms are pinned to, are kept in memory only.
+ */
+pub struct FileVideoRoomState {
+    memory: MemoryVideoRoomState,
+    path: PathBuf,
+    /** Content of the file, by room */
+    permanent: Mutex<HashMap<Identity, CreateParameters>>
+}
+
+impl FileVideoRoomState {
+    /** Load rooms saved in `path`, the file is created on first permanent room */
+    pub fn open(path: &Path) -> Result<FileVideoRoomState, String> {
+        let rooms = match std::fs::read_to_string(path) {
+            Ok(text) => match serde_json::from_str::<Vec<CreateParameters>>(&text) {
+                Ok(x) => x,
+                Err(e) => return Err(format!("Invalid rooms file \"{}\": {}", path.display(), e))
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(format!("Could not read \"{}\": {}", path.display(), e))
+        };
+
+        let memory = MemoryVideoRoomState::new();
+        let mut permanent = HashMap::new();
+        for room in rooms.into_iter() {
+            let id = match &room.room {
+                Some(x) => x.clone(),
+                None => return Err(format!("Invalid rooms file \"{}\": room without id", path.display()))
+            };
+            memory.save_room_parameters(room.clone(), true)?;
+            permanent.insert(id, room);
+        }
+
+        Ok(FileVideoRoomState {
+            memory,
+            path: path.to_path_buf(),
+            permanent: Mutex::new(permanent)
+        })
+    }
+
+    /** See `MemoryVideoRoomState::with_id_generator` */
+    pub fn with_id_generator(mut self, ids: Box<dyn IdGenerator>) -> FileVideoRoomState {
+        self.memory = self.memory.with_id_generator(ids);
+        self
+    }
+
+    /** Replace the file as a whole, through a temporary one so that it's never half written */
+    fn write(&self, rooms: &HashMap<Identity, CreateParameters>) -> Result<(), String> {
+        let json = match serde_json::to_string_pretty(&rooms.values().collect::<Vec<_>>()) {
+            Ok(x) => x,
+            Err(e) => return Err(format!("Could not serialize permanent rooms: {}", e))
+        };
+        let tmp = self.path.with_extension("tmp");
+        if let Err(e) = std::fs::write(&tmp, json).and_then(|_| std::fs::rename(&tmp, &self.path)) {
+            error!(path = %self.path.display(), reason = %e, "Could not save permanent rooms");
+            return Err(format!("Could not save permanent rooms: {}", e))
+        }
+        Ok(())
+    }
+}
+
+impl VideoRoomStateProvider for FileVideoRoomState {
+    fn new_room_id(&self) -> Result<Identity, String> {
+        self.memory.new_room_id()
+    }
+
+    fn reserve_room(&self, id: &Identity) -> bool {
+        self.memory.reserve_room(id)
+    }
+
+    fn has_room(&self, id: &Identity) -> bool {
+        self.memory.has_room(id)
+    }
+
+    fn list_rooms(&self) -> Vec<Identity> {
+        self.memory.list_rooms()
+    }
+
+    fn save_room_parameters(&self, room: CreateParameters, permanent: bool) -> Result<(), String> {
+        if permanent {
+            // Keep the lock while writing, so that the file follows the order of changes
+            let mut rooms = self.permanent.lock().unwrap();
+            let id = room.room.clone().unwrap();
+            let previous = rooms.insert(id.clone(), room.clone());
+            if let Err(e) = self.write(&rooms) {
+                // As it is in the file
+                match previous {
+                    Some(x) => rooms.insert(id, x),
+                    None => rooms.remove(&id)
+                };
+                return Err(e)
+            }
+        }
+        self.memory.save_room_parameters(room, permanent)
+    }
+
+    fn get_room_parameters(&self, room: &Identity) -> Option<String> {
+        self.memory.get_room_parameters(room)
+    }
+
+    fn remove_room(&self, room: &Identity, permanent: bool) -> Result<(), String> {
+        if permanent {
+            let mut rooms = self.permanent.lock().unwrap();
+            if let Some(previous) = rooms.remove(room) {
+                if let Err(e) = self.write(&rooms) {
+                    rooms.insert(room.clone(), previous);
+                    return Err(e)
+                }
+            }
+        }
+        self.memory.remove_room(room, permanent)
+    }
+
+    fn get_room_backend(&self, room: &Identity) -> Option<String> {
+        self.memory.get_room_backend(room)
+    }
+
+    fn pin_room_backend(&self, room: &Identity, url: String) -> String {
+        self.memory.pin_room_backend(room, url)
+    }
+
+    fn unpin_room_backend(&self, room: &Identity, url: &str) {
+        self.memory.unpin_room_backend(room, url)
     }
 }
 
 // TODO: Redis implementation
 pub struct _RedisVideoRoomState;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::janus::provider::ScriptedIdGenerator;
+
+    /** Temporary rooms file, removed once dropped */
+    struct RoomsFile(PathBuf);
+
+    impl Drop for RoomsFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn rooms_file() -> RoomsFile {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "janus-proxy-rooms-{}-{}.json", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = std::fs::remove_file(&path);
+        RoomsFile(path)
+    }
+
+    fn room(id: u64, description: &str) -> CreateParameters {
+        serde_json::from_value(json!({"request": "create", "room": id, "description": description})).unwrap()
+    }
+
+    fn description(state: &FileVideoRoomState, id: u64) -> Option<String> {
+        let params = state.get_room_parameters(&Identity::from(id))?;
+        serde_json::from_str::<CreateParameters>(&params).unwrap().description
+    }
+
+    #[test]
+    fn permanent_rooms_are_reloaded() {
+        let file = rooms_file();
+        let path = &file.0;
+        let state = FileVideoRoomState::open(path).unwrap();
+        assert!(state.list_rooms().is_empty());
+        // Nothing to save yet
+        state.save_room_parameters(room(1, "temporary"), false).unwrap();
+        assert!(!path.exists());
+
+        state.save_room_parameters(room(2, "permanent"), true).unwrap();
+        state.save_room_parameters(room(3, "permanent too"), true).unwrap();
+        assert_eq!(state.list_rooms().len(), 3);
+
+        let state = FileVideoRoomState::open(path).unwrap();
+        let mut rooms = state.list_rooms();
+        rooms.sort_by_key(|x| x.to_string());
+        assert_eq!(rooms, vec![Identity::from(2), Identity::from(3)]);
+        assert_eq!(description(&state, 2).as_deref(), Some("permanent"));
+        assert_eq!(description(&state, 3).as_deref(), Some("permanent too"));
+    }
+
+    #[test]
+    fn permanent_changes_are_saved() {
+        let file = rooms_file();
+        let path = &file.0;
+        let state = FileVideoRoomState::open(path).unwrap();
+        for id in 1..4 {
+            state.save_room_parameters(room(id, "created"), true).unwrap();
+        }
+
+        // Only changes made `permanent` outlive a restart
+        state.save_room_parameters(room(1, "edited"), true).unwrap();
+        state.save_room_parameters(room(2, "edited until restart"), false).unwrap();
+        state.remove_room(&Identity::from(3), false).unwrap();
+        assert!(!state.has_room(&Identity::from(3)));
+
+        let state = FileVideoRoomState::open(path).unwrap();
+        assert_eq!(description(&state, 1).as_deref(), Some("edited"));
+        assert_eq!(description(&state, 2).as_deref(), Some("created"));
+        assert!(state.has_room(&Identity::from(3)));
+
+        state.remove_room(&Identity::from(3), true).unwrap();
+        let state = FileVideoRoomState::open(path).unwrap();
+        assert!(!state.has_room(&Identity::from(3)));
+        assert_eq!(state.list_rooms().len(), 2);
+    }
+
+    #[test]
+    fn invalid_file_is_rejected() {
+        let file = rooms_file();
+        let path = &file.0;
+        std::fs::write(path, "{}").unwrap();
+        assert!(FileVideoRoomState::open(path).err().unwrap().starts_with("Invalid rooms file"));
+
+        std::fs::write(path, r#"[{"request": "create", "description": "no id"}]"#).unwrap();
+        assert!(FileVideoRoomState::open(path).err().unwrap().ends_with("room without id"));
+    }
+
+    #[test]
+    fn rooms_are_reserved_once() {
+        let state = MemoryVideoRoomState::new().with_id_generator(Box::new(ScriptedIdGenerator::new(&[1, 2])));
+        assert_eq!(state.new_room_id(), Ok(Identity::from(1)));
+        assert!(!state.reserve_room(&Identity::from(1)));
+        assert!(state.reserve_room(&Identity::from(3)));
+        assert!(!state.reserve_room(&Identity::from(3)));
+        assert!(state.reserve_room(&Identity::from(2)));
+        state.remove_room(&Identity::from(1), false).unwrap();
+        // Taken ids are skipped, up to a point
+        assert_eq!(state.new_room_id(), Ok(Identity::from(1)));
+        assert!(state.new_room_id().is_err());
+    }
+
+    #[test]
+    fn failed_saves_are_reported() {
+        let state = FileVideoRoomState::open(Path::new("/nonexistent/rooms.json")).unwrap();
+        state.save_room_parameters(room(1, "temporary"), false).unwrap();
+        assert!(state.save_room_parameters(room(2, "permanent"), true).err().unwrap().starts_with("Could not save permanent rooms"));
+        assert!(!state.has_room(&Identity::from(2)));
+
+        // Nothing changed
+        assert!(state.save_room_parameters(room(1, "edited"), true).is_err());
+        assert_eq!(description(&state, 1).as_deref(), Some("temporary"));
+        assert!(state.permanent.lock().unwrap().is_empty());
+    }
+}
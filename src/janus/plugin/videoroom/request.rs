@@ -5,7 +5,7 @@ use crate::janus::core::json::*;
 
 // mixins: RoomParameters (optional), AdminKeyParameters (if enabled)
 #[skip_serializing_none]
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct CreateParameters {
 	request: String,
 	pub room: Option<Identity>,
@@ -106,7 +106,7 @@ pub struct StopRtpForwardParameters {
 // Mixins: RoomParameters,
 #[derive(Deserialize)]
 pub struct ExistsParameters {
-	pub room: u64
+	pub room: Identity
 }
 
 // mixins: RoomParameters
@@ -1,4 +1,5 @@
-use serde::Deserialize;
+use std::fmt;
+use serde::{Serialize, Deserialize};
 use crate::janus::core::json::*;
 
 #[derive(Deserialize)]
@@ -12,8 +13,27 @@ pub struct AdminKeyParameters {
 }
 
 // Configurable string_id,...
-#[derive(Deserialize)]
-pub enum Identity { Integer, str }
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Identity {
+    Integer(JSON_POSITIVE_INTEGER),
+    String(JSON_STRING)
+}
+
+impl From<JSON_POSITIVE_INTEGER> for Identity {
+    fn from(id: JSON_POSITIVE_INTEGER) -> Self {
+        Identity::Integer(id)
+    }
+}
+
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Identity::Integer(x) => write!(f, "{}", x),
+            Identity::String(x) => write!(f, "{}", x)
+        }
+    }
+}
 
 #[derive(Deserialize)]
 pub struct RoomParameters {
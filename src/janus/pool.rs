@@ -0,0 +1,167 @@
+use futures::future::join_all;
+use tokio::time::Duration;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use tracing::{debug, debug_span, Instrument};
+use super::core::apierror::JanusError;
+use super::core::request::IncomingRequestParameters;
+use super::gateway::JanusGateway;
+use super::provider::IdGenerator;
+
+/** Backend sessions are kept alive by janus-gateway `session_timeout` (60 seconds by default) */
+static KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/**
+ * Websocket connections to janus-gateway shared by backend sessions, see `JanusGateway::subscribe`.
+ * Connections are owned by their users (e.g. `JanusHandle`), one is closed when nobody uses it anymore.
+ */
+pub(crate) struct JanusGatewayPool {
+    /** Users of a connection before another one is opened, at least 1 */
+    sessions_per_connection: usize,
+    connections: Mutex<HashMap<String, Vec<Weak<JanusGateway>>>>
+}
+
+impl JanusGatewayPool {
+    pub(crate) fn new(sessions_per_connection: usize) -> JanusGatewayPool {
+        JanusGatewayPool {
+            sessions_per_connection: sessions_per_connection.max(1),
+            connections: Mutex::new(HashMap::new())
+        }
+    }
+
+    /** Least used open connection to `url` with room for another session, if any */
+    fn find(&self, url: &str) -> Option<Arc<JanusGateway>> {
+        let mut connections = self.connections.lock().unwrap();
+        let list = connections.get_mut(url)?;
+        list.retain(|x| x.upgrade().is_some_and(|x| !x.is_closed()));
+
+        list.iter()
+            .filter_map(Weak::upgrade)
+            .map(|x| (x.subscriber_count(), x))
+            .filter(|(count, _)| *count < self.sessions_per_connection)
+            .min_by_key(|(count, _)| *count)
+            .map(|(_, x)| x)
+    }
+
+    /** A connection to `url`, opened if every one is full */
+    pub(crate) async fn get(&self, url: &str, secret: Option<String>, ids: &Arc<Box<dyn IdGenerator>>) -> Result<Arc<JanusGateway>, JanusError> {
+        if let Some(x) = self.find(url) {
+            return Ok(x)
+        }
+
+        let gateway = JanusGateway::connect(url.to_string(), secret, Arc::clone(ids)).await?;
+        Self::keepalive(&gateway);
+
+        self.connections.lock().unwrap()
+            .entry(url.to_string())
+            .or_default()
+            .push(Arc::downgrade(&gateway));
+        Ok(gateway)
+    }
+
+    /** Keep every session subscribed on `gateway` alive, one task per connection */
+    fn keepalive(gateway: &Arc<JanusGateway>) {
+        let span = debug_span!("janus_gateway", url = %gateway.url());
+        let gateway = Arc::downgrade(gateway);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(KEEPALIVE_INTERVAL).await;
+                let gateway = match gateway.upgrade() {
+                    Some(x) if !x.is_closed() => x,
+                    _ => break
+                };
+
+                let requests = gateway.sessions().into_iter().map(|session| {
+                    let mut request = IncomingRequestParameters::prepare("keepalive".to_string(), None, None);
+                    request.session_id = session;
+                    gateway.send(request, false)
+                });
+                let failed = join_all(requests).await.into_iter().filter(|x| x.is_err()).count();
+                if failed > 0 {
+                    debug!(failed, "Keepalive failed");
+                }
+            }
+            debug!("Connection to janus-gateway closed, stop ping");
+        }.instrument(span));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::janus::events::{event_queue, EventOverflowPolicy};
+    use crate::janus::provider::RandomIdGenerator;
+    use crate::janus::testing::FakeJanus;
+
+    fn ids() -> Arc<Box<dyn IdGenerator>> {
+        Arc::new(Box::new(RandomIdGenerator))
+    }
+
+    #[tokio::test]
+    async fn connections_are_shared_until_full() {
+        let backend = FakeJanus::start().await;
+        let pool = JanusGatewayPool::new(2);
+        let (tx, _rx) = event_queue(4, EventOverflowPolicy::DropOldest);
+
+        let first = pool.get(&backend.url, None, &ids()).await.unwrap();
+        first.subscribe(1, tx.clone()).unwrap();
+        let second = pool.get(&backend.url, None, &ids()).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        second.subscribe(2, tx.clone()).unwrap();
+
+        // Full, whoever else holds it
+        let third = pool.get(&backend.url, None, &ids()).await.unwrap();
+        assert!(!Arc::ptr_eq(&first, &third));
+        third.subscribe(3, tx.clone()).unwrap();
+        assert_eq!(backend.connections(), 2);
+
+        // Least used one with room
+        first.unsubscribe(1);
+        third.subscribe(4, tx.clone()).unwrap();
+        let fourth = pool.get(&backend.url, None, &ids()).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &fourth));
+
+        // Closed once nobody uses it
+        drop((first, second, fourth));
+        tokio::time::delay_for(Duration::from_millis(100)).await;
+        assert_eq!(backend.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn events_go_to_their_subscriber() {
+        let backend = FakeJanus::start().await;
+        let pool = JanusGatewayPool::new(10);
+        let gateway = pool.get(&backend.url, None, &ids()).await.unwrap();
+        let (first_tx, mut first) = event_queue(4, EventOverflowPolicy::DropOldest);
+        let (second_tx, mut second) = event_queue(4, EventOverflowPolicy::DropOldest);
+        gateway.subscribe(1, first_tx).unwrap();
+        gateway.subscribe(2, second_tx).unwrap();
+
+        backend.push(json!({"janus": "event", "session_id": 2, "sender": 20}));
+        backend.push(json!({"janus": "event", "session_id": 1, "sender": 10}));
+        backend.push(json!({"janus": "event", "session_id": 3, "sender": 30}));
+        assert_eq!(first.recv().await.unwrap().sender, 10);
+        assert_eq!(second.recv().await.unwrap().sender, 20);
+
+        // Nothing else, subscribers are dropped with the connection
+        backend.disconnect();
+        assert!(first.recv().await.is_none());
+        assert!(second.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_are_skipped() {
+        let backend = FakeJanus::start().await;
+        let pool = JanusGatewayPool::new(10);
+        let gateway = pool.get(&backend.url, None, &ids()).await.unwrap();
+        let (tx, mut rx) = event_queue(4, EventOverflowPolicy::DropOldest);
+        gateway.subscribe(1, tx).unwrap();
+
+        backend.push(json!("not a response"));
+        backend.push(json!({"janus": 1}));
+        backend.push(json!({"janus": "event", "session_id": 1, "sender": 10}));
+        assert_eq!(rx.recv().await.unwrap().sender, 10);
+        assert!(!gateway.is_closed());
+    }
+}
@@ -1,41 +1,260 @@
-use std::collections::BTreeSet;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Mutex;
+use async_trait::async_trait;
+use tracing::error;
+use super::strategy::{BackendStrategy, BackendLoad, RoundRobinStrategy};
+use super::redis_client::RedisClient;
 
 // Status: preview. TODO: refine the apis
+#[async_trait]
 pub trait JanusBackendProvider: Send + Sync {
-    fn update_backend(&self, url: String, up: bool);
-    fn get_backend(&self) -> Option<String>;
+    async fn update_backend(&self, url: String, up: bool);
+    /** A healthy backend for a new session, chosen by the configured strategy */
+    async fn get_backend(&self) -> Option<String>;
+    /** All healthy backends */
+    async fn list_backends(&self) -> Vec<String>;
+    /** A session has been created on backend `url` */
+    async fn session_opened(&self, url: &str);
+    /** A session created on backend `url` has been destroyed */
+    async fn session_closed(&self, url: &str);
+}
+
+struct MemoryBackend {
+    up: bool,
+    weight: u32,
+    sessions: u64
+}
+
+impl Default for MemoryBackend {
+    fn default() -> MemoryBackend {
+        MemoryBackend { up: false, weight: 1, sessions: 0 }
+    }
 }
 
 pub struct MemoryBackendProvider {
-    // TODO: set id for server replacement, use HashMap?
-    alive: Mutex<BTreeSet<String>>
+    // TODO: set id for server replacement
+    /** Known backends, down ones are kept for their weight and session count */
+    backends: Mutex<BTreeMap<String, MemoryBackend>>,
+    strategy: Box<dyn BackendStrategy>
 }
 
 impl MemoryBackendProvider {
     pub fn new() -> MemoryBackendProvider {
         MemoryBackendProvider {
-            alive: Mutex::new(BTreeSet::new())
+            backends: Mutex::new(BTreeMap::new()),
+            strategy: Box::new(RoundRobinStrategy::default())
         }
     }
+
+    /** Round-robin by default */
+    pub fn with_strategy(mut self, strategy: Box<dyn BackendStrategy>) -> MemoryBackendProvider {
+        self.strategy = strategy;
+        self
+    }
+
+    /** Relative share of new sessions for `url`, 1 by default */
+    pub fn set_weight(&self, url: String, weight: u32) {
+        self.backends.lock().unwrap().entry(url).or_default().weight = weight.max(1);
+    }
 }
 
+#[async_trait]
 impl JanusBackendProvider for MemoryBackendProvider {
-    fn update_backend(&self, url: String, up: bool) {
-        if up {
-            self.alive.lock().unwrap().insert(url);
+    async fn update_backend(&self, url: String, up: bool) {
+        self.backends.lock().unwrap().entry(url).or_default().up = up;
+    }
+
+    async fn get_backend(&self) -> Option<String> {
+        let candidates = self.backends.lock().unwrap().iter()
+            .filter(|(_, x)| x.up)
+            .map(|(url, x)| BackendLoad { url: url.clone(), weight: x.weight, sessions: x.sessions })
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            return None
         }
-        else {
-            self.alive.lock().unwrap().remove(&url);
+
+        let i = self.strategy.select(&candidates);
+        candidates.into_iter().nth(i).map(|x| x.url)
+    }
+
+    async fn list_backends(&self) -> Vec<String> {
+        self.backends.lock().unwrap().iter()
+            .filter(|(_, x)| x.up)
+            .map(|(url, _)| url.clone())
+            .collect()
+    }
+
+    async fn session_opened(&self, url: &str) {
+        if let Some(x) = self.backends.lock().unwrap().get_mut(url) {
+            x.sessions += 1;
         }
     }
 
-    fn get_backend(&self) -> Option<String> {
-        // TODO: round-robin,... fashion
-        let x = self.alive.lock().unwrap().iter().next()?.clone();
-        Some(x)
+    async fn session_closed(&self, url: &str) {
+        if let Some(x) = self.backends.lock().unwrap().get_mut(url) {
+            x.sessions = x.sessions.saturating_sub(1);
+        }
     }
 }
 
-// TODO: Redis version
-struct _RedisBackendProvider;
+/**
+ * Backend registry shared by proxy instances through redis: status and session count of each backend,
+ * so strategies see sessions of the whole cluster. Weights and strategy are settings of this instance.
+ */
+pub struct RedisBackendProvider {
+    client: RedisClient,
+    prefix: String,
+    weights: Mutex<HashMap<String, u32>>,
+    strategy: Box<dyn BackendStrategy>
+}
+
+impl RedisBackendProvider {
+    /** `url` is only checked, redis is connected to on first use */
+    pub fn new(url: &str) -> Result<RedisBackendProvider, String> {
+        Ok(RedisBackendProvider {
+            client: RedisClient::open(url)?,
+            prefix: String::from("janus-proxy"),
+            weights: Mutex::new(HashMap::new()),
+            strategy: Box::new(RoundRobinStrategy::default())
+        })
+    }
+
+    /** Namespace of keys, "janus-proxy" by default */
+    pub fn with_prefix(mut self, prefix: String) -> RedisBackendProvider {
+        self.prefix = prefix;
+        self
+    }
+
+    /** Round-robin by default */
+    pub fn with_strategy(mut self, strategy: Box<dyn BackendStrategy>) -> RedisBackendProvider {
+        self.strategy = strategy;
+        self
+    }
+
+    /** Relative share of new sessions for `url`, 1 by default */
+    pub fn set_weight(&self, url: String, weight: u32) {
+        self.weights.lock().unwrap().insert(url, weight.max(1));
+    }
+
+    fn status_key(&self) -> String {
+        format!("{}:backends", self.prefix)
+    }
+
+    fn sessions_key(&self) -> String {
+        format!("{}:backend_sessions", self.prefix)
+    }
+
+    /** Healthy backends sorted by url, with their load */
+    async fn load(&self) -> Vec<BackendLoad> {
+        let pipeline = redis::pipe()
+            .cmd("HGETALL").arg(self.status_key())
+            .cmd("HGETALL").arg(self.sessions_key())
+            .clone();
+        let (status, sessions) = match self.client.query_pipeline::<(BTreeMap<String, i64>, HashMap<String, i64>)>(&pipeline).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not get backends");
+                return Vec::new()
+            }
+        };
+
+        let weights = self.weights.lock().unwrap();
+        status.into_iter()
+            .filter(|(_, up)| *up != 0)
+            .map(|(url, _)| BackendLoad {
+                weight: weights.get(&url).cloned().unwrap_or(1),
+                sessions: sessions.get(&url).cloned().unwrap_or(0).max(0) as u64,
+                url
+            })
+            .collect()
+    }
+
+    async fn add_sessions(&self, url: &str, n: i64) {
+        if let Err(e) = self.client.query::<i64>(redis::cmd("HINCRBY").arg(self.sessions_key()).arg(url).arg(n)).await {
+            error!(reason = %e, url, "Could not update backend sessions");
+        }
+    }
+}
+
+#[async_trait]
+impl JanusBackendProvider for RedisBackendProvider {
+    async fn update_backend(&self, url: String, up: bool) {
+        if let Err(e) = self.client.query::<i64>(redis::cmd("HSET").arg(self.status_key()).arg(&url).arg(up as i64)).await {
+            error!(reason = %e, url = %url, "Could not update backend");
+        }
+    }
+
+    async fn get_backend(&self) -> Option<String> {
+        let candidates = self.load().await;
+        if candidates.is_empty() {
+            return None
+        }
+
+        let i = self.strategy.select(&candidates);
+        candidates.into_iter().nth(i).map(|x| x.url)
+    }
+
+    async fn list_backends(&self) -> Vec<String> {
+        self.load().await.into_iter().map(|x| x.url).collect()
+    }
+
+    async fn session_opened(&self, url: &str) {
+        self.add_sessions(url, 1).await;
+    }
+
+    async fn session_closed(&self, url: &str) {
+        self.add_sessions(url, -1).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::janus::provider::LeastSessionsStrategy;
+    use crate::janus::provider::redis_stub::RedisStub;
+
+    static A: &str = "ws://a:8188";
+    static B: &str = "ws://b:8188";
+
+    fn provider(redis: &RedisStub) -> RedisBackendProvider {
+        RedisBackendProvider::new(&redis.url).unwrap().with_strategy(Box::new(LeastSessionsStrategy))
+    }
+
+    #[tokio::test]
+    async fn status_is_shared_between_instances() {
+        let redis = RedisStub::start();
+        let (a, b) = (provider(&redis), provider(&redis));
+        assert_eq!(b.get_backend().await, None);
+
+        a.update_backend(A.to_string(), true).await;
+        a.update_backend(B.to_string(), true).await;
+        assert_eq!(b.list_backends().await, vec![A.to_string(), B.to_string()]);
+
+        b.update_backend(A.to_string(), false).await;
+        assert_eq!(a.list_backends().await, vec![B.to_string()]);
+        assert_eq!(a.get_backend().await, Some(B.to_string()));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_counted_across_instances() {
+        let redis = RedisStub::start();
+        let (a, b) = (provider(&redis), provider(&redis));
+        a.update_backend(A.to_string(), true).await;
+        a.update_backend(B.to_string(), true).await;
+
+        a.session_opened(A).await;
+        assert_eq!(b.get_backend().await, Some(B.to_string()));
+        b.session_opened(B).await;
+        b.session_opened(B).await;
+        assert_eq!(a.get_backend().await, Some(A.to_string()));
+
+        // Weights are local: B takes 3 times A's sessions for this instance only
+        b.set_weight(B.to_string(), 3);
+        assert_eq!(b.get_backend().await, Some(B.to_string()));
+        assert_eq!(a.get_backend().await, Some(A.to_string()));
+
+        b.session_closed(B).await;
+        b.session_closed(B).await;
+        assert_eq!(a.get_backend().await, Some(B.to_string()));
+    }
+}
@@ -0,0 +1,144 @@
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(test)]
+use std::sync::atomic::AtomicUsize;
+
+/** Largest integer a JavaScript Number holds exactly (2^53 - 1), clients may parse ids as such */
+pub const MAX_SAFE_ID: u64 = (1 << 53) - 1;
+
+/** Fresh ids drawn before giving up on a unique one, only a broken `IdGenerator` (or a full id space) gets there */
+pub const MAX_ATTEMPTS: usize = 16;
+
+/** Session, handle, room and transaction ids */
+pub trait IdGenerator: Send + Sync {
+    /** A candidate id in `1..=MAX_SAFE_ID`, callers make sure it's not in use */
+    fn next_id(&self) -> u64;
+}
+
+/** Uniformly random, collisions are unlikely even across instances */
+#[derive(Default)]
+pub struct RandomIdGenerator;
+
+impl IdGenerator for RandomIdGenerator {
+    fn next_id(&self) -> u64 {
+        thread_rng().gen_range(1, MAX_SAFE_ID + 1)
+    }
+}
+
+/** 1, 2, 3... then around again after `MAX_SAFE_ID`. Easy to follow in logs, but guessable and per instance */
+pub struct MonotonicIdGenerator {
+    next: AtomicU64
+}
+
+impl MonotonicIdGenerator {
+    pub fn new(start: u64) -> MonotonicIdGenerator {
+        MonotonicIdGenerator {
+            next: AtomicU64::new(start)
+        }
+    }
+}
+
+impl Default for MonotonicIdGenerator {
+    fn default() -> MonotonicIdGenerator {
+        Self::new(1)
+    }
+}
+
+impl IdGenerator for MonotonicIdGenerator {
+    fn next_id(&self) -> u64 {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        (n.wrapping_sub(1) % MAX_SAFE_ID) + 1
+    }
+}
+
+/** Random, but the same sequence for the same seed, for tests */
+pub struct SeededIdGenerator {
+    rng: Mutex<StdRng>
+}
+
+impl SeededIdGenerator {
+    pub fn new(seed: u64) -> SeededIdGenerator {
+        SeededIdGenerator {
+            rng: Mutex::new(StdRng::seed_from_u64(seed))
+        }
+    }
+}
+
+impl IdGenerator for SeededIdGenerator {
+    fn next_id(&self) -> u64 {
+        self.rng.lock().unwrap().gen_range(1, MAX_SAFE_ID + 1)
+    }
+}
+
+/** `ids` in turn, over and over, for tests which need collisions */
+#[cfg(test)]
+pub(crate) struct ScriptedIdGenerator {
+    ids: Vec<u64>,
+    next: AtomicUsize
+}
+
+#[cfg(test)]
+impl ScriptedIdGenerator {
+    pub(crate) fn new(ids: &[u64]) -> ScriptedIdGenerator {
+        assert!(!ids.is_empty(), "No id to script");
+        ScriptedIdGenerator {
+            ids: ids.to_vec(),
+            next: AtomicUsize::new(0)
+        }
+    }
+}
+
+#[cfg(test)]
+impl IdGenerator for ScriptedIdGenerator {
+    fn next_id(&self) -> u64 {
+        self.ids[self.next.fetch_add(1, Ordering::Relaxed) % self.ids.len()]
+    }
+}
+
+/** Generator by configuration name, `seed` is only used by "seeded" */
+pub fn id_generator(name: &str, seed: u64) -> Option<Box<dyn IdGenerator>> {
+    match name {
+        "random" => Some(Box::new(RandomIdGenerator)),
+        "monotonic" => Some(Box::new(MonotonicIdGenerator::default())),
+        "seeded" => Some(Box::new(SeededIdGenerator::new(seed))),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_fit_javascript_numbers() {
+        let generators: Vec<Box<dyn IdGenerator>> = vec![
+            Box::new(RandomIdGenerator),
+            Box::new(SeededIdGenerator::new(7)),
+            Box::new(MonotonicIdGenerator::new(MAX_SAFE_ID - 1))
+        ];
+        for ids in generators.iter() {
+            for _ in 0..1000 {
+                let id = ids.next_id();
+                assert!(id >= 1 && id <= MAX_SAFE_ID, "{} out of range", id);
+            }
+        }
+    }
+
+    #[test]
+    fn monotonic_ids_wrap_around() {
+        let ids = MonotonicIdGenerator::new(MAX_SAFE_ID);
+        assert_eq!(ids.next_id(), MAX_SAFE_ID);
+        assert_eq!(ids.next_id(), 1);
+        assert_eq!(ids.next_id(), 2);
+    }
+
+    #[test]
+    fn seeded_ids_are_deterministic() {
+        let (a, b) = (SeededIdGenerator::new(42), SeededIdGenerator::new(42));
+        let sequence = (0..100).map(|_| a.next_id()).collect::<Vec<_>>();
+        assert_eq!(sequence, (0..100).map(|_| b.next_id()).collect::<Vec<_>>());
+        assert_ne!(sequence, (0..100).map(|_| SeededIdGenerator::new(43).next_id()).collect::<Vec<_>>());
+    }
+}
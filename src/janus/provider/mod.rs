@@ -1,5 +1,12 @@
 mod state;
 mod backend;
+mod strategy;
+mod id;
+mod redis_client;
+#[cfg(test)]
+pub(crate) mod redis_stub;
 
 pub use self::state::*;
 pub use self::backend::*;
+pub use self::strategy::*;
+pub use self::id::*;
@@ -0,0 +1,86 @@
+use redis::{Client, Connection, ConnectionLike, ErrorKind, FromRedisValue, Pipeline, Cmd, RedisResult};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/** Requests fail after that, rather than holding a blocking thread */
+static TIMEOUT: Duration = Duration::from_secs(2);
+
+/** Idle connections kept for later queries, others are closed once used */
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+struct Connections {
+    client: Client,
+    idle: Mutex<Vec<Connection>>
+}
+
+/**
+ * Connections to redis shared by a provider. redis is queried with blocking connections on tokio's blocking pool,
+ * so concurrent queries each get a connection, and none holds up async tasks.
+ * A connection is dropped after a failure, another one is opened by the next query.
+ */
+pub(crate) struct RedisClient(Arc<Connections>);
+
+impl RedisClient {
+    /** Only check `url`, connect on first query */
+    pub(crate) fn open(url: &str) -> Result<RedisClient, String> {
+        match Client::open(url) {
+            Ok(client) => Ok(RedisClient(Arc::new(Connections { client, idle: Mutex::new(Vec::new()) }))),
+            Err(e) => Err(format!("Invalid redis url \"{}\": {}", url, e))
+        }
+    }
+
+    pub(crate) async fn query<T: FromRedisValue + Send + 'static>(&self, cmd: &Cmd) -> RedisResult<T> {
+        let cmd = cmd.clone();
+        self.run(move |connection| cmd.query(connection)).await
+    }
+
+    pub(crate) async fn query_pipeline<T: FromRedisValue + Send + 'static>(&self, pipeline: &Pipeline) -> RedisResult<T> {
+        let pipeline = pipeline.clone();
+        self.run(move |connection| pipeline.query(connection)).await
+    }
+
+    async fn run<T, F>(&self, f: F) -> RedisResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Connection) -> RedisResult<T> + Send + 'static
+    {
+        let connections = Arc::clone(&self.0);
+        match tokio::task::spawn_blocking(move || connections.with_connection(f)).await {
+            Ok(x) => x,
+            Err(e) => Err((ErrorKind::ClientError, "Redis query aborted", e.to_string()).into())
+        }
+    }
+}
+
+impl Connections {
+    fn connect(&self) -> RedisResult<Connection> {
+        let connection = self.client.get_connection_with_timeout(TIMEOUT)?;
+        connection.set_read_timeout(Some(TIMEOUT))?;
+        connection.set_write_timeout(Some(TIMEOUT))?;
+        Ok(connection)
+    }
+
+    /** Blocking: run `f` on an idle connection, or a new one */
+    fn with_connection<T, F: FnOnce(&mut Connection) -> RedisResult<T>>(&self, f: F) -> RedisResult<T> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut connection = match idle {
+            Some(x) if x.is_open() => x,
+            _ => self.connect()?
+        };
+
+        let result = f(&mut connection);
+        match &result {
+            Err(e) if e.is_io_error() || e.is_connection_dropped() || e.is_timeout() => {
+                warn!(reason = %e, "Connection to redis lost");
+            },
+            _ => {
+                let mut idle = self.idle.lock().unwrap();
+                if idle.len() < MAX_IDLE_CONNECTIONS {
+                    idle.push(connection);
+                }
+            }
+        }
+        result
+    }
+}
@@ -0,0 +1,200 @@
+/**
+ * In-process stand-in for redis-server, for tests: only the commands providers use, over RESP.
+ */
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+enum Value {
+    Text(String),
+    Set(HashSet<String>),
+    Hash(HashMap<String, String>)
+}
+
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<String>),
+    Array(Vec<Reply>)
+}
+
+impl Reply {
+    fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            Reply::Status(x) => out.extend(format!("+{}\r\n", x).bytes()),
+            Reply::Error(x) => out.extend(format!("-ERR {}\r\n", x).bytes()),
+            Reply::Integer(x) => out.extend(format!(":{}\r\n", x).bytes()),
+            Reply::Bulk(None) => out.extend(b"$-1\r\n"),
+            Reply::Bulk(Some(x)) => out.extend(format!("${}\r\n{}\r\n", x.len(), x).bytes()),
+            Reply::Array(items) => {
+                out.extend(format!("*{}\r\n", items.len()).bytes());
+                for x in items.iter() {
+                    x.encode(out);
+                }
+            }
+        }
+    }
+
+    fn strings<I: IntoIterator<Item = String>>(items: I) -> Reply {
+        Reply::Array(items.into_iter().map(|x| Reply::Bulk(Some(x))).collect())
+    }
+}
+
+#[derive(Default)]
+struct Store {
+    data: HashMap<String, (Value, Option<Instant>)>
+}
+
+impl Store {
+    /** Drop expired keys, and empty sets and hashes like redis */
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.data.retain(|_, (value, deadline)| match value {
+            Value::Set(x) if x.is_empty() => false,
+            Value::Hash(x) if x.is_empty() => false,
+            _ => deadline.map_or(true, |x| x > now)
+        });
+    }
+
+    fn set(&mut self, key: &str) -> &mut HashSet<String> {
+        let entry = self.data.entry(key.to_string()).or_insert_with(|| (Value::Set(HashSet::new()), None));
+        match &mut entry.0 {
+            Value::Set(x) => x,
+            _ => panic!("WRONGTYPE {}", key)
+        }
+    }
+
+    fn hash(&mut self, key: &str) -> &mut HashMap<String, String> {
+        let entry = self.data.entry(key.to_string()).or_insert_with(|| (Value::Hash(HashMap::new()), None));
+        match &mut entry.0 {
+            Value::Hash(x) => x,
+            _ => panic!("WRONGTYPE {}", key)
+        }
+    }
+
+    fn execute(&mut self, args: &[String]) -> Reply {
+        self.expire();
+        let reply = self.run(args);
+        self.expire();
+        reply
+    }
+
+    fn run(&mut self, args: &[String]) -> Reply {
+        let name = args[0].to_uppercase();
+        match &name[..] {
+            "PING" => Reply::Status("PONG"),
+            "SET" => {
+                let options = args[3..].iter().map(|x| x.to_uppercase()).collect::<Vec<_>>();
+                if options.contains(&"NX".to_string()) && self.data.contains_key(&args[1]) {
+                    return Reply::Bulk(None)
+                }
+                let deadline = options.iter().position(|x| x == "PX")
+                    .map(|i| Instant::now() + Duration::from_millis(args[3 + i + 1].parse().unwrap()));
+                self.data.insert(args[1].clone(), (Value::Text(args[2].clone()), deadline));
+                Reply::Status("OK")
+            },
+            "GET" => match self.data.get(&args[1]) {
+                Some((Value::Text(x), _)) => Reply::Bulk(Some(x.clone())),
+                _ => Reply::Bulk(None)
+            },
+            "EXISTS" => Reply::Integer(args[1..].iter().filter(|x| self.data.contains_key(*x)).count() as i64),
+            "DEL" => Reply::Integer(args[1..].iter().filter(|x| self.data.remove(*x).is_some()).count() as i64),
+            "PEXPIRE" => match self.data.get_mut(&args[1]) {
+                Some((_, deadline)) => {
+                    *deadline = Some(Instant::now() + Duration::from_millis(args[2].parse().unwrap()));
+                    Reply::Integer(1)
+                },
+                None => Reply::Integer(0)
+            },
+            "SADD" => {
+                let set = self.set(&args[1]);
+                Reply::Integer(args[2..].iter().filter(|x| set.insert(x.to_string())).count() as i64)
+            },
+            "SREM" => {
+                let set = self.set(&args[1]);
+                Reply::Integer(args[2..].iter().filter(|x| set.remove(*x)).count() as i64)
+            },
+            "SMEMBERS" => Reply::strings(self.set(&args[1]).iter().cloned().collect::<Vec<_>>()),
+            "SISMEMBER" => Reply::Integer(self.set(&args[1]).contains(&args[2]) as i64),
+            "HSET" => {
+                let hash = self.hash(&args[1]);
+                let added = args[2..].chunks(2).filter(|x| hash.insert(x[0].clone(), x[1].clone()).is_none()).count();
+                Reply::Integer(added as i64)
+            },
+            "HDEL" => {
+                let hash = self.hash(&args[1]);
+                Reply::Integer(args[2..].iter().filter(|x| hash.remove(*x).is_some()).count() as i64)
+            },
+            "HGETALL" => {
+                let hash = self.hash(&args[1]);
+                Reply::strings(hash.iter().flat_map(|(k, v)| vec![k.clone(), v.clone()]).collect::<Vec<_>>())
+            },
+            "HINCRBY" => {
+                let hash = self.hash(&args[1]);
+                let value = hash.get(&args[2]).map_or(0, |x| x.parse::<i64>().unwrap()) + args[3].parse::<i64>().unwrap();
+                hash.insert(args[2].clone(), value.to_string());
+                Reply::Integer(value)
+            },
+            x => Reply::Error(format!("unknown command '{}'", x))
+        }
+    }
+}
+
+fn read_line(reader: &mut BufReader<TcpStream>) -> Option<String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_end().to_string())
+    }
+}
+
+/** One command, as an array of bulk strings */
+fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<String>> {
+    let count = read_line(reader)?.trim_start_matches('*').parse::<usize>().ok()?;
+    let mut args = Vec::with_capacity(count);
+    for _ in 0..count {
+        let len = read_line(reader)?.trim_start_matches('$').parse::<usize>().ok()?;
+        let mut data = vec![0; len + 2];
+        reader.read_exact(&mut data).ok()?;
+        data.truncate(len);
+        args.push(String::from_utf8(data).ok()?);
+    }
+    Some(args)
+}
+
+fn serve(stream: TcpStream, store: Arc<Mutex<Store>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = BufReader::new(stream);
+    while let Some(args) = read_command(&mut reader) {
+        let mut out = Vec::new();
+        store.lock().unwrap().execute(&args).encode(&mut out);
+        if writer.write_all(&out).is_err() {
+            break
+        }
+    }
+}
+
+pub(crate) struct RedisStub {
+    pub url: String
+}
+
+impl RedisStub {
+    /** Listen on a random local port, for the rest of the test process */
+    pub(crate) fn start() -> RedisStub {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", listener.local_addr().unwrap());
+        let store = Arc::new(Mutex::new(Store::default()));
+
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let store = Arc::clone(&store);
+                thread::spawn(move || serve(stream, store));
+            }
+        });
+        RedisStub { url }
+    }
+}
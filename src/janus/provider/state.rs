@@ -1,75 +1,726 @@
 use crate::janus::helper;
 use crate::janus::core::json::*;
-use std::collections::HashSet;
-use std::sync::Mutex;
+use crate::janus::core::apierror::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Once, Weak};
+use std::time::Duration;
+use async_trait::async_trait;
+use tracing::{error, info, warn};
+use super::redis_client::RedisClient;
+use super::id::{IdGenerator, RandomIdGenerator, MAX_ATTEMPTS};
 
 type ID = JSON_POSITIVE_INTEGER;
 
-// TODO: prevent stale session ids: some instances not gracefully shut down.
+/** janus-gateway session (url, session_id) backing a handle */
+pub type BackendSession = (String, u64);
+
+/** No id could be reserved, see `MAX_ATTEMPTS` */
+fn unavailable_id(kind: &str) -> JanusError {
+    JanusError::new(JANUS_ERROR_UNKNOWN, format!("Could not allocate a unique {} id", kind))
+}
+
+#[async_trait]
 pub trait ProxyStateProvider: Send + Sync {
-    fn new_session(&self) -> ID;
-    fn new_handle(&self) -> ID;
+    /** Reserve an id unused by any instance, fail after `MAX_ATTEMPTS` taken ones */
+    async fn new_session(&self) -> Result<ID, JanusError>;
+    async fn new_handle(&self) -> Result<ID, JanusError>;
 
     // TODO: return handle/session object?
-    fn has_session(&self, id: &ID) -> bool;
-    fn has_handle(&self, id: &ID) -> bool;
+    async fn has_session(&self, id: &ID) -> bool;
+    async fn has_handle(&self, id: &ID) -> bool;
 
-    fn remove_session(&self, id: &ID) -> bool;
-    fn remove_handle(&self, id: &ID) -> bool;
+    async fn remove_session(&self, id: &ID) -> bool;
+    async fn remove_handle(&self, id: &ID) -> bool;
+
+    /** Remember the janus-gateway session of handle `id`, it's destroyed by `sweep` if this instance is gone */
+    async fn set_handle_backend(&self, id: &ID, backend: BackendSession);
+    /** Sessions of every proxy instance sharing this state, by instance */
+    async fn list_sessions(&self) -> HashMap<String, Vec<ID>>;
+    /** Reclaim ids whose lease has expired, return their janus-gateway sessions to destroy */
+    async fn sweep(&self) -> Vec<BackendSession>;
+
+    /**
+     * Stored tokens, mirror janus-gateway `auth.c`: a token is only allowed the plugins it was added with,
+     * none if `plugins` is empty. Adding a known token allows it more plugins.
+     */
+    async fn add_token(&self, token: &str, plugins: Vec<String>);
+    async fn remove_token(&self, token: &str) -> bool;
+    async fn list_tokens(&self) -> Vec<(String, Vec<String>)>;
+    async fn check_token(&self, token: &str) -> bool;
+    async fn check_token_plugin(&self, token: &str, plugin: &str) -> bool;
 }
 
 pub struct MemoryStateProvider {
+    ids: Box<dyn IdGenerator>,
     sessions: Mutex<HashSet<ID>>,
     // Must be unique within a session, using global unique for simplicity
-    handles: Mutex<HashSet<ID>>
+    handles: Mutex<HashSet<ID>>,
+    tokens: Mutex<HashMap<String, HashSet<String>>>
 }
 
 impl MemoryStateProvider {
     pub fn new() -> MemoryStateProvider {
         MemoryStateProvider {
+            ids: Box::new(RandomIdGenerator),
             sessions: Mutex::new(HashSet::new()),
-            handles: Mutex::new(HashSet::new())
+            handles: Mutex::new(HashSet::new()),
+            tokens: Mutex::new(HashMap::new())
+        }
+    }
+
+    /** Source of session and handle ids, random by default */
+    pub fn with_id_generator(mut self, ids: Box<dyn IdGenerator>) -> MemoryStateProvider {
+        self.ids = ids;
+        self
+    }
+
+    /** An id from `ids` not in `used`, which reserves it */
+    fn reserve(&self, used: &Mutex<HashSet<ID>>, kind: &str) -> Result<ID, JanusError> {
+        let mut used = used.lock().unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            let id = self.ids.next_id();
+            if used.insert(id) {
+                return Ok(id)
+            }
         }
+        Err(unavailable_id(kind))
     }
 }
 
+#[async_trait]
 impl ProxyStateProvider for MemoryStateProvider {
-    fn new_session(&self) -> ID {
+    async fn new_session(&self) -> Result<ID, JanusError> {
+        self.reserve(&self.sessions, "session")
+    }
+
+    async fn new_handle(&self) -> Result<ID, JanusError> {
+        self.reserve(&self.handles, "handle")
+    }
+
+    async fn has_session(&self, id: &ID) -> bool {
+        self.sessions.lock().unwrap().contains(id)
+    }
+
+    async fn has_handle(&self, id: &ID) -> bool {
+        self.handles.lock().unwrap().contains(id)
+    }
+
+    async fn remove_session(&self, id: &ID) -> bool {
+        self.sessions.lock().unwrap().remove(id)
+    }
+
+    async fn remove_handle(&self, id: &ID) -> bool {
+        self.handles.lock().unwrap().remove(id)
+    }
+
+    /** Handles are destroyed along with this instance */
+    async fn set_handle_backend(&self, _id: &ID, _backend: BackendSession) {}
+
+    async fn list_sessions(&self) -> HashMap<String, Vec<ID>> {
+        let sessions = self.sessions.lock().unwrap().iter().cloned().collect();
+        vec![(String::from("local"), sessions)].into_iter().collect()
+    }
+
+    /** Ids are never leaked, they're gone with this instance */
+    async fn sweep(&self) -> Vec<BackendSession> {
+        Vec::new()
+    }
+
+    async fn add_token(&self, token: &str, plugins: Vec<String>) {
+        let mut tokens = self.tokens.lock().unwrap();
+        tokens.entry(token.to_string()).or_default().extend(plugins);
+    }
+
+    async fn remove_token(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().remove(token).is_some()
+    }
+
+    async fn list_tokens(&self) -> Vec<(String, Vec<String>)> {
+        self.tokens.lock().unwrap().iter()
+            .map(|(token, plugins)| (token.clone(), plugins.iter().cloned().collect()))
+            .collect()
+    }
+
+    async fn check_token(&self, token: &str) -> bool {
+        self.tokens.lock().unwrap().contains_key(token)
+    }
+
+    async fn check_token_plugin(&self, token: &str, plugin: &str) -> bool {
+        match self.tokens.lock().unwrap().get(token) {
+            Some(plugins) => plugins.contains(plugin),
+            None => false
+        }
+    }
+}
+
+/**
+ * State shared by proxy instances through redis, keys are namespaced by `prefix`.
+ * Session and handle ids are leases owned by `instance`: reserved with a TTL, renewed in background while in use,
+ * so ids of an instance which hasn't been shut down gracefully are released eventually.
+ * Each instance also indexes its leases, along with janus-gateway sessions of handles, for `list_sessions` and `sweep`.
+ * Redis errors are logged, requests go on as if ids were reserved and nothing was found.
+ */
+pub struct RedisStateProvider {
+    client: Arc<RedisClient>,
+    ids: Box<dyn IdGenerator>,
+    prefix: String,
+    instance: String,
+    lease: Duration,
+    /** Keys of ids reserved by this instance, renewed until removed */
+    leases: Arc<Mutex<HashSet<String>>>,
+    renewal: Once
+}
+
+impl RedisStateProvider {
+    /** `url` is only checked, redis is connected to on first use */
+    pub fn new(url: &str) -> Result<RedisStateProvider, String> {
+        Ok(RedisStateProvider {
+            client: Arc::new(RedisClient::open(url)?),
+            ids: Box::new(RandomIdGenerator),
+            prefix: String::from("janus-proxy"),
+            instance: format!("{:08x}", helper::rand_id()),
+            lease: Duration::from_secs(60),
+            leases: Arc::new(Mutex::new(HashSet::new())),
+            renewal: Once::new()
+        })
+    }
+
+    /** Namespace of keys, "janus-proxy" by default */
+    pub fn with_prefix(mut self, prefix: String) -> RedisStateProvider {
+        self.prefix = prefix;
+        self
+    }
+
+    /** Source of session and handle ids, random by default. Other instances must not produce the same sequence */
+    pub fn with_id_generator(mut self, ids: Box<dyn IdGenerator>) -> RedisStateProvider {
+        self.ids = ids;
+        self
+    }
+
+    /** Name of this proxy instance, unique among those sharing `prefix`. Random by default */
+    pub fn with_instance(mut self, instance: String) -> RedisStateProvider {
+        self.instance = instance;
+        self
+    }
+
+    /** How long an id outlives its (crashed) instance, 60 seconds by default. Leases are renewed every third of it */
+    pub fn with_lease(mut self, lease: Duration) -> RedisStateProvider {
+        self.lease = lease;
+        self
+    }
+
+    fn id_key(&self, kind: &str, id: &ID) -> String {
+        format!("{}:{}:{}", self.prefix, kind, id)
+    }
+
+    /** Leases of `instance`: field "session:<id>" or "handle:<id>", value the janus-gateway session of a handle if any */
+    fn instance_key(&self, instance: &str) -> String {
+        format!("{}:instance:{}", self.prefix, instance)
+    }
+
+    fn instances_key(&self) -> String {
+        format!("{}:instances", self.prefix)
+    }
+
+    fn token_key(&self, token: &str) -> String {
+        format!("{}:token:{}", self.prefix, token)
+    }
+
+    fn tokens_key(&self) -> String {
+        format!("{}:tokens", self.prefix)
+    }
+
+    fn lease_millis(&self) -> u64 {
+        self.lease.as_millis() as u64
+    }
+
+    /** Renew leases of this instance until the provider is dropped */
+    fn start_renewal(&self) {
+        let client = Arc::clone(&self.client);
+        let leases = Arc::downgrade(&self.leases);
+        let lease = self.lease;
+        self.renewal.call_once(move || {
+            tokio::spawn(Self::renew(client, leases, lease));
+        });
+    }
+
+    async fn renew(client: Arc<RedisClient>, leases: Weak<Mutex<HashSet<String>>>, lease: Duration) {
         loop {
-            let id = helper::rand_id();
-            let mut sessions = self.sessions.lock().unwrap();
-            if sessions.insert(id) {
-                return id
+            tokio::time::delay_for(lease / 3).await;
+            let leases = match leases.upgrade() {
+                None => break,
+                Some(x) => x
+            };
+            let keys = leases.lock().unwrap().iter().cloned().collect::<Vec<_>>();
+            if keys.is_empty() {
+                continue
+            }
+
+            let mut pipeline = redis::pipe();
+            for key in keys.iter() {
+                pipeline.cmd("PEXPIRE").arg(key).arg(lease.as_millis() as u64);
+            }
+            match client.query_pipeline::<Vec<bool>>(&pipeline).await {
+                Ok(renewed) => for (key, _) in keys.iter().zip(renewed).filter(|(_, x)| !x) {
+                    // Removed meanwhile, or expired already: the id may have been reused
+                    if leases.lock().unwrap().contains(key) {
+                        warn!(key = %key, "Lease expired before renewal");
+                    }
+                },
+                Err(e) => warn!(reason = %e, "Could not renew leases")
             }
         }
     }
 
-    fn new_handle(&self) -> ID {
-        loop {
-            let id = helper::rand_id();
-            let mut handles = self.handles.lock().unwrap();
-            if !handles.insert(id) {
-                return id
+    async fn reserve(&self, kind: &str) -> Result<ID, JanusError> {
+        self.start_renewal();
+        for _ in 0..MAX_ATTEMPTS {
+            let id = self.ids.next_id();
+            let key = self.id_key(kind, &id);
+            let cmd = redis::cmd("SET").arg(&key).arg(&self.instance).arg("NX").arg("PX").arg(self.lease_millis()).clone();
+            match self.client.query::<Option<String>>(&cmd).await {
+                Ok(Some(_)) => {
+                    self.leases.lock().unwrap().insert(key);
+                    let pipeline = redis::pipe()
+                        .cmd("SADD").arg(self.instances_key()).arg(&self.instance).ignore()
+                        .cmd("HSET").arg(self.instance_key(&self.instance)).arg(format!("{}:{}", kind, id)).arg("").ignore()
+                        .clone();
+                    if let Err(e) = self.client.query_pipeline::<()>(&pipeline).await {
+                        error!(reason = %e, kind, "Could not index lease");
+                    }
+                    return Ok(id)
+                },
+                // Reserved by any instance already
+                Ok(None) => continue,
+                Err(e) => {
+                    error!(reason = %e, kind, "Could not reserve id, using an unreserved one");
+                    return Ok(id)
+                }
             }
         }
+        Err(unavailable_id(kind))
     }
 
-    fn has_session(&self, id: &ID) -> bool {
-        self.sessions.lock().unwrap().contains(id)
+    async fn exists(&self, key: &str) -> bool {
+        match self.client.query::<bool>(redis::cmd("EXISTS").arg(key)).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, key, "Could not check existence");
+                false
+            }
+        }
     }
 
-    fn has_handle(&self, id: &ID) -> bool {
-        self.handles.lock().unwrap().contains(id)
+    async fn release(&self, kind: &str, id: &ID) -> bool {
+        let key = self.id_key(kind, id);
+        self.leases.lock().unwrap().remove(&key);
+        // Index first, a lease without index entry is never swept
+        let pipeline = redis::pipe()
+            .cmd("HDEL").arg(self.instance_key(&self.instance)).arg(format!("{}:{}", kind, id)).ignore()
+            .cmd("DEL").arg(&key)
+            .clone();
+        match self.client.query_pipeline::<(i64,)>(&pipeline).await {
+            Ok((x,)) => x > 0,
+            Err(e) => {
+                error!(reason = %e, key = %key, "Could not release id");
+                false
+            }
+        }
     }
+}
 
-    fn remove_session(&self, id: &ID) -> bool {
-        self.sessions.lock().unwrap().remove(id)
+/** Index entry of a lease, see `RedisStateProvider::instance_key` */
+struct IndexedLease {
+    instance: String,
+    /** "session:<id>" or "handle:<id>" */
+    field: String,
+    /** janus-gateway session as JSON, empty if none */
+    backend: String,
+    /** Instance holding the lease now, None once expired */
+    owner: Option<String>
+}
+
+impl IndexedLease {
+    fn is_stale(&self) -> bool {
+        self.owner.as_ref() != Some(&self.instance)
     }
+}
 
-    fn remove_handle(&self, id: &ID) -> bool {
-        self.handles.lock().unwrap().remove(id)
+impl RedisStateProvider {
+    /** Every indexed lease, of every instance */
+    async fn list_leases(&self) -> redis::RedisResult<Vec<IndexedLease>> {
+        let instances = self.client.query::<Vec<String>>(redis::cmd("SMEMBERS").arg(self.instances_key())).await?;
+
+        let mut pipeline = redis::pipe();
+        for instance in instances.iter() {
+            pipeline.cmd("HGETALL").arg(self.instance_key(instance));
+        }
+        let indexes = self.client.query_pipeline::<Vec<HashMap<String, String>>>(&pipeline).await?;
+
+        let mut leases = Vec::new();
+        for (instance, index) in instances.into_iter().zip(indexes) {
+            // Nothing left of an instance which is gone
+            if index.is_empty() && instance != self.instance {
+                self.client.query::<()>(redis::cmd("SREM").arg(self.instances_key()).arg(&instance)).await?;
+            }
+            for (field, backend) in index.into_iter() {
+                leases.push((instance.clone(), field, backend));
+            }
+        }
+        if leases.is_empty() {
+            return Ok(Vec::new())
+        }
+
+        let mut pipeline = redis::pipe();
+        for (_, field, _) in leases.iter() {
+            pipeline.cmd("GET").arg(format!("{}:{}", self.prefix, field));
+        }
+        let owners = self.client.query_pipeline::<Vec<Option<String>>>(&pipeline).await?;
+        Ok(leases.into_iter().zip(owners)
+            .map(|((instance, field, backend), owner)| IndexedLease { instance, field, backend, owner })
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ProxyStateProvider for RedisStateProvider {
+    async fn new_session(&self) -> Result<ID, JanusError> {
+        self.reserve("session").await
+    }
+
+    async fn new_handle(&self) -> Result<ID, JanusError> {
+        self.reserve("handle").await
+    }
+
+    async fn has_session(&self, id: &ID) -> bool {
+        self.exists(&self.id_key("session", id)).await
+    }
+
+    async fn has_handle(&self, id: &ID) -> bool {
+        self.exists(&self.id_key("handle", id)).await
+    }
+
+    async fn remove_session(&self, id: &ID) -> bool {
+        self.release("session", id).await
+    }
+
+    async fn remove_handle(&self, id: &ID) -> bool {
+        self.release("handle", id).await
+    }
+
+    async fn set_handle_backend(&self, id: &ID, backend: BackendSession) {
+        let value = serde_json::to_string(&backend).unwrap();
+        let cmd = redis::cmd("HSET").arg(self.instance_key(&self.instance)).arg(format!("handle:{}", id)).arg(value).clone();
+        if let Err(e) = self.client.query::<()>(&cmd).await {
+            error!(reason = %e, handle_id = id, "Could not save janus-gateway session of handle");
+        }
+    }
+
+    async fn list_sessions(&self) -> HashMap<String, Vec<ID>> {
+        let leases = match self.list_leases().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not list sessions");
+                return HashMap::new()
+            }
+        };
+
+        let mut sessions = HashMap::new();
+        for lease in leases.into_iter().filter(|x| !x.is_stale()) {
+            if let Some(id) = lease.field.strip_prefix("session:").and_then(|x| x.parse::<ID>().ok()) {
+                sessions.entry(lease.instance).or_insert_with(Vec::new).push(id);
+            }
+        }
+        sessions
+    }
+
+    async fn sweep(&self) -> Vec<BackendSession> {
+        let leases = match self.list_leases().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not sweep leases");
+                return Vec::new()
+            }
+        };
+
+        // Expired, or reserved again by another instance already
+        let stale = leases.into_iter().filter(IndexedLease::is_stale).collect::<Vec<_>>();
+        if stale.is_empty() {
+            return Vec::new()
+        }
+
+        // Other instances may sweep at the same time, whoever removes the entry reclaims it
+        let mut pipeline = redis::pipe();
+        for lease in stale.iter() {
+            pipeline.cmd("HDEL").arg(self.instance_key(&lease.instance)).arg(&lease.field);
+        }
+        let removed = match self.client.query_pipeline::<Vec<bool>>(&pipeline).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not sweep leases");
+                return Vec::new()
+            }
+        };
+
+        let mut backends = Vec::new();
+        for (lease, _) in stale.into_iter().zip(removed).filter(|(_, x)| *x) {
+            info!(instance = %lease.instance, lease = %lease.field, "Lease expired, reclaimed");
+            if let Ok(x) = serde_json::from_str::<BackendSession>(&lease.backend) {
+                backends.push(x);
+            }
+        }
+        backends
+    }
+
+    async fn add_token(&self, token: &str, plugins: Vec<String>) {
+        let mut pipeline = redis::pipe();
+        pipeline.cmd("SADD").arg(self.tokens_key()).arg(token).ignore();
+        if !plugins.is_empty() {
+            pipeline.cmd("SADD").arg(self.token_key(token)).arg(plugins).ignore();
+        }
+        if let Err(e) = self.client.query_pipeline::<()>(&pipeline).await {
+            error!(reason = %e, "Could not add token");
+        }
+    }
+
+    async fn remove_token(&self, token: &str) -> bool {
+        let pipeline = redis::pipe()
+            .cmd("SREM").arg(self.tokens_key()).arg(token)
+            .cmd("DEL").arg(self.token_key(token)).ignore()
+            .clone();
+        match self.client.query_pipeline::<(i64,)>(&pipeline).await {
+            Ok((x,)) => x > 0,
+            Err(e) => {
+                error!(reason = %e, "Could not remove token");
+                false
+            }
+        }
+    }
+
+    async fn list_tokens(&self) -> Vec<(String, Vec<String>)> {
+        let tokens = match self.client.query::<Vec<String>>(redis::cmd("SMEMBERS").arg(self.tokens_key())).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not list tokens");
+                return Vec::new()
+            }
+        };
+
+        let mut pipeline = redis::pipe();
+        for token in tokens.iter() {
+            pipeline.cmd("SMEMBERS").arg(self.token_key(token));
+        }
+        match self.client.query_pipeline::<Vec<Vec<String>>>(&pipeline).await {
+            Ok(plugins) => tokens.into_iter().zip(plugins).collect(),
+            Err(e) => {
+                error!(reason = %e, "Could not list tokens");
+                Vec::new()
+            }
+        }
+    }
+
+    async fn check_token(&self, token: &str) -> bool {
+        match self.client.query::<bool>(redis::cmd("SISMEMBER").arg(self.tokens_key()).arg(token)).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not check token");
+                false
+            }
+        }
+    }
+
+    async fn check_token_plugin(&self, token: &str, plugin: &str) -> bool {
+        match self.client.query::<bool>(redis::cmd("SISMEMBER").arg(self.token_key(token)).arg(plugin)).await {
+            Ok(x) => x,
+            Err(e) => {
+                error!(reason = %e, "Could not check token");
+                false
+            }
+        }
     }
 }
 
-/* TODO: Implement redis for scale */
-pub struct _RedisStateProvider;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::janus::provider::redis_stub::RedisStub;
+    use crate::janus::provider::ScriptedIdGenerator;
+
+    fn provider(redis: &RedisStub) -> RedisStateProvider {
+        RedisStateProvider::new(&redis.url).unwrap()
+    }
+
+    #[tokio::test]
+    async fn memory_ids_are_unique() {
+        let provider = MemoryStateProvider::new().with_id_generator(Box::new(ScriptedIdGenerator::new(&[1, 2, 3])));
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            handles.push(provider.new_handle().await.unwrap());
+        }
+        handles.sort();
+        assert_eq!(handles, vec![1, 2, 3]);
+
+        // Only the released one is handed out again
+        provider.remove_handle(&2).await;
+        assert_eq!(provider.new_handle().await.unwrap(), 2);
+        assert_eq!(provider.new_session().await.unwrap(), 3);
+    }
+
+    #[tokio::test]
+    async fn exhausted_ids_are_an_error() {
+        let memory = MemoryStateProvider::new().with_id_generator(Box::new(ScriptedIdGenerator::new(&[1])));
+        let redis = RedisStub::start();
+        let redis = provider(&redis).with_id_generator(Box::new(ScriptedIdGenerator::new(&[1])));
+        for provider in [&memory as &dyn ProxyStateProvider, &redis].iter() {
+            assert_eq!(provider.new_session().await.unwrap(), 1);
+            assert_eq!(provider.new_session().await.err().unwrap().code, JANUS_ERROR_UNKNOWN);
+            // Other kinds have ids of their own
+            assert_eq!(provider.new_handle().await.unwrap(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn redis_ids_are_unique() {
+        let redis = RedisStub::start();
+        let a = provider(&redis).with_id_generator(Box::new(ScriptedIdGenerator::new(&[1, 2, 3])));
+        let b = provider(&redis).with_id_generator(Box::new(ScriptedIdGenerator::new(&[1, 2, 3])));
+        let mut sessions = vec![a.new_session().await.unwrap(), b.new_session().await.unwrap(), a.new_session().await.unwrap()];
+        sessions.sort();
+        assert_eq!(sessions, vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn ids_are_shared_between_instances() {
+        let redis = RedisStub::start();
+        let (a, b) = (provider(&redis), provider(&redis));
+
+        let session = a.new_session().await.unwrap();
+        let handle = b.new_handle().await.unwrap();
+        assert!(b.has_session(&session).await);
+        assert!(a.has_handle(&handle).await);
+
+        assert!(b.remove_session(&session).await);
+        assert!(!a.has_session(&session).await);
+        assert!(!a.remove_session(&session).await);
+        assert!(a.remove_handle(&handle).await);
+        assert!(!b.has_handle(&handle).await);
+    }
+
+    #[tokio::test]
+    async fn leases_are_renewed_until_instance_is_gone() {
+        let redis = RedisStub::start();
+        let lease = Duration::from_millis(300);
+        let a = provider(&redis).with_lease(lease);
+        let b = provider(&redis).with_lease(lease);
+
+        let session = a.new_session().await.unwrap();
+        tokio::time::delay_for(lease * 3).await;
+        assert!(b.has_session(&session).await);
+
+        // Like a crash: nothing removed, nobody renews
+        drop(a);
+        tokio::time::delay_for(lease * 2).await;
+        assert!(!b.has_session(&session).await);
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_by_instance() {
+        let redis = RedisStub::start();
+        let a = provider(&redis).with_instance("a".to_string());
+        let b = provider(&redis).with_instance("b".to_string());
+
+        let (s1, s2, s3) = (a.new_session().await.unwrap(), a.new_session().await.unwrap(), b.new_session().await.unwrap());
+        a.new_handle().await.unwrap();
+        let mut sessions = b.list_sessions().await;
+        sessions.values_mut().for_each(|x| x.sort());
+        let mut expected = vec![s1, s2];
+        expected.sort();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions["a"], expected);
+        assert_eq!(sessions["b"], vec![s3]);
+
+        a.remove_session(&s1).await;
+        assert_eq!(b.list_sessions().await["a"], vec![s2]);
+    }
+
+    #[tokio::test]
+    async fn expired_leases_are_swept_once() {
+        let redis = RedisStub::start();
+        let lease = Duration::from_millis(300);
+        let a = provider(&redis).with_instance("a".to_string()).with_lease(lease);
+        let b = provider(&redis).with_instance("b".to_string()).with_lease(lease);
+        let c = provider(&redis).with_instance("c".to_string()).with_lease(lease);
+
+        let session = a.new_session().await.unwrap();
+        let handle = a.new_handle().await.unwrap();
+        a.set_handle_backend(&handle, ("ws://janus:8188".to_string(), 42)).await;
+        let alive = b.new_handle().await.unwrap();
+        b.set_handle_backend(&alive, ("ws://janus:8188".to_string(), 43)).await;
+        assert!(b.sweep().await.is_empty());
+
+        // Crashed: leases expire, the index is left behind
+        drop(a);
+        tokio::time::delay_for(lease * 2).await;
+        assert!(!b.has_session(&session).await);
+        assert!(b.list_sessions().await.get("a").is_none());
+
+        assert_eq!(b.sweep().await, vec![("ws://janus:8188".to_string(), 42)]);
+        assert!(c.sweep().await.is_empty());
+        assert!(c.has_handle(&alive).await);
+    }
+
+    async fn assert_listed_plugins_only(provider: &dyn ProxyStateProvider) {
+        provider.add_token("secret", vec!["janus.plugin.videoroom".to_string()]).await;
+        provider.add_token("other", vec![]).await;
+        assert!(provider.check_token_plugin("secret", "janus.plugin.videoroom").await);
+        assert!(!provider.check_token_plugin("secret", "janus.plugin.echotest").await);
+        assert!(provider.check_token("other").await);
+        assert!(!provider.check_token_plugin("other", "janus.plugin.videoroom").await);
+
+        provider.add_token("other", vec!["janus.plugin.echotest".to_string()]).await;
+        assert!(provider.check_token_plugin("other", "janus.plugin.echotest").await);
+        assert!(!provider.check_token_plugin("unknown", "janus.plugin.echotest").await);
+    }
+
+    #[tokio::test]
+    async fn tokens_allow_listed_plugins_only() {
+        assert_listed_plugins_only(&MemoryStateProvider::new()).await;
+        assert_listed_plugins_only(&provider(&RedisStub::start())).await;
+    }
+
+    #[tokio::test]
+    async fn tokens_are_shared_between_instances() {
+        let redis = RedisStub::start();
+        let (a, b) = (provider(&redis), provider(&redis));
+
+        a.add_token("secret", vec!["janus.plugin.videoroom".to_string()]).await;
+        a.add_token("other", vec![]).await;
+        assert!(b.check_token("secret").await);
+        assert!(b.check_token_plugin("secret", "janus.plugin.videoroom").await);
+        assert!(!b.check_token_plugin("secret", "janus.plugin.echotest").await);
+        assert!(b.check_token("other").await);
+        assert!(!b.check_token_plugin("other", "janus.plugin.videoroom").await);
+
+        let mut tokens = b.list_tokens().await;
+        tokens.sort();
+        assert_eq!(tokens, vec![
+            ("other".to_string(), vec![]),
+            ("secret".to_string(), vec!["janus.plugin.videoroom".to_string()])
+        ]);
+
+        assert!(b.remove_token("secret").await);
+        assert!(!a.check_token("secret").await);
+        assert!(!a.check_token_plugin("secret", "janus.plugin.videoroom").await);
+        assert!(!a.remove_token("secret").await);
+    }
+
+    #[tokio::test]
+    async fn redis_unavailable() {
+        // Nothing listens on port 1
+        let provider = RedisStateProvider::new("redis://127.0.0.1:1/").unwrap();
+        let session = provider.new_session().await.unwrap();
+        assert!(!provider.has_session(&session).await);
+        assert!(!provider.check_token("secret").await);
+        assert!(provider.list_tokens().await.is_empty());
+    }
+}
@@ -0,0 +1,216 @@
+use rand::prelude::*;
+use rand::distributions::uniform::SampleUniform;
+use rand::rngs::StdRng;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/** A healthy backend, as seen by `BackendStrategy::select` */
+pub struct BackendLoad {
+    pub url: String,
+    /** Relative share of new sessions, at least 1 */
+    pub weight: u32,
+    /** Sessions currently opened on it, see `JanusBackendProvider::session_opened` */
+    pub sessions: u64
+}
+
+impl BackendLoad {
+    /** Whether it's less loaded than `other`, relatively to their weights */
+    fn lighter_than(&self, other: &BackendLoad) -> bool {
+        (self.sessions as u128) * (other.weight as u128) < (other.sessions as u128) * (self.weight as u128)
+    }
+}
+
+/** Randomness of a strategy, thread-local unless seeded for a reproducible sequence (tests) */
+#[derive(Default)]
+struct StrategyRng(Option<Mutex<StdRng>>);
+
+impl StrategyRng {
+    fn seeded(seed: u64) -> StrategyRng {
+        StrategyRng(Some(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    fn gen_range<T: SampleUniform>(&self, low: T, high: T) -> T {
+        match &self.0 {
+            Some(x) => x.lock().unwrap().gen_range(low, high),
+            None => thread_rng().gen_range(low, high)
+        }
+    }
+}
+
+/** Backend selection for new sessions */
+pub trait BackendStrategy: Send + Sync {
+    /** Index of the chosen one in `backends`, which is never empty */
+    fn select(&self, backends: &[BackendLoad]) -> usize;
+}
+
+/** Each backend in turn, ignore weights */
+#[derive(Default)]
+pub struct RoundRobinStrategy {
+    next: AtomicUsize
+}
+
+impl BackendStrategy for RoundRobinStrategy {
+    fn select(&self, backends: &[BackendLoad]) -> usize {
+        self.next.fetch_add(1, Ordering::Relaxed) % backends.len()
+    }
+}
+
+/** Random backend, with probability proportional to its weight */
+#[derive(Default)]
+pub struct WeightedRandomStrategy {
+    rng: StrategyRng
+}
+
+impl WeightedRandomStrategy {
+    /** Same choices for the same seed and backends */
+    pub fn seeded(seed: u64) -> WeightedRandomStrategy {
+        WeightedRandomStrategy { rng: StrategyRng::seeded(seed) }
+    }
+}
+
+impl BackendStrategy for WeightedRandomStrategy {
+    fn select(&self, backends: &[BackendLoad]) -> usize {
+        let total = backends.iter().map(|x| x.weight as u64).sum::<u64>();
+        let mut n = self.rng.gen_range(0, total.max(1));
+        for (i, x) in backends.iter().enumerate() {
+            if n < x.weight as u64 {
+                return i
+            }
+            n -= x.weight as u64;
+        }
+        0
+    }
+}
+
+/** Backend with fewest sessions per weight unit */
+#[derive(Default)]
+pub struct LeastSessionsStrategy;
+
+impl BackendStrategy for LeastSessionsStrategy {
+    fn select(&self, backends: &[BackendLoad]) -> usize {
+        let mut best = 0;
+        for (i, x) in backends.iter().enumerate().skip(1) {
+            if x.lighter_than(&backends[best]) {
+                best = i
+            }
+        }
+        best
+    }
+}
+
+/** Lighter of two random backends, close to least sessions without herding on a single one */
+#[derive(Default)]
+pub struct PowerOfTwoStrategy {
+    rng: StrategyRng
+}
+
+impl PowerOfTwoStrategy {
+    /** Same choices for the same seed and backends */
+    pub fn seeded(seed: u64) -> PowerOfTwoStrategy {
+        PowerOfTwoStrategy { rng: StrategyRng::seeded(seed) }
+    }
+}
+
+impl BackendStrategy for PowerOfTwoStrategy {
+    fn select(&self, backends: &[BackendLoad]) -> usize {
+        if backends.len() < 2 {
+            return 0
+        }
+        let a = self.rng.gen_range(0, backends.len());
+        let b = (a + self.rng.gen_range(1, backends.len())) % backends.len();
+        if backends[b].lighter_than(&backends[a]) { b } else { a }
+    }
+}
+
+/** Strategy by configuration name */
+pub fn strategy(name: &str) -> Option<Box<dyn BackendStrategy>> {
+    match name {
+        "round_robin" => Some(Box::new(RoundRobinStrategy::default())),
+        "weighted_random" => Some(Box::new(WeightedRandomStrategy::default())),
+        "least_sessions" => Some(Box::new(LeastSessionsStrategy)),
+        "power_of_two" => Some(Box::new(PowerOfTwoStrategy::default())),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(url: &str, weight: u32, sessions: u64) -> BackendLoad {
+        BackendLoad { url: url.to_string(), weight, sessions }
+    }
+
+    /** How many times each backend is chosen out of `n` */
+    fn distribution(strategy: &dyn BackendStrategy, backends: &[BackendLoad], n: usize) -> Vec<usize> {
+        let mut counts = vec![0; backends.len()];
+        for _ in 0..n {
+            counts[strategy.select(backends)] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let strategy = RoundRobinStrategy::default();
+        let backends = [load("a", 1, 0), load("b", 5, 0), load("c", 1, 100)];
+        let choices = (0..7).map(|_| strategy.select(&backends)).collect::<Vec<_>>();
+        assert_eq!(choices, vec![0, 1, 2, 0, 1, 2, 0]);
+    }
+
+    #[test]
+    fn weighted_random_follows_weights() {
+        let backends = [load("a", 1, 0), load("b", 3, 0), load("c", 0, 0)];
+        let counts = distribution(&WeightedRandomStrategy::seeded(1), &backends, 4000);
+        assert_eq!(counts[2], 0);
+        assert!((900..1100).contains(&counts[0]), "{:?}", counts);
+        assert!((2900..3100).contains(&counts[1]), "{:?}", counts);
+
+        // Reproducible with a seed
+        let a = WeightedRandomStrategy::seeded(7);
+        let b = WeightedRandomStrategy::seeded(7);
+        for _ in 0..100 {
+            assert_eq!(a.select(&backends), b.select(&backends));
+        }
+    }
+
+    #[test]
+    fn least_sessions_per_weight() {
+        let strategy = LeastSessionsStrategy;
+        assert_eq!(strategy.select(&[load("a", 1, 3), load("b", 1, 2), load("c", 1, 4)]), 1);
+        // Twice the weight, twice the sessions
+        assert_eq!(strategy.select(&[load("a", 1, 3), load("b", 2, 5)]), 1);
+        assert_eq!(strategy.select(&[load("a", 1, 2), load("b", 2, 5)]), 0);
+        // Ties go to the first one
+        assert_eq!(strategy.select(&[load("a", 1, 2), load("b", 1, 2), load("c", 2, 4)]), 0);
+        assert_eq!(strategy.select(&[load("a", 1, 3), load("b", 1, 2), load("c", 1, 2)]), 1);
+    }
+
+    #[test]
+    fn power_of_two_picks_lighter() {
+        let strategy = PowerOfTwoStrategy::seeded(1);
+        assert_eq!(strategy.select(&[load("a", 1, 0)]), 0);
+
+        // Whatever pair is drawn, the lighter one wins
+        let backends = [load("a", 1, 10), load("b", 1, 0)];
+        assert_eq!(distribution(&strategy, &backends, 100), vec![0, 100]);
+
+        // The heaviest one is never chosen, the lightest one is whenever drawn (2 pairs out of 3)
+        let backends = [load("a", 1, 10), load("b", 1, 5), load("c", 1, 0)];
+        let counts = distribution(&strategy, &backends, 3000);
+        assert_eq!(counts[0], 0);
+        assert!((1900..2100).contains(&counts[2]), "{:?}", counts);
+    }
+
+    #[test]
+    fn strategy_by_name() {
+        let backends = [load("a", 1, 1), load("b", 1, 0)];
+        for name in ["round_robin", "weighted_random", "least_sessions", "power_of_two"].iter() {
+            let strategy = strategy(name).unwrap();
+            assert!(strategy.select(&backends) < 2, "{}", name);
+        }
+        assert_eq!(strategy("least_sessions").unwrap().select(&backends), 1);
+        assert!(strategy("fastest").is_none());
+        assert!(strategy("").is_none());
+    }
+}
@@ -0,0 +1,99 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tracing::{info, warn};
+use super::{JanusProxy, ProxyState};
+use super::core::apierror::*;
+use super::core::request::IncomingRequestParameters;
+
+impl JanusProxy {
+    /** Reclaim leases of proxy instances gone without shutdown, see `ProxyStateProvider::sweep`. Only one per instance */
+    pub(crate) fn start_sweeper(janus: &Arc<JanusProxy>) {
+        if janus.sweep_interval.as_secs() == 0 || janus.sweeper_started.swap(true, Ordering::SeqCst) {
+            return
+        }
+
+        let janus = Arc::downgrade(janus);
+        tokio::spawn(async move {
+            loop {
+                let janus = match janus.upgrade() {
+                    None => break,
+                    Some(x) => x
+                };
+                for (url, session) in janus.state.sweep().await.into_iter() {
+                    // Counted by the instance which opened it, which is gone
+                    janus.backend.session_closed(&url).await;
+                    if let Err(e) = janus.destroy_backend_session(&url, session).await {
+                        warn!(url = %url, backend_session_id = session, reason = %e.reason, "Could not destroy janus-gateway session of expired lease");
+                    }
+                }
+
+                tokio::select! {
+                    _ = tokio::time::delay_for(janus.sweep_interval) => {},
+                    _ = janus.wait_for(ProxyState::Stopped) => break
+                }
+            }
+        });
+    }
+
+    /** Destroy a janus-gateway session left by another instance, its handles go with it */
+    async fn destroy_backend_session(&self, url: &str, session: u64) -> Result<(), JanusError> {
+        let secret = self.backend_secrets.get(url).cloned();
+        let gateway = self.gateways.get(url, secret, &self.transaction_ids).await?;
+
+        let mut request = IncomingRequestParameters::prepare("destroy".to_string(), None, None);
+        request.session_id = session;
+        let response = gateway.send(request, false).await?;
+        match response.error {
+            // Timed out on janus-gateway already
+            Some(e) if e.code != JANUS_ERROR_SESSION_NOT_FOUND => Err(e),
+            _ => {
+                info!(url, backend_session_id = session, "Destroyed janus-gateway session of expired lease");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::time::Duration;
+    use crate::janus::plugin::JanusPluginProvider;
+    use crate::janus::provider::*;
+    use crate::janus::provider::redis_stub::RedisStub;
+    use crate::janus::testing::FakeJanus;
+
+    fn backend_provider(redis: &RedisStub) -> RedisBackendProvider {
+        RedisBackendProvider::new(&redis.url).unwrap().with_strategy(Box::new(LeastSessionsStrategy))
+    }
+
+    #[tokio::test]
+    async fn sessions_of_expired_leases_are_uncounted() {
+        let redis = RedisStub::start();
+        let (a, b) = (FakeJanus::start().await, "ws://localhost:1");
+        let lease = Duration::from_millis(300);
+        let backend = backend_provider(&redis);
+        backend.update_backend(a.url.clone(), true).await;
+        backend.update_backend(b.to_string(), true).await;
+
+        // Handle of an instance which crashes with its janus-gateway session open
+        let crashed = RedisStateProvider::new(&redis.url).unwrap().with_lease(lease);
+        let handle = crashed.new_handle().await.unwrap();
+        crashed.set_handle_backend(&handle, (a.url.clone(), 42)).await;
+        backend.session_opened(&a.url).await;
+        drop(crashed);
+        assert_eq!(backend.get_backend().await, Some(b.to_string()));
+        tokio::time::delay_for(lease * 2).await;
+
+        let janus = Arc::new(JanusProxy::new(
+            JanusPluginProvider::default(),
+            Arc::new(Box::new(RedisStateProvider::new(&redis.url).unwrap().with_lease(lease))),
+            Arc::new(Box::new(backend_provider(&redis)))
+        ).with_sweep_interval(Duration::from_secs(60)));
+        JanusProxy::start_sweeper(&janus);
+        tokio::time::delay_for(Duration::from_millis(300)).await;
+
+        assert_eq!(a.requests("destroy"), 1);
+        assert_eq!(backend.get_backend().await, Some(a.url.clone()));
+    }
+}
@@ -0,0 +1,303 @@
+/**
+ * Shared fixtures for tests: a proxy with in-memory state talking to whatever backends are given,
+ * a Janus API client over websocket, and a fake janus-gateway.
+ */
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use super::JanusProxy;
+use super::connection::{accept_ws, new_backend_connection, JANUS_PROTOCOL};
+use super::plugin::JanusPluginProvider;
+use super::provider::{MemoryStateProvider, MemoryBackendProvider, JanusBackendProvider};
+
+/** Messages taking longer than that are considered missing */
+static RECEIVE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/** Proxy with in-memory state, every backend of `backends` is up */
+pub(crate) async fn proxy(backends: &[&str]) -> JanusProxy {
+    let backend = MemoryBackendProvider::new();
+    for x in backends.iter() {
+        backend.update_backend(x.to_string(), true).await;
+    }
+    JanusProxy::new(
+        JanusPluginProvider::default(),
+        Arc::new(Box::new(MemoryStateProvider::new())),
+        Arc::new(Box::new(backend))
+    )
+}
+
+/** Serve `janus` over websocket on a random local port, return its url */
+pub(crate) async fn listen(janus: Arc<JanusProxy>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(JanusProxy::listen(janus, listener));
+    url
+}
+
+/** Serve Admin API of `janus` over websocket on a random local port, return its url */
+pub(crate) async fn listen_admin(janus: Arc<JanusProxy>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(JanusProxy::listen_admin(janus, listener));
+    url
+}
+
+/** Janus API client, events received while waiting for a response are kept for `event` */
+pub(crate) struct WsClient {
+    ws: WebSocketStream<TcpStream>,
+    events: VecDeque<Value>
+}
+
+impl WsClient {
+    pub(crate) async fn connect(url: &str) -> WsClient {
+        WsClient {
+            ws: new_backend_connection(url, JANUS_PROTOCOL).await.unwrap(),
+            events: VecDeque::new()
+        }
+    }
+
+    /** Next message, None if the connection is closed or nothing came in time */
+    async fn receive(&mut self) -> Option<Value> {
+        loop {
+            match tokio::time::timeout(RECEIVE_TIMEOUT, self.ws.next()).await {
+                Ok(Some(Ok(Message::Text(x)))) => return Some(serde_json::from_str(&x).unwrap()),
+                Ok(Some(Ok(Message::Close(_)))) | Ok(Some(Err(_))) | Ok(None) | Err(_) => return None,
+                Ok(Some(Ok(_))) => continue
+            }
+        }
+    }
+
+    /** Send `request`, return the first message with its transaction */
+    pub(crate) async fn request(&mut self, request: Value) -> Value {
+        let transaction = request["transaction"].clone();
+        self.ws.send(Message::Text(request.to_string())).await.unwrap();
+        loop {
+            match self.receive().await {
+                Some(x) if x["transaction"] == transaction => return x,
+                Some(x) => self.events.push_back(x),
+                None => panic!("No response to {}", request)
+            }
+        }
+    }
+
+    /** Next message which isn't a response, None if the connection is closed or nothing came in time */
+    pub(crate) async fn event(&mut self) -> Option<Value> {
+        match self.events.pop_front() {
+            Some(x) => Some(x),
+            None => self.receive().await
+        }
+    }
+
+    /** Create a session, return its id */
+    pub(crate) async fn create(&mut self) -> u64 {
+        let response = self.request(serde_json::json!({"janus": "create", "transaction": "create"})).await;
+        response["data"]["id"].as_u64().unwrap()
+    }
+
+    /** Attach `plugin` to `session`, return the handle id */
+    pub(crate) async fn attach(&mut self, session: u64, plugin: &str) -> u64 {
+        let request = serde_json::json!({"janus": "attach", "session_id": session, "plugin": plugin, "transaction": "attach"});
+        let response = self.request(request).await;
+        response["data"]["id"].as_u64().unwrap()
+    }
+
+    /** Create a VideoRoom room and join it with `handle`, so that it's got a janus-gateway handle */
+    pub(crate) async fn join(&mut self, session: u64, handle: u64) {
+        let message = |body: Value| json!({"janus": "message", "session_id": session, "handle_id": handle, "transaction": "join", "body": body});
+        let response = self.request(message(json!({"request": "create"}))).await;
+        let room = response["plugindata"]["data"]["room"].clone();
+        self.request(message(json!({"request": "join", "room": room, "ptype": "publisher"}))).await;
+        assert_eq!(self.event().await.unwrap()["plugindata"]["data"]["videoroom"], "joined");
+    }
+}
+
+/** VideoRoom requests janus-gateway answers right away, others get "ack" then an event */
+static SYNCHRONOUS_REQUESTS: [&str; 8] = ["create", "destroy", "edit", "exists", "list", "listparticipants", "allowed", "kick"];
+
+#[derive(Default)]
+struct FakeJanusState {
+    next_id: u64,
+    /** Handles of each session */
+    sessions: HashMap<u64, Vec<u64>>,
+    /** Requests received so far, by `janus` */
+    requests: HashMap<String, usize>,
+    /** Requests answered with an error that many more times, by `janus` */
+    failures: HashMap<String, usize>,
+    /** Requests never answered, by `janus` */
+    stalled: HashSet<String>,
+    /** Open connections, by id */
+    connections: Vec<(u64, mpsc::UnboundedSender<Message>)>
+}
+
+/** janus-gateway speaking just enough Janus API over websocket, every connection shares its sessions */
+pub(crate) struct FakeJanus {
+    pub(crate) url: String,
+    state: Arc<Mutex<FakeJanusState>>
+}
+
+impl FakeJanus {
+    pub(crate) async fn start() -> FakeJanus {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(FakeJanusState::default()));
+
+        let state_ref = Arc::clone(&state);
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = Arc::clone(&state_ref);
+                tokio::spawn(async move {
+                    let mut ws = match accept_ws(stream, JANUS_PROTOCOL).await {
+                        Ok(x) => x,
+                        Err(_) => return
+                    };
+                    let (tx, mut rx) = mpsc::unbounded_channel();
+                    let id = {
+                        let mut state = state.lock().unwrap();
+                        state.next_id += 1;
+                        let id = state.next_id;
+                        state.connections.push((id, tx));
+                        id
+                    };
+                    loop {
+                        tokio::select! {
+                            x = ws.next() => match x {
+                                Some(Ok(Message::Text(x))) => for reply in Self::reply(&state, serde_json::from_str(&x).unwrap()) {
+                                    let _ = ws.send(Message::Text(reply.to_string())).await;
+                                },
+                                Some(Ok(_)) => {},
+                                _ => break
+                            },
+                            // Gone with `disconnect`
+                            x = rx.recv() => match x {
+                                Some(x) => if ws.send(x).await.is_err() { break },
+                                None => break
+                            }
+                        }
+                    }
+                    state.lock().unwrap().connections.retain(|(x, _)| *x != id);
+                });
+            }
+        });
+        FakeJanus { url, state }
+    }
+
+    fn reply(state: &Mutex<FakeJanusState>, request: Value) -> Vec<Value> {
+        let mut state = state.lock().unwrap();
+        let janus = request["janus"].as_str().unwrap_or_default().to_string();
+        let transaction = request["transaction"].clone();
+        let session = request["session_id"].as_u64().unwrap_or(0);
+        let handle = request["handle_id"].as_u64().unwrap_or(0);
+        *state.requests.entry(janus.clone()).or_default() += 1;
+
+        if state.stalled.contains(&janus) {
+            return Vec::new()
+        }
+        if let Some(x) = state.failures.get_mut(&janus).filter(|x| **x > 0) {
+            *x -= 1;
+            return vec![json!({"janus": "error", "transaction": transaction, "error": {"code": 490, "reason": "Injected failure"}})]
+        }
+        if session != 0 && !state.sessions.contains_key(&session) {
+            return vec![json!({"janus": "error", "transaction": transaction, "error": {"code": 458, "reason": "No such session"}})]
+        }
+
+        let success = json!({"janus": "success", "session_id": session, "transaction": transaction});
+        match &janus[..] {
+            "info" => vec![json!({
+                "janus": "server_info", "transaction": transaction,
+                "name": "Janus WebRTC Server", "version": 1005, "version_string": "0.10.5",
+                "plugins": {"janus.plugin.videoroom": {"name": "JANUS VideoRoom plugin"}}
+            })],
+            "create" | "attach" => {
+                state.next_id += 1;
+                let id = state.next_id;
+                if janus == "create" {
+                    state.sessions.insert(id, Vec::new());
+                } else {
+                    state.sessions.get_mut(&session).unwrap().push(id);
+                }
+                let mut success = success;
+                success["data"] = json!({"id": id});
+                vec![success]
+            },
+            "detach" => {
+                state.sessions.get_mut(&session).unwrap().retain(|x| *x != handle);
+                vec![success]
+            },
+            "destroy" => {
+                state.sessions.remove(&session);
+                vec![success]
+            },
+            "keepalive" | "trickle" => vec![json!({"janus": "ack", "session_id": session, "transaction": transaction})],
+            "message" => {
+                let body = &request["body"];
+                let name = body["request"].as_str().unwrap_or_default();
+                let data = match name {
+                    "join" => json!({"videoroom": "joined", "room": body["room"]}),
+                    _ => json!({"videoroom": "event", "request": name, "room": body["room"]})
+                };
+                let plugindata = json!({"plugin": "janus.plugin.videoroom", "data": data});
+                if SYNCHRONOUS_REQUESTS.contains(&name) {
+                    return vec![json!({
+                        "janus": "success", "session_id": session, "sender": handle, "transaction": transaction, "plugindata": plugindata
+                    })]
+                }
+                vec![
+                    json!({"janus": "ack", "session_id": session, "transaction": transaction}),
+                    json!({"janus": "event", "session_id": session, "sender": handle, "transaction": transaction, "plugindata": plugindata})
+                ]
+            },
+            // Admin API
+            "handle_info" => {
+                let mut success = success;
+                success["handle_id"] = json!(handle);
+                success["info"] = json!({"session_id": session, "handle_id": handle, "plugin": "janus.plugin.videoroom"});
+                vec![success]
+            },
+            x if x.starts_with("set_") => vec![success],
+            _ => vec![json!({"janus": "error", "transaction": transaction, "error": {"code": 453, "reason": "Unknown request"}})]
+        }
+    }
+
+    /** Answer the next `times` `janus` requests with an error */
+    pub(crate) fn fail(&self, janus: &str, times: usize) {
+        self.state.lock().unwrap().failures.insert(janus.to_string(), times);
+    }
+
+    /** Never answer `janus` requests from now on */
+    pub(crate) fn stall(&self, janus: &str) {
+        self.state.lock().unwrap().stalled.insert(janus.to_string());
+    }
+
+    /** How many `janus` requests came in */
+    pub(crate) fn requests(&self, janus: &str) -> usize {
+        self.state.lock().unwrap().requests.get(janus).cloned().unwrap_or(0)
+    }
+
+    /** Sessions alive */
+    pub(crate) fn sessions(&self) -> usize {
+        self.state.lock().unwrap().sessions.len()
+    }
+
+    /** Websocket connections open */
+    pub(crate) fn connections(&self) -> usize {
+        self.state.lock().unwrap().connections.len()
+    }
+
+    /** Send `event` on every connection */
+    pub(crate) fn push(&self, event: Value) {
+        for (_, x) in self.state.lock().unwrap().connections.iter() {
+            let _ = x.send(Message::Text(event.to_string()));
+        }
+    }
+
+    /** Drop every connection, sessions are kept like janus-gateway does until they time out */
+    pub(crate) fn disconnect(&self) {
+        self.state.lock().unwrap().connections.clear();
+    }
+}
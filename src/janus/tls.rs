@@ -0,0 +1,362 @@
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+use tokio_rustls::rustls::{Certificate, PrivateKey, ServerConfig, NoClientAuth, SignatureScheme};
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::sign;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::webpki;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::SystemTime;
+use tracing::{info, warn};
+
+/** Identify a file version, so that a rewritten certificate is picked up */
+type FileStamp = (Option<SystemTime>, u64);
+
+/** Signature schemes a key is tried with to tell whether it belongs to a certificate */
+static PAIR_CHECKS: [(SignatureScheme, &webpki::SignatureAlgorithm); 4] = [
+    (SignatureScheme::ECDSA_NISTP256_SHA256, &webpki::ECDSA_P256_SHA256),
+    (SignatureScheme::ECDSA_NISTP384_SHA384, &webpki::ECDSA_P384_SHA384),
+    (SignatureScheme::ED25519, &webpki::ED25519),
+    (SignatureScheme::RSA_PKCS1_SHA256, &webpki::RSA_PKCS1_2048_8192_SHA256)
+];
+
+fn stamp(path: &Path) -> FileStamp {
+    match fs::metadata(path) {
+        Ok(x) => (x.modified().ok(), x.len()),
+        Err(_) => (None, 0)
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn open(path: &Path) -> io::Result<BufReader<File>> {
+    match File::open(path) {
+        Ok(x) => Ok(BufReader::new(x)),
+        Err(e) => Err(io::Error::new(e.kind(), format!("Could not open \"{}\": {}", path.display(), e)))
+    }
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = open(path)?;
+    match pemfile::certs(&mut reader) {
+        Ok(x) if !x.is_empty() => Ok(x),
+        _ => Err(invalid_data(format!("No PEM certificate found in \"{}\"", path.display())))
+    }
+}
+
+fn load_key(path: &Path) -> io::Result<PrivateKey> {
+    // Either "PRIVATE KEY" (PKCS#8) or "RSA PRIVATE KEY" (PKCS#1)
+    let mut reader = open(path)?;
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader).unwrap_or_default();
+    if keys.is_empty() {
+        let mut reader = open(path)?;
+        keys = pemfile::rsa_private_keys(&mut reader).unwrap_or_default();
+    }
+    match keys.into_iter().next() {
+        Some(x) => Ok(x),
+        None => Err(invalid_data(format!("No PEM private key found in \"{}\"", path.display())))
+    }
+}
+
+/** Whether `key` belongs to `cert`: something signed with the former is verified with the latter */
+fn is_pair(cert: &Certificate, key: &PrivateKey) -> bool {
+    let (key, cert) = match (sign::any_supported_type(key), webpki::EndEntityCert::from(&cert.0)) {
+        (Ok(key), Ok(cert)) => (key, cert),
+        _ => return false
+    };
+    let message = b"janus-proxy";
+    PAIR_CHECKS.iter().any(|(scheme, algorithm)| {
+        match key.choose_scheme(&[*scheme]).map(|x| x.sign(message)) {
+            Some(Ok(signature)) => cert.verify_signature(algorithm, message, &signature).is_ok(),
+            _ => false
+        }
+    })
+}
+
+/**
+ * TLS termination for client listener, from PEM certificate chain and private key.
+ * Files are checked periodically once `watch`-ed, and reloaded once they change.
+ * A broken update (including a certificate and a key which don't match) is reported and the previous pair is kept in use.
+ */
+pub struct TlsAcceptor {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    /** Stamps of certificate and key files when loaded by `new` */
+    stamps: (FileStamp, FileStamp),
+    reload_interval: Duration,
+    watch_started: AtomicBool,
+    current: Mutex<tokio_rustls::TlsAcceptor>
+}
+
+impl TlsAcceptor {
+    pub fn new<P: Into<PathBuf>>(cert_path: P, key_path: P) -> io::Result<TlsAcceptor> {
+        let cert_path = cert_path.into();
+        let key_path = key_path.into();
+        let stamps = (stamp(&cert_path), stamp(&key_path));
+        let acceptor = Self::load(&cert_path, &key_path)?;
+        Ok(TlsAcceptor {
+            cert_path, key_path, stamps,
+            reload_interval: Duration::from_secs(5),
+            watch_started: AtomicBool::new(false),
+            current: Mutex::new(acceptor)
+        })
+    }
+
+    /** Check certificate files for changes that often, 5 seconds by default */
+    pub fn with_reload_interval(mut self, interval: Duration) -> TlsAcceptor {
+        self.reload_interval = interval;
+        self
+    }
+
+    /** Read certificate and key at once, and make sure they go together */
+    fn load(cert_path: &Path, key_path: &Path) -> io::Result<tokio_rustls::TlsAcceptor> {
+        let certs = load_certs(cert_path)?;
+        let key = load_key(key_path)?;
+        if !is_pair(&certs[0], &key) {
+            return Err(invalid_data(format!(
+                "Private key \"{}\" doesn't match certificate \"{}\"", key_path.display(), cert_path.display()
+            )))
+        }
+
+        let mut config = ServerConfig::new(NoClientAuth::new());
+        if let Err(e) = config.set_single_cert(certs, key) {
+            return Err(invalid_data(format!("Invalid certificate/key pair: {}", e)))
+        }
+        Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
+    }
+
+    /** Reload certificate and key every `reload_interval` if either has changed on disk. Only once per acceptor */
+    pub(crate) fn watch(acceptor: &Arc<TlsAcceptor>) {
+        if acceptor.watch_started.swap(true, Ordering::SeqCst) {
+            return
+        }
+
+        let mut loaded = acceptor.stamps;
+        let interval = acceptor.reload_interval;
+        let acceptor = Arc::downgrade(acceptor);
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(interval).await;
+                let acceptor = match acceptor.upgrade() {
+                    None => break,
+                    Some(x) => x
+                };
+                // File system access blocks
+                loaded = match tokio::task::spawn_blocking(move || acceptor.reload(loaded)).await {
+                    Ok(x) => x,
+                    Err(_) => break
+                };
+            }
+        });
+    }
+
+    /** Reload the pair if files differ from `loaded` stamps, return stamps of the files now */
+    fn reload(&self, loaded: (FileStamp, FileStamp)) -> (FileStamp, FileStamp) {
+        let stamps = (stamp(&self.cert_path), stamp(&self.key_path));
+        if stamps != loaded {
+            match Self::load(&self.cert_path, &self.key_path) {
+                Ok(x) => {
+                    info!(cert = %self.cert_path.display(), "TLS certificate reloaded");
+                    *self.current.lock().unwrap() = x;
+                },
+                Err(e) => warn!(error = %e, "Could not reload TLS certificate, keep the previous one")
+            }
+        }
+        stamps
+    }
+
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream<TcpStream>> {
+        let acceptor = self.current.lock().unwrap().clone();
+        acceptor.accept(stream).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::{TlsConnector, webpki::DNSNameRef};
+    use tokio_rustls::rustls::ClientConfig;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::tungstenite::handshake::client::Request;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::janus::testing;
+
+    /** Self-signed certificate for "localhost" as (cert PEM, key PEM) */
+    fn self_signed() -> (String, String) {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        (cert.serialize_pem().unwrap(), cert.serialize_private_key_pem())
+    }
+
+    /** Directory removed with its content once dropped, even if the test fails */
+    struct TempDir(PathBuf);
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn temp_dir() -> TempDir {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "janus-proxy-tls-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    fn write_pair(dir: &Path, cert: &str, key: &str) -> (PathBuf, PathBuf) {
+        let cert_path = dir.join("cert.pem");
+        let key_path = dir.join("key.pem");
+        fs::write(&cert_path, cert).unwrap();
+        fs::write(&key_path, key).unwrap();
+        (cert_path, key_path)
+    }
+
+    fn connector(cert: &str) -> TlsConnector {
+        let mut config = ClientConfig::new();
+        config.root_store.add_pem_file(&mut cert.as_bytes()).unwrap();
+        TlsConnector::from(Arc::new(config))
+    }
+
+    /** Accept one connection and echo a single read back */
+    async fn echo_once(acceptor: Arc<TlsAcceptor>) -> u16 {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok(mut stream) = acceptor.accept(stream).await {
+                let mut buf = [0u8; 64];
+                let n = stream.read(&mut buf).await.unwrap();
+                stream.write_all(&buf[..n]).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+        port
+    }
+
+    async fn echo(port: u16, connector: &TlsConnector) -> io::Result<Vec<u8>> {
+        let stream = TcpStream::connect(("127.0.0.1", port)).await?;
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let mut stream = connector.connect(domain, stream).await?;
+        stream.write_all(b"hello").await?;
+        let mut buf = vec![0u8; 5];
+        stream.read_exact(&mut buf).await?;
+        Ok(buf)
+    }
+
+    #[tokio::test]
+    async fn handshake_with_self_signed_cert() {
+        let (cert, key) = self_signed();
+        let dir = temp_dir();
+        let (cert_path, key_path) = write_pair(&dir.0, &cert, &key);
+        let acceptor = Arc::new(TlsAcceptor::new(cert_path, key_path).unwrap());
+
+        let port = echo_once(acceptor).await;
+        assert_eq!(echo(port, &connector(&cert)).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn client_rejects_untrusted_server_cert() {
+        let (cert, key) = self_signed();
+        let (other, _) = self_signed();
+        let dir = temp_dir();
+        let (cert_path, key_path) = write_pair(&dir.0, &cert, &key);
+        let acceptor = Arc::new(TlsAcceptor::new(cert_path, key_path).unwrap());
+
+        let port = echo_once(acceptor).await;
+        assert!(echo(port, &connector(&other)).await.is_err());
+    }
+
+    #[test]
+    fn invalid_pem() {
+        let dir = temp_dir();
+        let (cert, key) = self_signed();
+
+        let (cert_path, key_path) = write_pair(&dir.0, "not a certificate", &key);
+        assert_eq!(TlsAcceptor::new(cert_path, key_path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (cert_path, key_path) = write_pair(&dir.0, &cert, "not a key");
+        assert_eq!(TlsAcceptor::new(cert_path, key_path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        let (other_cert, _) = self_signed();
+        let (cert_path, key_path) = write_pair(&dir.0, &other_cert, &key);
+        assert_eq!(TlsAcceptor::new(cert_path, key_path).err().unwrap().kind(), io::ErrorKind::InvalidData);
+
+        assert!(TlsAcceptor::new(dir.0.join("missing.pem"), dir.0.join("key.pem")).is_err());
+    }
+
+    #[tokio::test]
+    async fn reload_on_change() {
+        let dir = temp_dir();
+        let (old_cert, old_key) = self_signed();
+        let (cert_path, key_path) = write_pair(&dir.0, &old_cert, &old_key);
+        let acceptor = TlsAcceptor::new(cert_path, key_path).unwrap().with_reload_interval(Duration::from_millis(50));
+        let acceptor = Arc::new(acceptor);
+        TlsAcceptor::watch(&acceptor);
+
+        let (new_cert, new_key) = self_signed();
+        write_pair(&dir.0, &new_cert, &new_key);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let port = echo_once(Arc::clone(&acceptor)).await;
+        assert_eq!(echo(port, &connector(&new_cert)).await.unwrap(), b"hello");
+
+        // A broken update keeps the current certificate
+        write_pair(&dir.0, "garbage", "garbage");
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let port = echo_once(Arc::clone(&acceptor)).await;
+        assert_eq!(echo(port, &connector(&new_cert)).await.unwrap(), b"hello");
+
+        // So does a certificate which doesn't go with the key, e.g. the key isn't written yet
+        let (next_cert, next_key) = self_signed();
+        write_pair(&dir.0, &next_cert, &new_key);
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let port = echo_once(Arc::clone(&acceptor)).await;
+        assert_eq!(echo(port, &connector(&new_cert)).await.unwrap(), b"hello");
+
+        fs::write(dir.0.join("key.pem"), next_key).unwrap();
+        tokio::time::delay_for(Duration::from_millis(200)).await;
+        let port = echo_once(acceptor).await;
+        assert_eq!(echo(port, &connector(&next_cert)).await.unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn serve_janus_over_wss() {
+        let (cert, key) = self_signed();
+        let dir = temp_dir();
+        let (cert_path, key_path) = write_pair(&dir.0, &cert, &key);
+
+        let janus = testing::proxy(&[]).await.with_tls(TlsAcceptor::new(cert_path, key_path).unwrap());
+        // Served over TLS despite the scheme
+        let url = testing::listen(Arc::new(janus)).await;
+        let port = url.rsplit(':').next().unwrap().parse::<u16>().unwrap();
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let domain = DNSNameRef::try_from_ascii_str("localhost").unwrap();
+        let stream = connector(&cert).connect(domain, stream).await.unwrap();
+
+        let request = Request::builder()
+            .uri(format!("wss://localhost:{}", port))
+            .header("Sec-WebSocket-Protocol", "janus-protocol")
+            .body(())
+            .unwrap();
+        let (mut ws, _) = tokio_tungstenite::client_async(request, stream).await.unwrap();
+
+        ws.send(Message::Text(r#"{"janus":"ping","transaction":"t"}"#.to_string())).await.unwrap();
+        let response = match ws.next().await {
+            Some(Ok(Message::Text(x))) => serde_json::from_str::<serde_json::Value>(&x).unwrap(),
+            x => panic!("Unexpected message: {:?}", x)
+        };
+        assert_eq!(response["janus"], "pong");
+        assert_eq!(response["transaction"], "t");
+    }
+}
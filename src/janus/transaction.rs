@@ -0,0 +1,176 @@
+use tokio::sync::oneshot;
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::{Arc, Mutex};
+use super::core::apierror::*;
+use super::core::response::JanusResponse;
+use super::provider::{IdGenerator, MAX_ATTEMPTS};
+
+struct PendingTransaction {
+    callback: oneshot::Sender<JanusResponse>,
+    /** May or may not ignore "ack" response */
+    asynchronous: bool,
+    /** Transaction of the client request it's been sent on behalf of, empty for proxy's own */
+    client: String
+}
+
+/**
+ * Transactions of requests in flight on a janus-gateway connection.
+ * Clients choose transactions freely and many of them share a connection (see `JanusGatewayPool`),
+ * so each request gets its own backend transaction, mapped back to the client one on response.
+ */
+pub(crate) struct TransactionManager {
+    ids: Arc<Box<dyn IdGenerator>>,
+    pending: Mutex<HashMap<String, PendingTransaction>>
+}
+
+impl TransactionManager {
+    pub(crate) fn new(ids: Arc<Box<dyn IdGenerator>>) -> TransactionManager {
+        TransactionManager {
+            ids,
+            pending: Mutex::new(HashMap::new())
+        }
+    }
+
+    /** Backend transaction unique among pending ones for a request on behalf of `client`, resolved by `complete` */
+    pub(crate) fn begin(&self, client: String, asynchronous: bool) -> Result<(String, oneshot::Receiver<JanusResponse>), JanusError> {
+        let mut pending = self.pending.lock().unwrap();
+        for _ in 0..MAX_ATTEMPTS {
+            // In use by another request: draw again rather than overwrite it
+            if let Entry::Vacant(x) = pending.entry(self.ids.next_id().to_string()) {
+                let transaction = x.key().clone();
+                let (tx, rx) = oneshot::channel::<JanusResponse>();
+                x.insert(PendingTransaction { callback: tx, asynchronous, client });
+                return Ok((transaction, rx))
+            }
+        }
+        Err(JanusError::new(JANUS_ERROR_GATEWAY_INTERNAL_ERROR, String::from("Could not allocate a unique janus-gateway transaction")))
+    }
+
+    /**
+     * Deliver `response` to its request, with the client transaction back. "ack" of asynchronous requests is dropped.
+     * Return it as is if it's not a response to a pending request, i.e. an event.
+     */
+    pub(crate) fn complete(&self, mut response: JanusResponse) -> Option<JanusResponse> {
+        let mut pending = self.pending.lock().unwrap();
+        let request = match pending.get(&response.transaction) {
+            Some(x) if x.asynchronous && response.janus == "ack" => return None,
+            Some(_) => pending.remove(&response.transaction).unwrap(),
+            None => return Some(response)
+        };
+        drop(pending);
+
+        response.transaction = request.client;
+        // Requester is gone (e.g. timed out) otherwise, nothing to do
+        let _ = request.callback.send(response);
+        None
+    }
+
+    /** Forget a request which won't be answered, e.g. timed out */
+    pub(crate) fn cancel(&self, transaction: &str) {
+        self.pending.lock().unwrap().remove(transaction);
+    }
+
+    /** Drop every pending request, requesters get an error */
+    pub(crate) fn clear(&self) {
+        self.pending.lock().unwrap().clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{SinkExt, StreamExt};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+    use crate::janus::connection::{accept_ws, JANUS_PROTOCOL};
+    use crate::janus::core::request::IncomingRequestParameters;
+    use crate::janus::gateway::JanusGateway;
+    use crate::janus::provider::ScriptedIdGenerator;
+
+    fn sequence(ids: &[u64]) -> Arc<Box<dyn IdGenerator>> {
+        Arc::new(Box::new(ScriptedIdGenerator::new(ids)))
+    }
+
+    fn response(janus: &'static str, transaction: &str) -> JanusResponse {
+        JanusResponse::new(janus, 0, transaction.to_string())
+    }
+
+    #[tokio::test]
+    async fn collisions_are_rekeyed() {
+        let transactions = TransactionManager::new(sequence(&[7, 7, 7, 8]));
+        let (a, rx_a) = transactions.begin("client".to_string(), false).unwrap();
+        let (b, rx_b) = transactions.begin("client".to_string(), true).unwrap();
+        assert_eq!((&a[..], &b[..]), ("7", "8"));
+
+        // Responses in any order reach their own request, with the client transaction
+        assert!(transactions.complete(response("ack", &b)).is_none());
+        assert!(transactions.complete(response("event", &b)).is_none());
+        assert!(transactions.complete(response("success", &a)).is_none());
+        let (a, b) = (rx_a.await.unwrap(), rx_b.await.unwrap());
+        assert_eq!((&a.janus[..], &a.transaction[..]), ("success", "client"));
+        assert_eq!((&b.janus[..], &b.transaction[..]), ("event", "client"));
+
+        // Not pending anymore, so it's an event
+        assert!(transactions.complete(response("event", "7")).is_some());
+    }
+
+    #[tokio::test]
+    async fn duplicates_are_rejected_when_no_id_is_left() {
+        let transactions = TransactionManager::new(sequence(&[1]));
+        let (first, _rx) = transactions.begin(String::new(), false).unwrap();
+        assert_eq!(first, "1");
+        assert!(transactions.begin(String::new(), false).is_err());
+
+        transactions.cancel(&first);
+        assert!(transactions.begin(String::new(), false).is_ok());
+    }
+
+    /** janus-gateway answering `count` requests in reverse order, echoing `id` */
+    async fn reverse_janus(count: usize) -> String {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut ws = accept_ws(stream, JANUS_PROTOCOL).await.unwrap();
+            let mut requests = Vec::new();
+            while requests.len() < count {
+                if let Some(Ok(Message::Text(x))) = ws.next().await {
+                    requests.push(serde_json::from_str::<serde_json::Value>(&x).unwrap());
+                }
+            }
+            for request in requests.iter().rev() {
+                let text = serde_json::json!({
+                    "janus": "success",
+                    "transaction": request["transaction"],
+                    "data": { "id": request["id"] }
+                }).to_string();
+                ws.send(Message::Text(text)).await.unwrap();
+            }
+            // Keep the connection until the test is done
+            while ws.next().await.is_some() {}
+        });
+        url
+    }
+
+    #[tokio::test]
+    async fn responses_are_routed_under_forced_collisions() {
+        let url = reverse_janus(3).await;
+        let gateway = JanusGateway::connect(url, None, sequence(&[5, 5, 5, 5, 6, 5, 6, 9])).await.unwrap();
+
+        // Same client transaction on each, as if from different clients
+        let requests = (1..=3).map(|id| {
+            let mut request = IncomingRequestParameters::prepare("info".to_string(), None, None);
+            request.transaction = "same".to_string();
+            request.id = id;
+            gateway.send(request, false)
+        });
+        let responses = futures::future::join_all(requests).await;
+
+        for (id, response) in (1..=3).zip(responses) {
+            let response = response.unwrap();
+            assert_eq!(response.transaction, "same");
+            assert_eq!(response.data.unwrap()["id"], id);
+        }
+    }
+}
@@ -0,0 +1,238 @@
+use futures::{StreamExt, SinkExt};
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, RwLock};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::{Message, Error};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, info, warn, error, info_span, Span, Instrument};
+use super::{JanusProxy, JanusTransport, ProxyState, next_transport_id};
+use super::core::JanusSession;
+use super::metrics::{self, GaugeGuard};
+use super::core::json;
+use super::core::response::JanusResponse;
+use super::connection::{accept_ws, JANUS_PROTOCOL};
+
+/** A websocket connection, may own multiple sessions sharing the same socket */
+struct WebSocketConnection {
+    id: u64,
+    janus: Arc<JanusProxy>,
+    /** Underlying websocket connection, impl by channel, send only */
+    sender: mpsc::Sender<Message>,
+    sessions: RwLock<HashMap<u64, Arc<JanusSession>>>
+}
+
+#[async_trait]
+impl JanusTransport for WebSocketConnection {
+    async fn add_session(&self, id: u64) -> Arc<JanusSession> {
+        let session = Arc::new(JanusSession::new(Arc::clone(&self.janus), id));
+        self.claim_session(Arc::clone(&session)).await;
+        session
+    }
+
+    async fn claim_session(&self, session: Arc<JanusSession>) {
+        session.bind(self.id, self.sender.clone()).await;
+        self.sessions.write().await.insert(session.id, session);
+    }
+
+    async fn get_session(&self, id: u64) -> Option<Arc<JanusSession>> {
+        let session = self.sessions.read().await.get(&id).map(Arc::clone)?;
+        if session.is_owned_by(self.id).await {
+            Some(session)
+        } else {
+            None
+        }
+    }
+
+    async fn remove_session(&self, id: u64) -> Option<Arc<JanusSession>> {
+        self.sessions.write().await.remove(&id)
+    }
+}
+
+impl WebSocketConnection {
+    async fn handle_message(&self, item: Message) -> Message {
+        if let Message::Text(data) = item {
+            let response = match json::parse(&data) {
+                Ok(request) => self.janus.handle_request(self, request).await,
+                Err(e) => JanusResponse::bad_request(e)
+            };
+            response.into()
+        }
+        else {
+            item
+        }
+    }
+
+    /** Release sessions (if present), they're destroyed with associated resources unless claimed in time */
+    async fn close(&self) {
+        let sessions = self.sessions.write().await.drain().map(|(_, x)| x).collect::<Vec<_>>();
+        for session in sessions.into_iter() {
+            self.janus.release_session(session, self.id).await;
+        }
+    }
+}
+
+pub(crate) async fn serve(janus: Arc<JanusProxy>, mut listener: TcpListener) {
+    loop {
+        let (stream, addr) = tokio::select! {
+            x = listener.accept() => match x {
+                Ok(x) => x,
+                Err(_) => break
+            },
+            _ = janus.wait_for(ProxyState::Draining) => break
+        };
+
+        // Handshakes run apart from accept loop, a slow client must not block others
+        let janus = Arc::clone(&janus);
+        let id = next_transport_id();
+        tokio::spawn(async move {
+            match janus.tls.clone() {
+                Some(tls) => match tls.accept(stream).await {
+                    Ok(stream) => handshake(janus, id, stream).await,
+                    Err(e) => warn!(error = %e, "TLS handshake failed")
+                },
+                None => handshake(janus, id, stream).await
+            }
+        }.instrument(info_span!("connection", id, peer = %addr)));
+    }
+}
+
+async fn handshake<S>(janus: Arc<JanusProxy>, id: u64, stream: S)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    match accept_ws(stream, JANUS_PROTOCOL).await {
+        Ok(ws) => serve_connection(janus, id, ws),
+        Err(e) => warn!(error = %e, "Websocket handshake failed")
+    }
+}
+
+/** Serve transport `id` in the current (connection) span */
+fn serve_connection<S>(janus: Arc<JanusProxy>, id: u64, ws: WebSocketStream<S>)
+    where S: AsyncRead + AsyncWrite + Unpin + Send + 'static
+{
+    let (mut wtx, mut wrx) = ws.split();
+    let (mut tx, mut rx) = mpsc::channel::<Message>(32);
+
+    info!("New connection");
+    tokio::spawn(async move {
+        while let Some(message) = rx.recv().await {
+            if let Err(e) = wtx.send(message).await {
+                // TODO: more properly error handling
+                match e {
+                    Error::ConnectionClosed => debug!("Connection closed while sending"),
+                    Error::AlreadyClosed => error!("Internal error: connection already closed"),
+                    // Error::Io(_) => {}
+                    // Error::Tls(_) => {}
+                    // Error::Capacity(_) => {}
+                    // Error::Protocol(_) => {}
+                    // Error::SendQueueFull(_) => {}
+                    // Error::Utf8 => {}
+                    // Error::Url(_) => {}
+                    // Error::Http(_) => {}
+                    // Error::HttpFormat(_) => {}
+                    _ => continue
+                }
+                break
+            }
+        }
+    }.instrument(Span::current()));
+
+    // Each connection may create multiple sessions, each has an unique id.
+    // Each process websocket message in synchronous fashion, response before next request.
+    // And, may emit event back to websocket connection.
+    let connection = WebSocketConnection {
+        id,
+        janus: Arc::clone(&janus),
+        sender: tx.clone(),
+        sessions: RwLock::new(HashMap::new())
+    };
+    let connected = GaugeGuard::new(metrics::CONNECTIONS.with_label_values(&["websocket"]));
+    tokio::spawn(async move {
+        let _connected = connected;
+        loop {
+            let item = tokio::select! {
+                x = wrx.next() => match x {
+                    Some(x) => x,
+                    None => break
+                },
+                // Sessions are torn down already, close the socket cleanly
+                _ = connection.janus.wait_for(ProxyState::Stopped) => {
+                    let _ = tx.send(Message::Close(None)).await;
+                    break
+                }
+            };
+            match item {
+                Ok(message) => {
+                    let res = connection.handle_message(message).await;
+                    if tx.send(res).await.is_err() {
+                        break     // channel closed
+                    }
+                },
+                Err(e) => error!(error = %e, "Internal error")
+            };
+        }
+
+        info!("Connection closed");
+        connection.close().await;
+    }.instrument(Span::current()));
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use std::sync::Arc;
+    use crate::janus::core::apierror::*;
+    use crate::janus::testing::{self, WsClient};
+
+    fn keepalive(session: u64) -> serde_json::Value {
+        json!({"janus": "keepalive", "session_id": session, "transaction": format!("k{}", session)})
+    }
+
+    #[tokio::test]
+    async fn sessions_share_a_connection() {
+        let url = testing::listen(Arc::new(testing::proxy(&[]).await)).await;
+        let mut client = WsClient::connect(&url).await;
+        let first = client.create().await;
+        let second = client.create().await;
+        assert_ne!(first, second);
+
+        for session in [first, second].iter() {
+            let response = client.request(keepalive(*session)).await;
+            assert_eq!((&response["janus"], &response["session_id"]), (&json!("ack"), &json!(session)));
+            let handle = client.attach(*session, "janus.plugin.videoroom").await;
+            assert!(handle != 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn destroy_leaves_other_sessions() {
+        let url = testing::listen(Arc::new(testing::proxy(&[]).await)).await;
+        let mut client = WsClient::connect(&url).await;
+        let first = client.create().await;
+        let second = client.create().await;
+
+        let response = client.request(json!({"janus": "destroy", "session_id": first, "transaction": "d"})).await;
+        assert_eq!(response["janus"], "success");
+        let response = client.request(keepalive(first)).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_SESSION_NOT_FOUND);
+        let response = client.request(keepalive(second)).await;
+        assert_eq!(response["janus"], "ack");
+    }
+
+    #[tokio::test]
+    async fn sessions_are_only_found_on_their_connection() {
+        let url = testing::listen(Arc::new(testing::proxy(&[]).await)).await;
+        let mut owner = WsClient::connect(&url).await;
+        let mut other = WsClient::connect(&url).await;
+        let session = owner.create().await;
+
+        let response = other.request(keepalive(session)).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_SESSION_NOT_FOUND);
+        let response = other.request(keepalive(session + 1)).await;
+        assert_eq!(response["error"]["code"], JANUS_ERROR_SESSION_NOT_FOUND);
+        let response = owner.request(keepalive(session)).await;
+        assert_eq!(response["janus"], "ack");
+    }
+}
@@ -1 +1,2 @@
 pub mod janus;
+pub mod config;
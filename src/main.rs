@@ -1,27 +1,154 @@
 use std::sync::Arc;
 use tokio::net::{TcpListener};
+use tokio::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use std::collections::HashMap;
+use structopt::StructOpt;
+use tracing::info;
+use janus_proxy::config::{Cli, Config};
 use janus_proxy::janus::JanusProxy;
-use janus_proxy::janus::plugin::JanusPluginProvider;
-use janus_proxy::janus::provider::{MemoryStateProvider, MemoryBackendProvider, JanusBackendProvider};
+use janus_proxy::janus::admin::{JanusAdminConfig, JanusAdminBackend};
+use janus_proxy::janus::tls::TlsAcceptor;
+
+/** Resolve on SIGTERM or SIGINT */
+async fn shutdown_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = terminate.recv() => {},
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/** Listen on `addr` if given, for optional listeners */
+async fn bind(addr: &Option<String>) -> Option<TcpListener> {
+    match addr {
+        Some(x) => Some(TcpListener::bind(x).await.expect("Failed to bind")),
+        None => None
+    }
+}
 
 #[tokio::main]
 async fn main() {
-    let addr = "0.0.0.0:3000";
-    let socket = TcpListener::bind(addr).await;
-    let listener = socket.expect("Failed to bind");
-
-    let server = String::from("ws://localhost:8188");
-    let backend = MemoryBackendProvider::new();
-    backend.update_backend(server, true);
-
-    let janus = JanusProxy::new(
-        JanusPluginProvider::default(),
-        Arc::new(Box::new(MemoryStateProvider::new())),
-        Arc::new(Box::new(backend))
-    );
+    let cli = Cli::from_args();
+    let config = match Config::load(&cli) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(errors) = config.validate() {
+        eprintln!("Invalid configuration:");
+        for e in errors.iter() {
+            eprintln!("  {}", e);
+        }
+        std::process::exit(1);
+    }
+    if cli.check_config {
+        println!("Configuration OK");
+        return
+    }
+    config.init_logging();
+
+    let listener = TcpListener::bind(&config.listen.websocket).await.expect("Failed to bind");
+    let http_listener = bind(&config.listen.http).await;
+    let admin_listener = bind(&config.listen.admin).await;
+    let metrics_listener = bind(&config.listen.metrics).await;
+
+    // With health checking, backends are up once the first probe succeeds
+    let health_check = config.health_check();
+    let backend = config.backend_provider();
+    let mut admin_backends = HashMap::new();
+    for x in config.backends.iter() {
+        if health_check.is_none() {
+            backend.update_backend(x.url.clone(), true).await;
+        }
+        if let Some(url) = &x.admin_url {
+            admin_backends.insert(x.url.clone(), JanusAdminBackend {
+                url: url.clone(),
+                secret: x.admin_secret.clone()
+            });
+        }
+    }
+
+    let mut janus = JanusProxy::new(
+        config.plugin_provider(),
+        Arc::new(config.state_provider()),
+        Arc::new(backend)
+    ).with_admin(JanusAdminConfig {
+        secret: config.auth.admin_secret.clone(),
+        backends: admin_backends
+    })
+    .with_api_secret(config.auth.api_secret.clone())
+    .with_token_auth(config.auth.token_auth)
+    .with_session_timeout(Duration::from_secs(config.session.timeout))
+    .with_reclaim_timeout(Duration::from_secs(config.session.reclaim_timeout))
+    .with_backend_reconnect(config.session.reconnect)
+    .with_backend_pool(config.backend_pool.sessions_per_connection)
+    .with_event_queue(config.events.queue_size, config.event_overflow())
+    .with_sweep_interval(Duration::from_secs(config.state.sweep_interval))
+    .with_transaction_ids(config.id_generator());
+
+    if let Some(x) = health_check {
+        janus = janus.with_health_check(x);
+    }
+    for x in config.backends.iter() {
+        if let Some(secret) = &x.secret {
+            janus = janus.with_backend_secret(x.url.clone(), secret.clone());
+        }
+    }
+
+    // Serve `wss://`, certificate is checked by `validate` already
+    if let Some(tls) = &config.tls {
+        janus = janus.with_tls(TlsAcceptor::new(&tls.cert, &tls.key).expect("Failed to load TLS certificate"));
+    }
+    let janus = Arc::new(janus);
 
     // TODO: enable http server for managing janus-gateway instances, token...
 
     // TODO: Check whether .await yield task back to scheduler
-    JanusProxy::listen(janus, listener).await;
+    let shutdown = {
+        let janus = Arc::clone(&janus);
+        let deadline = Duration::from_secs(config.shutdown.deadline);
+        let refuse_sessions = config.shutdown.refuse_sessions;
+        async move {
+            shutdown_signal().await;
+            info!("Shutdown signal received, draining");
+            janus.shutdown(deadline, refuse_sessions).await;
+        }
+    };
+
+    let http = {
+        let janus = Arc::clone(&janus);
+        async move {
+            if let Some(listener) = http_listener {
+                JanusProxy::listen_http(janus, listener).await
+            }
+        }
+    };
+    let admin = {
+        let janus = Arc::clone(&janus);
+        async move {
+            if let Some(listener) = admin_listener {
+                JanusProxy::listen_admin(janus, listener).await
+            }
+        }
+    };
+
+    let metrics = {
+        let janus = Arc::clone(&janus);
+        async move {
+            if let Some(listener) = metrics_listener {
+                JanusProxy::listen_metrics(janus, listener).await
+            }
+        }
+    };
+
+    tokio::join!(
+        JanusProxy::listen(janus, listener),
+        http,
+        admin,
+        metrics,
+        shutdown
+    );
 }